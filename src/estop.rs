@@ -0,0 +1,271 @@
+#![allow(dead_code)]
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use esp_idf_hal::gpio::{Input, InputPin, OutputPin, PinDriver, Pull};
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::Write;
+use anyhow::Error;
+use log::*;
+
+use crate::servo_bus::ServoBusProtocol;
+
+/// What triggered an emergency stop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EStopSource {
+    /// The e-stop button input.
+    Button,
+    /// Firmware code, e.g. a supervisor.
+    Software,
+    /// A request over the network.
+    Network,
+}
+
+/// The trigger that latched the e-stop.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EStopEvent {
+    pub source: EStopSource,
+    pub reason: String,
+    pub at: Instant,
+}
+
+/// Error returned by motion commands while the e-stop is latched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EStopActive {
+    pub source: EStopSource,
+}
+
+impl core::fmt::Display for EStopActive {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "emergency stop is latched ({:?}); reset it before commanding motion", self.source)
+    }
+}
+
+impl std::error::Error for EStopActive {}
+
+#[derive(Debug, Default)]
+struct EStopState {
+    latched: Option<EStopEvent>,
+    /// The button is still pressed, so a reset would not hold.
+    button_held: bool,
+    /// The buses have been stopped since the latch was set.
+    buses_stopped: bool,
+    /// Motion may restart; requested after the reset, cleared by the next trigger.
+    resume_requested: bool,
+}
+
+/// Latching emergency stop shared by every part of the firmware.
+///
+/// Cloning gives another handle to the same latch, so network handlers and other threads can [`trigger`](Self::trigger)
+/// it. The main loop calls [`service`](Self::service) to broadcast `MOVE_STOP` and unload torque on every bus as soon
+/// as the latch is set; buses with the e-stop attached refuse motion commands with [`EStopActive`] until an explicit
+/// [`reset`](Self::reset). The reset alone does not restart anything: the owner of the motion waits for a separate
+/// [`request_resume`](Self::request_resume) before loading the servos again.
+///
+/// A trigger from another thread only sets the latch: the stop frame goes out at the owner's next call to
+/// [`service`](Self::service), so the worst-case latency is the longest bus transaction made between two calls.
+#[derive(Debug, Clone, Default)]
+pub struct EStop {
+    state: Arc<Mutex<EStopState>>,
+}
+
+impl EStop {
+    pub fn new() -> Self {
+        EStop::default()
+    }
+
+    /// Latch the e-stop. Returns false if it was already latched (the first trigger is kept).
+    pub fn trigger(&self, source: EStopSource, reason: &str) -> bool {
+        let mut state = self.lock();
+        if state.latched.is_some() {
+            return false;
+        }
+        error!("EMERGENCY STOP ({:?}): {}", source, reason);
+        state.latched = Some(EStopEvent {
+            source,
+            reason: reason.to_string(),
+            at: Instant::now(),
+        });
+        state.buses_stopped = false;
+        state.resume_requested = false;
+        true
+    }
+
+    /// True while the e-stop is latched.
+    pub fn is_latched(&self) -> bool {
+        self.lock().latched.is_some()
+    }
+
+    /// The trigger that latched the e-stop, if latched.
+    pub fn event(&self) -> Option<EStopEvent> {
+        self.lock().latched.clone()
+    }
+
+    /// Release the latch. Refused while the e-stop button is still pressed. Servos stay unloaded until
+    /// [`request_resume`](Self::request_resume).
+    pub fn reset(&self) -> Result<(), Error> {
+        let mut state = self.lock();
+        if state.button_held {
+            anyhow::bail!("Cannot reset the emergency stop while the button is pressed");
+        }
+        if state.latched.take().is_some() {
+            info!("Emergency stop reset");
+        }
+        Ok(())
+    }
+
+    /// Ask for torque and motion to come back after a reset. Refused while the e-stop is latched.
+    pub fn request_resume(&self) -> Result<(), Error> {
+        let mut state = self.lock();
+        if let Some(event) = &state.latched {
+            return Err(EStopActive { source: event.source }.into());
+        }
+        info!("Resume requested");
+        state.resume_requested = true;
+        Ok(())
+    }
+
+    /// Consume a pending resume request. Returns true once per [`request_resume`](Self::request_resume).
+    pub fn take_resume(&self) -> bool {
+        core::mem::take(&mut self.lock().resume_requested)
+    }
+
+    /// Fail with [`EStopActive`] while the e-stop is latched. Called by the buses before every motion command.
+    pub fn check(&self) -> Result<(), Error> {
+        match &self.lock().latched {
+            Some(event) => Err(EStopActive { source: event.source }.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Stop every bus if the e-stop has been latched since the last call. Call it on every main loop iteration.
+    /// Returns true while latched, so the caller can skip its motion code.
+    ///
+    /// The stop is retried on the next call if any bus failed to take it.
+    pub fn service(&self, buses: &mut [&mut dyn ServoBusProtocol]) -> bool {
+        if !self.is_latched() {
+            return false;
+        }
+        if self.lock().buses_stopped {
+            return true;
+        }
+        let mut stopped = true;
+        for bus in buses.iter_mut() {
+            if let Err(e) = bus.emergency_stop() {
+                error!("Emergency stop broadcast failed: {:?}", e);
+                stopped = false;
+            }
+        }
+        self.lock().buses_stopped = stopped;
+        true
+    }
+
+    fn set_button_held(&self, held: bool) {
+        self.lock().button_held = held;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, EStopState> {
+        // A panic while holding the lock must not disable the e-stop
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Debounced e-stop button input.
+///
+/// The button must be a normally-closed contact between the pin and GND: the internal pull-up reads low while the
+/// circuit is closed, and high when the button is pressed or a wire is cut, so a broken cable also stops the robot.
+pub struct EStopButton<'d, T: InputPin + OutputPin> {
+    pin: PinDriver<'d, T, Input>,
+    estop: EStop,
+    debounce: Duration,
+    /// Last raw reading and the time it was first seen.
+    raw: (bool, Instant),
+    pressed: bool,
+}
+
+impl<'d, T: InputPin + OutputPin> EStopButton<'d, T> {
+    /// Watch `pin` and trigger `estop` when the button has been pressed for `debounce`.
+    pub fn new(pin: impl Peripheral<P = T> + 'd, estop: EStop, debounce: Duration) -> Result<Self, Error> {
+        let mut pin = PinDriver::input(pin)?;
+        pin.set_pull(Pull::Up)?;
+        let raw = pin.is_high();
+        Ok(EStopButton {
+            pin,
+            estop,
+            debounce,
+            raw: (raw, Instant::now()),
+            pressed: false,
+        })
+    }
+
+    /// Sample the input. Call it on every main loop iteration, before [`EStop::service`].
+    pub fn poll(&mut self) {
+        let now = Instant::now();
+        let raw = self.pin.is_high();
+        if raw != self.raw.0 {
+            self.raw = (raw, now);
+            return;
+        }
+        if raw == self.pressed || now.duration_since(self.raw.1) < self.debounce {
+            return;
+        }
+        self.pressed = raw;
+        self.estop.set_button_held(raw);
+        if raw {
+            self.estop.trigger(EStopSource::Button, "e-stop button pressed");
+        } else {
+            info!("E-stop button released");
+        }
+    }
+
+    /// Debounced button state.
+    pub fn is_pressed(&self) -> bool {
+        self.pressed
+    }
+}
+
+/// Register `POST /estop` (trigger), `POST /estop/reset`, `POST /estop/resume` and `GET /estop` (status as JSON) on an
+/// HTTP server.
+pub fn register_http(server: &mut EspHttpServer<'static>, estop: &EStop) -> Result<(), Error> {
+    let handle = estop.clone();
+    server.fn_handler("/estop", Method::Post, move |request| {
+        handle.trigger(EStopSource::Network, "HTTP request");
+        request.into_ok_response()?.write_all(b"stopped")?;
+        Ok::<(), Error>(())
+    })?;
+
+    let handle = estop.clone();
+    server.fn_handler("/estop/reset", Method::Post, move |request| {
+        match handle.reset() {
+            Ok(()) => request.into_ok_response()?.write_all(b"reset")?,
+            Err(e) => request.into_status_response(409)?.write_all(e.to_string().as_bytes())?,
+        }
+        Ok::<(), Error>(())
+    })?;
+
+    let handle = estop.clone();
+    server.fn_handler("/estop/resume", Method::Post, move |request| {
+        match handle.request_resume() {
+            Ok(()) => request.into_ok_response()?.write_all(b"resuming")?,
+            Err(e) => request.into_status_response(409)?.write_all(e.to_string().as_bytes())?,
+        }
+        Ok::<(), Error>(())
+    })?;
+
+    let handle = estop.clone();
+    server.fn_handler("/estop", Method::Get, move |request| {
+        let body = match handle.event() {
+            Some(event) => serde_json::json!({
+                "latched": true,
+                "source": format!("{:?}", event.source),
+                "reason": event.reason,
+                "age_ms": event.at.elapsed().as_millis() as u64,
+            }),
+            None => serde_json::json!({ "latched": false }),
+        };
+        request.into_ok_response()?.write_all(body.to_string().as_bytes())?;
+        Ok::<(), Error>(())
+    })?;
+    Ok(())
+}
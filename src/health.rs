@@ -0,0 +1,449 @@
+#![allow(dead_code)]
+use std::collections::BTreeMap;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, Instant};
+use anyhow::Error;
+use log::*;
+
+use crate::servo_bus::{ServoBusProtocol, ServoMode};
+
+/// Health of one servo as seen by the [`HealthMonitor`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthState {
+    /// Answering pings.
+    Online,
+    /// Missed at least one ping, but not enough to be declared offline. Commands are still allowed.
+    Degraded,
+    /// Missed enough consecutive pings to be considered unplugged; commands are refused until it recovers.
+    Offline,
+}
+
+/// Tuning of the health state machine.
+#[derive(Debug, Clone, Copy)]
+pub struct HealthConfig {
+    /// Time between two ping rounds.
+    pub ping_interval: Duration,
+    /// Consecutive failed pings before an online servo becomes degraded.
+    pub degraded_after: u32,
+    /// Consecutive failed pings before a servo becomes offline.
+    pub offline_after: u32,
+    /// Consecutive successful pings before a degraded or offline servo is online again.
+    pub online_after: u32,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            ping_interval: Duration::from_millis(1000),
+            degraded_after: 1,
+            offline_after: 3,
+            online_after: 2,
+        }
+    }
+}
+
+/// A state transition of one servo, sent to subscribers and callbacks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HealthEvent {
+    pub id: u8,
+    pub from: HealthState,
+    pub to: HealthState,
+    pub at: Instant,
+}
+
+/// Error returned by [`HealthMonitor::ensure_online`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServoOffline {
+    pub id: u8,
+}
+
+impl core::fmt::Display for ServoOffline {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "servo {} is offline", self.id)
+    }
+}
+
+impl std::error::Error for ServoOffline {}
+
+/// Callback registered with [`HealthMonitor::on_transition`].
+type TransitionCallback = Box<dyn FnMut(&HealthEvent) + Send>;
+
+/// Per-servo counters of the state machine.
+#[derive(Debug, Clone, Copy)]
+struct ServoHealth {
+    state: HealthState,
+    failures: u32,
+    successes: u32,
+    last_seen: Option<Instant>,
+}
+
+impl ServoHealth {
+    fn new() -> Self {
+        ServoHealth {
+            state: HealthState::Online,
+            failures: 0,
+            successes: 0,
+            last_seen: None,
+        }
+    }
+
+    /// Record one ping result and return the new state if it changed.
+    ///
+    /// Failures and successes are counted consecutively, so a flaky servo oscillates between
+    /// online and degraded instead of flapping in and out of offline.
+    fn record(&mut self, ok: bool, config: &HealthConfig, now: Instant) -> Option<HealthState> {
+        if ok {
+            self.failures = 0;
+            self.successes += 1;
+            self.last_seen = Some(now);
+        } else {
+            self.successes = 0;
+            self.failures += 1;
+        }
+
+        let next = match self.state {
+            HealthState::Online if self.failures >= config.offline_after => HealthState::Offline,
+            HealthState::Online if self.failures >= config.degraded_after => HealthState::Degraded,
+            HealthState::Degraded if self.failures >= config.offline_after => HealthState::Offline,
+            HealthState::Degraded | HealthState::Offline if self.successes >= config.online_after => HealthState::Online,
+            state => state,
+        };
+
+        if next == self.state {
+            return None;
+        }
+        self.state = next;
+        Some(next)
+    }
+}
+
+/// Periodically pings every known servo and tracks an online/degraded/offline state per ID.
+///
+/// Call [`poll`](Self::poll) from the main loop; it starts a ping round every `ping_interval` and pings one servo of
+/// the round per call, so a servo that does not answer holds the loop for a single bus timeout at most.
+/// Transitions are delivered to [`subscribe`](Self::subscribe) channels and [`on_transition`](Self::on_transition) callbacks,
+/// and motion code commands the servos through [`guard`](Self::guard), which refuses to move offline servos.
+pub struct HealthMonitor {
+    config: HealthConfig,
+    servos: BTreeMap<u8, ServoHealth>,
+    subscribers: Vec<Sender<HealthEvent>>,
+    callbacks: Vec<TransitionCallback>,
+    last_poll: Option<Instant>,
+    /// Servos still to be pinged in the current round, last one first.
+    pending: Vec<u8>,
+}
+
+impl HealthMonitor {
+    /// Create a monitor for the given servo IDs. Servos start online until pings say otherwise.
+    pub fn new(ids: &[u8], config: HealthConfig) -> Self {
+        HealthMonitor {
+            config,
+            servos: ids.iter().map(|id| (*id, ServoHealth::new())).collect(),
+            subscribers: Vec::new(),
+            callbacks: Vec::new(),
+            last_poll: None,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Start monitoring another servo.
+    pub fn add(&mut self, id: u8) {
+        self.servos.entry(id).or_insert_with(ServoHealth::new);
+    }
+
+    /// Stop monitoring a servo.
+    pub fn remove(&mut self, id: u8) {
+        self.servos.remove(&id);
+    }
+
+    /// Receive every state transition on a channel. Dropped receivers are pruned automatically.
+    pub fn subscribe(&mut self) -> Receiver<HealthEvent> {
+        let (tx, rx) = channel();
+        self.subscribers.push(tx);
+        rx
+    }
+
+    /// Call `callback` on every state transition, from within [`poll`](Self::poll).
+    pub fn on_transition<F>(&mut self, callback: F)
+    where
+        F: FnMut(&HealthEvent) + Send + 'static,
+    {
+        self.callbacks.push(Box::new(callback));
+    }
+
+    /// Ping the next servo of the current round, starting a new round once `ping_interval` has elapsed since the
+    /// last one started. Returns the transition of the servo pinged, if any.
+    pub fn poll<B: ServoBusProtocol>(&mut self, bus: &mut B) -> Vec<HealthEvent> {
+        let now = Instant::now();
+        if self.pending.is_empty() {
+            if let Some(last) = self.last_poll {
+                if now.duration_since(last) < self.config.ping_interval {
+                    return Vec::new();
+                }
+            }
+            self.last_poll = Some(now);
+            self.pending = self.servos.keys().rev().copied().collect();
+        }
+
+        // Servos removed since the round started are skipped
+        while let Some(id) = self.pending.pop() {
+            if self.servos.contains_key(&id) {
+                let ok = bus.ping(id).is_ok();
+                return self.record(id, ok, Instant::now()).into_iter().collect();
+            }
+        }
+        Vec::new()
+    }
+
+    /// Feed the result of any bus transaction with servo `id` into the state machine, e.g. a failed position read.
+    pub fn record(&mut self, id: u8, ok: bool, now: Instant) -> Option<HealthEvent> {
+        let config = self.config;
+        let health = self.servos.get_mut(&id)?;
+        let from = health.state;
+        let to = health.record(ok, &config, now)?;
+        let event = HealthEvent { id, from, to, at: now };
+        self.notify(&event);
+        Some(event)
+    }
+
+    /// Current state of a servo; unknown IDs are reported offline.
+    pub fn state(&self, id: u8) -> HealthState {
+        self.servos.get(&id).map_or(HealthState::Offline, |health| health.state)
+    }
+
+    /// True unless the servo is offline (degraded servos may still be commanded).
+    pub fn is_online(&self, id: u8) -> bool {
+        self.state(id) != HealthState::Offline
+    }
+
+    /// Time the servo last answered, if ever.
+    pub fn last_seen(&self, id: u8) -> Option<Instant> {
+        self.servos.get(&id).and_then(|health| health.last_seen)
+    }
+
+    /// Refuse to command an offline servo. Returns [`ServoOffline`] wrapped in an [`anyhow::Error`].
+    pub fn ensure_online(&self, id: u8) -> Result<(), Error> {
+        if self.is_online(id) {
+            Ok(())
+        } else {
+            Err(ServoOffline { id }.into())
+        }
+    }
+
+    /// Wrap `bus` so moves, motor mode and torque enable are refused with [`ServoOffline`] for offline servos.
+    /// See [`GuardedBus`].
+    pub fn guard<'a, B: ServoBusProtocol>(&'a self, bus: &'a mut B) -> GuardedBus<'a, B> {
+        GuardedBus { bus, health: self }
+    }
+
+    fn notify(&mut self, event: &HealthEvent) {
+        match event.to {
+            HealthState::Online => info!("Servo {} is online", event.id),
+            HealthState::Degraded => warn!("Servo {} is degraded (missed ping)", event.id),
+            HealthState::Offline => error!("Servo {} is offline", event.id),
+        }
+        self.subscribers.retain(|tx| tx.send(*event).is_ok());
+        for callback in self.callbacks.iter_mut() {
+            callback(event);
+        }
+    }
+}
+
+/// A servo bus that checks [`HealthMonitor::ensure_online`] before every command that makes a servo move.
+///
+/// Reads, pings and stopping commands pass through, so an offline servo can still be probed and unloaded.
+pub struct GuardedBus<'a, B> {
+    bus: &'a mut B,
+    health: &'a HealthMonitor,
+}
+
+impl<B: ServoBusProtocol> ServoBusProtocol for GuardedBus<'_, B> {
+    fn position_max(&self, id: u8) -> u16 {
+        self.bus.position_max(id)
+    }
+
+    fn units_per_degree(&self, id: u8) -> f32 {
+        self.bus.units_per_degree(id)
+    }
+
+    fn move_to_position(&mut self, id: u8, position: u16, time_ms: u16) -> Result<(), Error> {
+        self.health.ensure_online(id)?;
+        self.bus.move_to_position(id, position, time_ms)
+    }
+
+    fn ping(&mut self, id: u8) -> Result<(), Error> {
+        self.bus.ping(id)
+    }
+
+    fn read_position(&mut self, id: u8) -> Result<u16, Error> {
+        self.bus.read_position(id)
+    }
+
+    fn set_torque(&mut self, id: u8, enable: bool) -> Result<(), Error> {
+        if enable {
+            self.health.ensure_online(id)?;
+        }
+        self.bus.set_torque(id, enable)
+    }
+
+    fn set_mode(&mut self, id: u8, mode: ServoMode) -> Result<(), Error> {
+        if let ServoMode::Motor { .. } = mode {
+            self.health.ensure_online(id)?;
+        }
+        self.bus.set_mode(id, mode)
+    }
+
+    fn read_mode(&mut self, id: u8) -> Result<ServoMode, Error> {
+        self.bus.read_mode(id)
+    }
+
+    fn set_position_limits(&mut self, id: u8, min: u16, max: u16) -> Result<(), Error> {
+        self.bus.set_position_limits(id, min, max)
+    }
+
+    fn emergency_stop(&mut self) -> Result<(), Error> {
+        self.bus.emergency_stop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bus where only the servos in `answering` reply, recording the moves it is sent.
+    struct FakeBus {
+        answering: Vec<u8>,
+        pinged: Vec<u8>,
+        moved: Vec<u8>,
+    }
+
+    impl FakeBus {
+        fn new(answering: &[u8]) -> Self {
+            FakeBus { answering: answering.to_vec(), pinged: Vec::new(), moved: Vec::new() }
+        }
+
+        fn reply(&self, id: u8) -> Result<(), Error> {
+            if self.answering.contains(&id) {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!("servo {} did not answer", id))
+            }
+        }
+    }
+
+    impl ServoBusProtocol for FakeBus {
+        fn position_max(&self, _id: u8) -> u16 {
+            1000
+        }
+
+        fn units_per_degree(&self, _id: u8) -> f32 {
+            1000.0 / 240.0
+        }
+
+        fn move_to_position(&mut self, id: u8, _position: u16, _time_ms: u16) -> Result<(), Error> {
+            self.moved.push(id);
+            self.reply(id)
+        }
+
+        fn ping(&mut self, id: u8) -> Result<(), Error> {
+            self.pinged.push(id);
+            self.reply(id)
+        }
+
+        fn read_position(&mut self, id: u8) -> Result<u16, Error> {
+            self.reply(id).map(|_| 500)
+        }
+
+        fn set_torque(&mut self, id: u8, _enable: bool) -> Result<(), Error> {
+            self.reply(id)
+        }
+
+        fn set_mode(&mut self, id: u8, _mode: ServoMode) -> Result<(), Error> {
+            self.reply(id)
+        }
+
+        fn read_mode(&mut self, id: u8) -> Result<ServoMode, Error> {
+            self.reply(id).map(|_| ServoMode::Position)
+        }
+
+        fn set_position_limits(&mut self, id: u8, _min: u16, _max: u16) -> Result<(), Error> {
+            self.reply(id)
+        }
+
+        fn emergency_stop(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    fn states(monitor: &mut HealthMonitor, id: u8, results: &[bool]) -> Vec<HealthState> {
+        let now = Instant::now();
+        results
+            .iter()
+            .map(|ok| {
+                monitor.record(id, *ok, now);
+                monitor.state(id)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn missed_pings_degrade_then_take_a_servo_offline() {
+        use HealthState::*;
+        let mut monitor = HealthMonitor::new(&[1], HealthConfig::default());
+        assert_eq!(states(&mut monitor, 1, &[false, false, false]), [Degraded, Degraded, Offline]);
+        // One answer is not enough to come back, and a miss in between restarts the count
+        assert_eq!(states(&mut monitor, 1, &[true, false, true, true]), [Offline, Offline, Offline, Online]);
+    }
+
+    #[test]
+    fn a_flaky_servo_stays_between_online_and_degraded() {
+        use HealthState::*;
+        let mut monitor = HealthMonitor::new(&[1], HealthConfig::default());
+        let results = [false, true, false, true, true, false, true, true];
+        let expected = [Degraded, Degraded, Degraded, Degraded, Online, Degraded, Degraded, Online];
+        assert_eq!(states(&mut monitor, 1, &results), expected);
+    }
+
+    #[test]
+    fn transitions_are_reported_once() {
+        let mut monitor = HealthMonitor::new(&[1], HealthConfig::default());
+        let events = monitor.subscribe();
+        states(&mut monitor, 1, &[false, false, false, false]);
+        let transitions: Vec<_> = events.try_iter().map(|event| (event.from, event.to)).collect();
+        assert_eq!(
+            transitions,
+            [(HealthState::Online, HealthState::Degraded), (HealthState::Degraded, HealthState::Offline)]
+        );
+        assert_eq!(monitor.state(2), HealthState::Offline);
+    }
+
+    #[test]
+    fn poll_pings_one_servo_per_call() {
+        let config = HealthConfig { ping_interval: Duration::from_secs(3600), ..HealthConfig::default() };
+        let mut monitor = HealthMonitor::new(&[1, 2, 3], config);
+        let mut bus = FakeBus::new(&[1, 3]);
+        for _ in 0..5 {
+            monitor.poll(&mut bus);
+        }
+        // The next round only starts after the ping interval
+        assert_eq!(bus.pinged, [1, 2, 3]);
+        assert_eq!(monitor.state(2), HealthState::Degraded);
+    }
+
+    #[test]
+    fn guard_refuses_to_move_offline_servos() {
+        let mut monitor = HealthMonitor::new(&[1, 2], HealthConfig::default());
+        states(&mut monitor, 2, &[false, false, false]);
+        let mut bus = FakeBus::new(&[1, 2]);
+        let mut guarded = monitor.guard(&mut bus);
+        guarded.move_to_position(1, 500, 100).unwrap();
+        let err = guarded.move_to_position(2, 500, 100).unwrap_err();
+        assert_eq!(err.downcast_ref::<ServoOffline>(), Some(&ServoOffline { id: 2 }));
+        assert!(guarded.set_torque(2, true).is_err());
+        assert!(guarded.set_mode(2, ServoMode::Motor { speed: 100 }).is_err());
+        // Unloading and probing still reach the servo
+        guarded.set_torque(2, false).unwrap();
+        guarded.ping(2).unwrap();
+        assert_eq!(bus.moved, [1]);
+    }
+}
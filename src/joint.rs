@@ -0,0 +1,375 @@
+#![allow(dead_code)]
+use std::collections::BTreeMap;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use serde::{Deserialize, Serialize};
+use anyhow::Error;
+use log::*;
+
+use crate::servo_bus::{ServoBusProtocol, ServoMode};
+
+/// NVS namespace holding the joint calibration.
+const NVS_NAMESPACE: &str = "joints";
+/// Key of the JSON map of every joint calibration.
+const CALIBRATION_KEY: &str = "calibration";
+
+/// Errors of joint-space commands, wrapped in [`anyhow::Error`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JointError {
+    /// No calibration is known for the servo.
+    Uncalibrated { id: u8 },
+    /// The requested angle is outside the joint's software limits.
+    OutOfLimits { id: u8, angle: f32, min: f32, max: f32 },
+    /// The requested angle maps to a servo position outside 0..=`position_max`.
+    Unreachable { id: u8, angle: f32, position: f32 },
+    /// The calibration cannot map angles to positions, e.g. a zero or non-finite gear ratio.
+    InvalidCalibration { id: u8, reason: &'static str },
+}
+
+impl core::fmt::Display for JointError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            JointError::Uncalibrated { id } => write!(f, "joint {} has no calibration", id),
+            JointError::OutOfLimits { id, angle, min, max } => {
+                write!(f, "joint {}: {:.3} rad is outside the limits {:.3}..={:.3} rad", id, angle, min, max)
+            }
+            JointError::Unreachable { id, angle, position } => {
+                write!(f, "joint {}: {:.3} rad maps to servo position {:.0}, outside the servo range", id, angle, position)
+            }
+            JointError::InvalidCalibration { id, reason } => write!(f, "joint {}: invalid calibration: {}", id, reason),
+        }
+    }
+}
+
+impl std::error::Error for JointError {}
+
+/// Mapping between a joint angle in radians and the raw position of the servo driving it.
+///
+/// `position = zero + direction * angle * gear_ratio * units_per_degree`, where `direction` is -1 when `inverted`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct JointCalibration {
+    /// Servo position (raw units) at joint angle 0.
+    pub zero: f32,
+    /// The servo turns the opposite way to the joint's positive direction.
+    pub inverted: bool,
+    /// Servo rotation per joint rotation (2.0 for a 2:1 reduction between servo horn and joint).
+    pub gear_ratio: f32,
+    /// Software limits of the joint in radians.
+    pub min_rad: f32,
+    pub max_rad: f32,
+}
+
+impl JointCalibration {
+    /// Calibration of servo `id` mounted directly on the joint: zero at mid-travel, no inversion, limits at the servo's end stops.
+    pub fn direct<B: ServoBusProtocol>(bus: &B, id: u8) -> Self {
+        let half_range = (bus.position_max(id) as f32 / 2.0 / bus.units_per_degree(id)).to_radians();
+        JointCalibration {
+            zero: bus.position_max(id) as f32 / 2.0,
+            inverted: false,
+            gear_ratio: 1.0,
+            min_rad: -half_range,
+            max_rad: half_range,
+        }
+    }
+
+    /// Servo position (raw units, unrounded) for a joint angle in radians.
+    pub fn angle_to_position(&self, angle: f32, units_per_degree: f32) -> f32 {
+        self.zero + self.direction() * angle.to_degrees() * self.gear_ratio * units_per_degree
+    }
+
+    /// Joint angle in radians for a servo position.
+    pub fn position_to_angle(&self, position: f32, units_per_degree: f32) -> f32 {
+        ((position - self.zero) / units_per_degree / self.gear_ratio * self.direction()).to_radians()
+    }
+
+    /// Check that the calibration maps angles to positions: finite zero, finite non-zero gear ratio and
+    /// finite limits with `min_rad <= max_rad`.
+    pub fn validate(&self, id: u8) -> Result<(), JointError> {
+        let reason = if !self.zero.is_finite() {
+            "zero is not a number"
+        } else if !(self.gear_ratio.is_finite() && self.gear_ratio != 0.0) {
+            "gear ratio must be a non-zero number"
+        } else if !(self.min_rad.is_finite() && self.max_rad.is_finite() && self.min_rad <= self.max_rad) {
+            "limits must be numbers with min_rad <= max_rad"
+        } else {
+            return Ok(());
+        };
+        Err(JointError::InvalidCalibration { id, reason })
+    }
+
+    /// True if `angle` is within the software limits.
+    pub fn in_limits(&self, angle: f32) -> bool {
+        (self.min_rad..=self.max_rad).contains(&angle)
+    }
+
+    fn direction(&self) -> f32 {
+        if self.inverted {
+            -1.0
+        } else {
+            1.0
+        }
+    }
+}
+
+/// Calibrated joints of the robot, keyed by servo ID.
+///
+/// Commands and reads go through the calibration, so kinematics code works in radians and never sees raw positions.
+/// Persist with [`JointStore`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Joints {
+    calibrations: BTreeMap<u8, JointCalibration>,
+}
+
+impl Joints {
+    pub fn new() -> Self {
+        Joints::default()
+    }
+
+    /// Set (or replace) the calibration of servo `id`. Fails with [`JointError::InvalidCalibration`] if it cannot be
+    /// used, leaving the previous calibration in place.
+    pub fn set_calibration(&mut self, id: u8, calibration: JointCalibration) -> Result<(), Error> {
+        calibration.validate(id)?;
+        self.calibrations.insert(id, calibration);
+        Ok(())
+    }
+
+    /// Calibration of servo `id`, if known.
+    pub fn calibration(&self, id: u8) -> Option<&JointCalibration> {
+        self.calibrations.get(&id)
+    }
+
+    /// IDs of every calibrated joint.
+    pub fn ids(&self) -> Vec<u8> {
+        self.calibrations.keys().copied().collect()
+    }
+
+    /// Servo position for `angle` on joint `id`, checked against the software limits and the servo range.
+    pub fn position_for<B: ServoBusProtocol>(&self, bus: &B, id: u8, angle: f32) -> Result<u16, Error> {
+        let calibration = self.get(id)?;
+        if !calibration.in_limits(angle) {
+            return Err(JointError::OutOfLimits {
+                id,
+                angle,
+                min: calibration.min_rad,
+                max: calibration.max_rad,
+            }
+            .into());
+        }
+        let position = calibration.angle_to_position(angle, bus.units_per_degree(id)).round();
+        if position < 0.0 || position > bus.position_max(id) as f32 {
+            return Err(JointError::Unreachable { id, angle, position }.into());
+        }
+        Ok(position as u16)
+    }
+
+    /// Joint angle in radians of joint `id` at servo position `position`.
+    pub fn angle_of<B: ServoBusProtocol>(&self, bus: &B, id: u8, position: u16) -> Result<f32, Error> {
+        Ok(self.get(id)?.position_to_angle(position as f32, bus.units_per_degree(id)))
+    }
+
+    /// Servo position that puts joint `id` where `position` would put a servo mounted directly on it
+    /// (see [`JointCalibration::direct`]). Uncalibrated servos keep `position`.
+    pub fn raw_position<B: ServoBusProtocol>(&self, bus: &B, id: u8, position: u16) -> Result<u16, Error> {
+        if self.calibration(id).is_none() {
+            return Ok(position);
+        }
+        let angle = JointCalibration::direct(bus, id).position_to_angle(position as f32, bus.units_per_degree(id));
+        self.position_for(bus, id, angle)
+    }
+
+    /// Inverse of [`raw_position`](Self::raw_position): the directly mounted servo position matching the servo
+    /// position `raw`, clamped to the servo range.
+    pub fn nominal_position<B: ServoBusProtocol>(&self, bus: &B, id: u8, raw: u16) -> u16 {
+        let calibration = match self.calibration(id) {
+            Some(calibration) => calibration,
+            None => return raw,
+        };
+        let units_per_degree = bus.units_per_degree(id);
+        let angle = calibration.position_to_angle(raw as f32, units_per_degree);
+        let position = JointCalibration::direct(bus, id).angle_to_position(angle, units_per_degree).round();
+        position.clamp(0.0, bus.position_max(id) as f32) as u16
+    }
+
+    /// View of `bus` through the calibration, for code that works in servo positions such as the
+    /// [sequence player](crate::sequence::player::SequencePlayer). See [`CalibratedBus`].
+    pub fn bus<'a, B: ServoBusProtocol>(&'a self, bus: &'a mut B) -> CalibratedBus<'a, B> {
+        CalibratedBus { bus, joints: self }
+    }
+
+    /// Move joint `id` to `angle` radians in `time_ms` milliseconds.
+    pub fn move_to<B: ServoBusProtocol>(&self, bus: &mut B, id: u8, angle: f32, time_ms: u16) -> Result<(), Error> {
+        let position = self.position_for(bus, id, angle)?;
+        bus.move_to_position(id, position, time_ms)
+    }
+
+    /// Move several joints together. Every angle is checked before any servo is commanded.
+    pub fn move_all<B: ServoBusProtocol>(&self, bus: &mut B, angles: &[(u8, f32)], time_ms: u16) -> Result<(), Error> {
+        let mut positions = Vec::with_capacity(angles.len());
+        for (id, angle) in angles {
+            positions.push((*id, self.position_for(bus, *id, *angle)?));
+        }
+        for (id, position) in positions {
+            bus.move_to_position(id, position, time_ms)?;
+        }
+        Ok(())
+    }
+
+    /// Read the angle of joint `id` in radians.
+    pub fn read<B: ServoBusProtocol>(&self, bus: &mut B, id: u8) -> Result<f32, Error> {
+        let calibration = *self.get(id)?;
+        let position = bus.read_position(id)?;
+        Ok(calibration.position_to_angle(position as f32, bus.units_per_degree(id)))
+    }
+
+    /// Calibrate the zero of joint `id` at the servo's current position, e.g. after posing the arm by hand
+    /// in its reference posture. Returns the new zero.
+    pub fn set_zero_here<B: ServoBusProtocol>(&mut self, bus: &mut B, id: u8) -> Result<f32, Error> {
+        let position = bus.read_position(id)? as f32;
+        let calibration = self.calibrations.get_mut(&id).ok_or(JointError::Uncalibrated { id })?;
+        calibration.zero = position;
+        info!("Joint {}: zero set at servo position {}", id, position);
+        Ok(position)
+    }
+
+    fn get(&self, id: u8) -> Result<&JointCalibration, Error> {
+        self.calibrations.get(&id).ok_or_else(|| JointError::Uncalibrated { id }.into())
+    }
+}
+
+/// A servo bus whose positions are those of servos mounted directly on their joints.
+///
+/// Positions written and read are converted with [`Joints::raw_position`] and [`Joints::nominal_position`], so a
+/// sequence authored on one arm plays the same on another with offset, inverted or geared servos, and the joint
+/// software limits are enforced. Servos without a calibration pass through unchanged.
+pub struct CalibratedBus<'a, B> {
+    bus: &'a mut B,
+    joints: &'a Joints,
+}
+
+impl<B: ServoBusProtocol> ServoBusProtocol for CalibratedBus<'_, B> {
+    fn position_max(&self, id: u8) -> u16 {
+        self.bus.position_max(id)
+    }
+
+    fn units_per_degree(&self, id: u8) -> f32 {
+        self.bus.units_per_degree(id)
+    }
+
+    fn move_to_position(&mut self, id: u8, position: u16, time_ms: u16) -> Result<(), Error> {
+        let raw = self.joints.raw_position(&*self.bus, id, position)?;
+        self.bus.move_to_position(id, raw, time_ms)
+    }
+
+    fn ping(&mut self, id: u8) -> Result<(), Error> {
+        self.bus.ping(id)
+    }
+
+    fn read_position(&mut self, id: u8) -> Result<u16, Error> {
+        let raw = self.bus.read_position(id)?;
+        Ok(self.joints.nominal_position(&*self.bus, id, raw))
+    }
+
+    fn set_torque(&mut self, id: u8, enable: bool) -> Result<(), Error> {
+        self.bus.set_torque(id, enable)
+    }
+
+    fn set_mode(&mut self, id: u8, mode: ServoMode) -> Result<(), Error> {
+        self.bus.set_mode(id, mode)
+    }
+
+    fn read_mode(&mut self, id: u8) -> Result<ServoMode, Error> {
+        self.bus.read_mode(id)
+    }
+
+    fn set_position_limits(&mut self, id: u8, min: u16, max: u16) -> Result<(), Error> {
+        let mut min = self.joints.raw_position(&*self.bus, id, min)?;
+        let mut max = self.joints.raw_position(&*self.bus, id, max)?;
+        // An inverted joint swaps the ends
+        if min > max {
+            core::mem::swap(&mut min, &mut max);
+        }
+        self.bus.set_position_limits(id, min, max)
+    }
+
+    fn emergency_stop(&mut self) -> Result<(), Error> {
+        self.bus.emergency_stop()
+    }
+}
+
+/// Joint calibration persisted in NVS as one JSON document.
+pub struct JointStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl JointStore {
+    /// Open (or create) the joint namespace on the default NVS partition.
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self, Error> {
+        let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+        Ok(JointStore { nvs })
+    }
+
+    /// Load the stored calibration; empty if none has been saved yet. Fails with [`JointError::InvalidCalibration`]
+    /// if a stored calibration cannot be used.
+    pub fn load(&self) -> Result<Joints, Error> {
+        let len = match self.nvs.str_len(CALIBRATION_KEY)? {
+            Some(len) => len,
+            None => return Ok(Joints::new()),
+        };
+        let mut buf = vec![0u8; len + 1];
+        let joints: Joints = match self.nvs.get_str(CALIBRATION_KEY, &mut buf)? {
+            Some(json) => serde_json::from_str(json)?,
+            None => return Ok(Joints::new()),
+        };
+        for (id, calibration) in &joints.calibrations {
+            calibration.validate(*id)?;
+        }
+        Ok(joints)
+    }
+
+    /// Store the calibration of every joint, replacing the previous one.
+    pub fn save(&mut self, joints: &Joints) -> Result<(), Error> {
+        self.nvs.set_str(CALIBRATION_KEY, &serde_json::to_string(joints)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CALIBRATION: JointCalibration = JointCalibration {
+        zero: 500.0,
+        inverted: true,
+        gear_ratio: 2.0,
+        min_rad: -1.0,
+        max_rad: 1.0,
+    };
+
+    #[test]
+    fn angle_and_position_round_trip() {
+        for angle in [-1.0, -0.25, 0.0, 0.5, 1.0] {
+            let position = CALIBRATION.angle_to_position(angle, 4.17);
+            assert!((CALIBRATION.position_to_angle(position, 4.17) - angle).abs() < 1e-5);
+        }
+        // Inverted: positive angles lower the servo position
+        assert!(CALIBRATION.angle_to_position(0.5, 4.17) < CALIBRATION.zero);
+    }
+
+    #[test]
+    fn unusable_calibrations_are_refused() {
+        let broken = [
+            JointCalibration { gear_ratio: 0.0, ..CALIBRATION },
+            JointCalibration { gear_ratio: f32::NAN, ..CALIBRATION },
+            JointCalibration { zero: f32::INFINITY, ..CALIBRATION },
+            JointCalibration { min_rad: 1.5, ..CALIBRATION },
+            JointCalibration { max_rad: f32::NAN, ..CALIBRATION },
+        ];
+        let mut joints = Joints::new();
+        joints.set_calibration(1, CALIBRATION).unwrap();
+        for calibration in broken {
+            let err = joints.set_calibration(1, calibration).unwrap_err();
+            assert!(matches!(err.downcast_ref::<JointError>(), Some(JointError::InvalidCalibration { id: 1, .. })));
+            assert_eq!(joints.calibration(1), Some(&CALIBRATION));
+        }
+    }
+}
@@ -0,0 +1,182 @@
+#![allow(dead_code)]
+use anyhow::Error;
+
+use crate::joint::Joints;
+use crate::servo_bus::ServoBusProtocol;
+
+/// Which of the two inverse kinematics solutions to use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Elbow {
+    /// Elbow above the line from the shoulder to the target (negative elbow angle).
+    Up,
+    /// Elbow below the line from the shoulder to the target (positive elbow angle).
+    Down,
+}
+
+/// Error returned when a target point is outside the arm's workspace.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Unreachable {
+    pub x: f32,
+    pub y: f32,
+    /// Distance from the shoulder to the target.
+    pub distance: f32,
+    /// Reachable distances from the shoulder: `|l1 - l2|..=l1 + l2`.
+    pub min_reach: f32,
+    pub max_reach: f32,
+}
+
+impl core::fmt::Display for Unreachable {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "point ({:.1}, {:.1}) is {:.1} from the shoulder, outside the reach {:.1}..={:.1}",
+            self.x, self.y, self.distance, self.min_reach, self.max_reach
+        )
+    }
+}
+
+impl std::error::Error for Unreachable {}
+
+/// Two-link planar arm: the shoulder servo turns the first link about the origin, the elbow servo turns the second link
+/// relative to the first.
+///
+/// Angles are joint radians as defined by the [`Joints`] calibration: 0 with the links stretched along +X,
+/// positive counter-clockwise. Lengths and coordinates share one unit (millimetres on our rig).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlanarArm2 {
+    pub shoulder: u8,
+    pub elbow: u8,
+    /// Shoulder-to-elbow length.
+    pub l1: f32,
+    /// Elbow-to-tip length.
+    pub l2: f32,
+}
+
+impl PlanarArm2 {
+    pub fn new(shoulder: u8, elbow: u8, l1: f32, l2: f32) -> Self {
+        PlanarArm2 { shoulder, elbow, l1, l2 }
+    }
+
+    /// End-effector position for shoulder angle `q1` and elbow angle `q2`.
+    pub fn forward(&self, q1: f32, q2: f32) -> (f32, f32) {
+        let x = self.l1 * q1.cos() + self.l2 * (q1 + q2).cos();
+        let y = self.l1 * q1.sin() + self.l2 * (q1 + q2).sin();
+        (x, y)
+    }
+
+    /// Joint angles `(q1, q2)` placing the end effector at `(x, y)` with the requested elbow configuration.
+    ///
+    /// # Returns
+    /// [`Unreachable`] wrapped in an [`anyhow::Error`] if the point is farther than `l1 + l2` or closer than `|l1 - l2|`.
+    pub fn inverse(&self, x: f32, y: f32, elbow: Elbow) -> Result<(f32, f32), Error> {
+        let (l1, l2) = (self.l1, self.l2);
+        let distance = x.hypot(y);
+        let cos_q2 = (distance * distance - l1 * l1 - l2 * l2) / (2.0 * l1 * l2);
+        // Allow a little rounding slack so points exactly on the workspace boundary stay reachable
+        if !(-1.0 - 1e-4..=1.0 + 1e-4).contains(&cos_q2) {
+            return Err(Unreachable {
+                x,
+                y,
+                distance,
+                min_reach: (l1 - l2).abs(),
+                max_reach: l1 + l2,
+            }
+            .into());
+        }
+        let q2 = match elbow {
+            Elbow::Up => -cos_q2.clamp(-1.0, 1.0).acos(),
+            Elbow::Down => cos_q2.clamp(-1.0, 1.0).acos(),
+        };
+        let q1 = y.atan2(x) - (l2 * q2.sin()).atan2(l1 + l2 * q2.cos());
+        Ok((q1, q2))
+    }
+
+    /// Move the end effector to `(x, y)` in `time_ms` milliseconds through the calibrated joints.
+    /// Both joints are checked against their limits before either servo moves. Returns the joint angles used.
+    pub fn move_to_xy<B: ServoBusProtocol>(
+        &self,
+        bus: &mut B,
+        joints: &Joints,
+        x: f32,
+        y: f32,
+        elbow: Elbow,
+        time_ms: u16,
+    ) -> Result<(f32, f32), Error> {
+        let (q1, q2) = self.inverse(x, y, elbow)?;
+        joints.move_all(bus, &[(self.shoulder, q1), (self.elbow, q2)], time_ms)?;
+        Ok((q1, q2))
+    }
+
+    /// Current end-effector position, from the calibrated joint readings.
+    pub fn read_xy<B: ServoBusProtocol>(&self, bus: &mut B, joints: &Joints) -> Result<(f32, f32), Error> {
+        let q1 = joints.read(bus, self.shoulder)?;
+        let q2 = joints.read(bus, self.elbow)?;
+        Ok(self.forward(q1, q2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::{FRAC_PI_2, PI};
+
+    const ARM: PlanarArm2 = PlanarArm2 { shoulder: 1, elbow: 2, l1: 100.0, l2: 80.0 };
+
+    fn assert_close(a: (f32, f32), b: (f32, f32)) {
+        assert!((a.0 - b.0).abs() < 1e-2 && (a.1 - b.1).abs() < 1e-2, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn forward_known_poses() {
+        assert_close(ARM.forward(0.0, 0.0), (180.0, 0.0));
+        assert_close(ARM.forward(FRAC_PI_2, 0.0), (0.0, 180.0));
+        assert_close(ARM.forward(0.0, FRAC_PI_2), (100.0, 80.0));
+        assert_close(ARM.forward(0.0, PI), (20.0, 0.0));
+    }
+
+    #[test]
+    fn inverse_then_forward_returns_the_point() {
+        for x in (-160..=160).step_by(20) {
+            for y in (-160..=160).step_by(20) {
+                let (x, y) = (x as f32, y as f32);
+                let distance = x.hypot(y);
+                if !(21.0..=179.0).contains(&distance) {
+                    continue;
+                }
+                for elbow in [Elbow::Up, Elbow::Down] {
+                    let (q1, q2) = ARM.inverse(x, y, elbow).unwrap();
+                    assert_close(ARM.forward(q1, q2), (x, y));
+                    match elbow {
+                        Elbow::Up => assert!(q2 <= 0.0),
+                        Elbow::Down => assert!(q2 >= 0.0),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn forward_then_inverse_returns_the_angles() {
+        for (q1, q2) in [(0.3, 0.8), (-1.2, 1.5), (2.0, -0.6), (0.0, -2.5)] {
+            let (x, y) = ARM.forward(q1, q2);
+            let elbow = if q2 < 0.0 { Elbow::Up } else { Elbow::Down };
+            assert_close(ARM.inverse(x, y, elbow).unwrap(), (q1, q2));
+        }
+    }
+
+    #[test]
+    fn workspace_boundary_is_reachable() {
+        assert_close(ARM.inverse(180.0, 0.0, Elbow::Up).unwrap(), (0.0, 0.0));
+        let (q1, q2) = ARM.inverse(0.0, 20.0, Elbow::Down).unwrap();
+        assert_close(ARM.forward(q1, q2), (0.0, 20.0));
+    }
+
+    #[test]
+    fn unreachable_points_are_rejected() {
+        for (x, y) in [(200.0, 0.0), (0.0, -181.0), (10.0, 5.0), (0.0, 0.0)] {
+            let err = ARM.inverse(x, y, Elbow::Up).unwrap_err();
+            let unreachable = err.downcast_ref::<Unreachable>().unwrap();
+            assert_eq!((unreachable.min_reach, unreachable.max_reach), (20.0, 180.0));
+        }
+    }
+}
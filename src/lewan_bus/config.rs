@@ -0,0 +1,246 @@
+use super::*;
+
+/// Persistent configuration of one LewanSoul servo, as read by [`LewanSoulBus::read_config`].
+///
+/// Applying the configuration read from one servo to its replacement makes the two behave identically.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServoConfig {
+    /// Servo ID (0-253).
+    pub id: u8,
+    /// Angle offset in position units (-125 to 125).
+    pub angle_offset: i8,
+    /// Angle limits in raw position units, `(min, max)`.
+    pub angle_limits: (u16, u16),
+    /// Input voltage limits in millivolts, `(min, max)`.
+    pub vin_limits_mv: (u16, u16),
+    /// Over-temperature limit in °C.
+    pub temp_max_c: u8,
+    /// Servo or motor mode. [`LewanSoulBus::apply_config`] restores motor mode stopped, whatever the speed.
+    pub mode: ServoMode,
+    /// LED alarm mask (`LED_ALARM_*` bits).
+    pub led_alarm: u8,
+}
+
+/// One field of a [`ServoConfig`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigField {
+    Id,
+    AngleOffset,
+    AngleLimits,
+    VinLimits,
+    TempMaxLimit,
+    Mode,
+    LedAlarm,
+}
+
+impl core::fmt::Display for ConfigField {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let name = match self {
+            ConfigField::Id => "id",
+            ConfigField::AngleOffset => "angle offset",
+            ConfigField::AngleLimits => "angle limits",
+            ConfigField::VinLimits => "voltage limits",
+            ConfigField::TempMaxLimit => "temperature limit",
+            ConfigField::Mode => "mode",
+            ConfigField::LedAlarm => "LED alarm",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A field that differed between the servo and the requested configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldChange {
+    pub field: ConfigField,
+    /// Value on the servo before applying, formatted for display.
+    pub from: String,
+    /// Requested value, formatted for display.
+    pub to: String,
+    /// True if reading the servo back after the write returned the requested value.
+    pub verified: bool,
+}
+
+/// Outcome of [`LewanSoulBus::apply_config`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigReport {
+    /// Configuration read before anything was written.
+    pub before: ServoConfig,
+    /// Configuration read back after writing.
+    pub after: ServoConfig,
+    /// Fields that were written, in write order. Empty if the servo already matched.
+    pub changes: Vec<FieldChange>,
+}
+
+impl ConfigReport {
+    /// True if every written field read back as requested.
+    pub fn is_verified(&self) -> bool {
+        self.changes.iter().all(|change| change.verified)
+    }
+}
+
+impl ServoConfig {
+    /// Fields whose values differ between `self` and `other`, in the order [`LewanSoulBus::apply_config`] writes them (ID last).
+    pub fn diff(&self, other: &ServoConfig) -> Vec<ConfigField> {
+        let mut fields = Vec::new();
+        if self.angle_offset != other.angle_offset { fields.push(ConfigField::AngleOffset); }
+        if self.angle_limits != other.angle_limits { fields.push(ConfigField::AngleLimits); }
+        if self.vin_limits_mv != other.vin_limits_mv { fields.push(ConfigField::VinLimits); }
+        if self.temp_max_c != other.temp_max_c { fields.push(ConfigField::TempMaxLimit); }
+        if self.mode != other.mode { fields.push(ConfigField::Mode); }
+        if self.led_alarm != other.led_alarm { fields.push(ConfigField::LedAlarm); }
+        if self.id != other.id { fields.push(ConfigField::Id); }
+        fields
+    }
+
+    /// Check every field against the ranges the setters used by [`LewanSoulBus::apply_config`] accept for `model`,
+    /// so a configuration from a file is refused before it is stored or half written.
+    pub fn validate(&self, model: &ServoModel) -> Result<(), Error> {
+        let id = self.id;
+        let invalid = |parameter| Err(BusError::InvalidParameter { id, parameter }.into());
+        if id > MAX_SERVO_ID {
+            return invalid("servo ID");
+        }
+        if !ANGLE_OFFSET_RANGE.contains(&self.angle_offset) {
+            return invalid("angle offset");
+        }
+        check_position_limits(id, self.angle_limits.0, self.angle_limits.1, model.position_max)?;
+        let (vin_min, vin_max) = self.vin_limits_mv;
+        if vin_min > vin_max
+            || !VIN_LIMIT_RANGE_MV.contains(&vin_min)
+            || !VIN_LIMIT_RANGE_MV.contains(&vin_max)
+            || !model.vin_limits_in_range(vin_min, vin_max)
+        {
+            return invalid("input voltage limits");
+        }
+        if !model.temp_limit_in_range(self.temp_max_c) {
+            return invalid("temperature limit");
+        }
+        if self.led_alarm > LED_ALARM_OVER_TEMPERATURE | LED_ALARM_OVER_VOLTAGE | LED_ALARM_LOCKED_ROTOR {
+            return invalid("LED alarm mask");
+        }
+        Ok(())
+    }
+
+    /// Display form of a single field's value.
+    pub fn field_value(&self, field: ConfigField) -> String {
+        match field {
+            ConfigField::Id => self.id.to_string(),
+            ConfigField::AngleOffset => self.angle_offset.to_string(),
+            ConfigField::AngleLimits => format!("{}..={}", self.angle_limits.0, self.angle_limits.1),
+            ConfigField::VinLimits => format!("{}..={} mV", self.vin_limits_mv.0, self.vin_limits_mv.1),
+            ConfigField::TempMaxLimit => format!("{} °C", self.temp_max_c),
+            ConfigField::Mode => format!("{:?}", self.mode),
+            ConfigField::LedAlarm => format!("0x{:02X}", self.led_alarm),
+        }
+    }
+}
+
+impl LewanSoulBus<'_> {
+    /// Read the full persistent configuration of a servo.
+    pub fn read_config(&mut self, id: u8) -> Result<ServoConfig, Error> {
+        Ok(ServoConfig {
+            id: self.read_id(id)?,
+            angle_offset: self.read_angle_offset(id)?,
+            angle_limits: self.read_angle_limits(id)?,
+            vin_limits_mv: self.read_vin_limits(id)?,
+            temp_max_c: self.read_temp_max_limit(id)?,
+            mode: self.read_mode(id)?,
+            led_alarm: self.read_led_alarm(id)?,
+        })
+    }
+
+    /// Make servo `id` match `config`, writing only the fields that differ, then read it back to verify.
+    ///
+    /// Motor mode is written with speed 0, so restoring a configuration never sets a wheel spinning; start it with
+    /// [`set_motor_speed`](Self::set_motor_speed) once the robot is ready.
+    ///
+    /// # Arguments
+    /// * `id` - Current ID of the servo to configure (0-253).
+    /// * `config` - Target configuration. If `config.id` differs from `id`, the ID is written last and the servo is verified at its new ID.
+    ///
+    /// # Returns
+    /// A [`ConfigReport`] listing every changed field and whether it verified, or an error if a read or write failed.
+    /// A field that was written but read back differently is reported with `verified: false` rather than as an error.
+    /// A configuration that fails [`ServoConfig::validate`] is refused before anything is written.
+    pub fn apply_config(&mut self, id: u8, config: &ServoConfig) -> Result<ConfigReport, Error> {
+        config.validate(self.model(id))?;
+        let config = &ServoConfig {
+            mode: match config.mode {
+                ServoMode::Motor { .. } => ServoMode::Motor { speed: 0 },
+                ServoMode::Position => ServoMode::Position,
+            },
+            ..*config
+        };
+        let before = self.read_config(id)?;
+        let fields = before.diff(config);
+
+        for field in &fields {
+            match field {
+                ConfigField::AngleOffset => self.set_angle_offset(id, config.angle_offset)?,
+                ConfigField::AngleLimits => self.set_position_limits(id, config.angle_limits.0, config.angle_limits.1)?,
+                ConfigField::VinLimits => self.set_vin_limits(id, config.vin_limits_mv.0, config.vin_limits_mv.1)?,
+                ConfigField::TempMaxLimit => self.set_temp_max_limit(id, config.temp_max_c)?,
+                ConfigField::Mode => self.set_mode(id, config.mode)?,
+                ConfigField::LedAlarm => self.set_led_alarm(id, config.led_alarm)?,
+                ConfigField::Id => self.set_id(id, config.id)?,
+            }
+        }
+
+        let after = self.read_config(config.id)?;
+        let changes = fields
+            .into_iter()
+            .map(|field| FieldChange {
+                field,
+                from: before.field_value(field),
+                to: config.field_value(field),
+                verified: after.field_value(field) == config.field_value(field),
+            })
+            .collect::<Vec<_>>();
+
+        for change in changes.iter().filter(|change| !change.verified) {
+            log::warn!(
+                "Servo {}: {} reads back as {} after writing {}",
+                config.id, change.field, after.field_value(change.field), change.to
+            );
+        }
+
+        Ok(ConfigReport { before, after, changes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: ServoConfig = ServoConfig {
+        id: 7,
+        angle_offset: -20,
+        angle_limits: (100, 900),
+        vin_limits_mv: (6500, 8400),
+        temp_max_c: 85,
+        mode: ServoMode::Position,
+        led_alarm: LED_ALARM_OVER_TEMPERATURE | LED_ALARM_LOCKED_ROTOR,
+    };
+
+    #[test]
+    fn accepts_a_config_read_from_a_servo() {
+        CONFIG.validate(&LX_16A).unwrap();
+    }
+
+    #[test]
+    fn rejects_out_of_range_fields() {
+        let invalid = [
+            ServoConfig { id: BROADCAST_ID, ..CONFIG },
+            ServoConfig { angle_offset: -126, ..CONFIG },
+            ServoConfig { angle_limits: (900, 100), ..CONFIG },
+            ServoConfig { angle_limits: (0, 1001), ..CONFIG },
+            ServoConfig { vin_limits_mv: (8400, 6500), ..CONFIG },
+            ServoConfig { vin_limits_mv: (4500, 8400), ..CONFIG },
+            ServoConfig { temp_max_c: 120, ..CONFIG },
+            ServoConfig { led_alarm: 0x08, ..CONFIG },
+        ];
+        for config in invalid {
+            assert!(config.validate(&LX_16A).is_err(), "{:?} accepted", config);
+        }
+    }
+}
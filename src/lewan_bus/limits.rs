@@ -0,0 +1,174 @@
+use super::*;
+use std::time::Instant;
+
+/// Number of violations kept by [`LewanSoulBus::limit_violations`]; older ones are dropped.
+const VIOLATION_LOG_LEN: usize = 32;
+
+/// What to do with a move that breaks a [`SoftLimits`] entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitPolicy {
+    /// Refuse the move with [`BusError::SoftLimit`]; nothing is sent.
+    Reject,
+    /// Clamp the position into range (and stretch the move time to respect the velocity limit), log a warning and send it.
+    Clamp,
+}
+
+/// Which limit a move broke.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitKind {
+    Position,
+    Velocity,
+}
+
+/// Software limits of one servo, checked by [`LewanSoulBus::move_to_position`] before anything is put on the wire.
+///
+/// Unlike the firmware angle limits they live in the controller, so they survive a servo swap and can be tighter than
+/// the mechanical range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SoftLimits {
+    /// Allowed position range in raw units.
+    pub min: u16,
+    pub max: u16,
+    /// Maximum average speed of a move in raw units per second, from the last commanded position.
+    pub max_velocity: Option<f32>,
+    pub policy: LimitPolicy,
+}
+
+/// A move that broke a soft limit, as recorded in the violation log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LimitViolation {
+    pub id: u8,
+    pub kind: LimitKind,
+    /// Position and move time that were requested.
+    pub requested: (u16, u16),
+    /// Position and move time actually sent, or `None` if the move was rejected.
+    pub sent: Option<(u16, u16)>,
+    pub at: Instant,
+}
+
+impl LewanSoulBus<'_> {
+    /// Set the software limits of servo `id`, replacing any previous ones.
+    pub fn set_soft_limits(&mut self, id: u8, limits: SoftLimits) -> Result<(), Error> {
+        if id == BROADCAST_ID {
+            return Err(BusError::InvalidParameter { id, parameter: "soft limit ID" }.into());
+        }
+        let position_max = self.model(id).position_max;
+        if limits.min > limits.max || limits.max > position_max {
+            return Err(BusError::InvalidParameter { id, parameter: "soft position limits" }.into());
+        }
+        // NaN would make every move time pass the check, silently disabling the limit
+        if limits.max_velocity.is_some_and(|velocity| !(velocity.is_finite() && velocity > 0.0)) {
+            return Err(BusError::InvalidParameter { id, parameter: "soft velocity limit" }.into());
+        }
+        self.soft_limits.insert(id, limits);
+        Ok(())
+    }
+
+    /// Remove the software limits of servo `id`.
+    pub fn clear_soft_limits(&mut self, id: u8) {
+        self.soft_limits.remove(&id);
+    }
+
+    /// The software limits of servo `id`, if any.
+    pub fn soft_limits(&self, id: u8) -> Option<&SoftLimits> {
+        self.soft_limits.get(&id)
+    }
+
+    /// The most recent limit violations, oldest first.
+    pub fn limit_violations(&self) -> impl Iterator<Item = &LimitViolation> {
+        self.violations.iter()
+    }
+
+    /// Forget the recorded limit violations.
+    pub fn clear_limit_violations(&mut self) {
+        self.violations.clear();
+    }
+
+    /// Apply the soft limits to a move and return the position and time to send.
+    ///
+    /// Broadcast moves reach every servo, so they are checked against the position limits of every configured servo.
+    /// The velocity check needs the servo's starting position: the last commanded one, or a position read if unknown.
+    pub(super) fn check_move(&mut self, id: u8, position: u16, time_ms: u16) -> Result<(u16, u16), Error> {
+        if id == BROADCAST_ID {
+            let ids: Vec<u8> = self.soft_limits.keys().copied().collect();
+            let mut sent = (position, time_ms);
+            for limited in ids {
+                sent.0 = self.check_position(limited, sent.0, time_ms)?;
+            }
+            return Ok(sent);
+        }
+        let limits = match self.soft_limits.get(&id) {
+            Some(limits) => *limits,
+            None => return Ok((position, time_ms)),
+        };
+        let position = self.check_position(id, position, time_ms)?;
+
+        let max_velocity = match limits.max_velocity {
+            Some(max_velocity) => max_velocity,
+            None => return Ok((position, time_ms)),
+        };
+        let from = match self.last_commanded.get(&id) {
+            Some(from) => *from,
+            None => self.read_position(id)?,
+        };
+        let distance = position.abs_diff(from) as f32;
+        // Shortest move time that keeps the average speed within the limit
+        let min_time_ms = (distance / max_velocity * 1000.0).ceil().min(u16::MAX as f32) as u16;
+        if time_ms >= min_time_ms {
+            return Ok((position, time_ms));
+        }
+        match limits.policy {
+            LimitPolicy::Reject => {
+                self.record_violation(id, LimitKind::Velocity, (position, time_ms), None);
+                Err(BusError::SoftLimit { id, kind: LimitKind::Velocity }.into())
+            }
+            LimitPolicy::Clamp => {
+                log::warn!(
+                    "Servo {}: move to {} in {} ms exceeds {} units/s, slowed to {} ms",
+                    id, position, time_ms, max_velocity, min_time_ms
+                );
+                self.record_violation(id, LimitKind::Velocity, (position, time_ms), Some((position, min_time_ms)));
+                Ok((position, min_time_ms))
+            }
+        }
+    }
+
+    /// Check `position` against the position limits of servo `id` and return the position to send.
+    fn check_position(&mut self, id: u8, position: u16, time_ms: u16) -> Result<u16, Error> {
+        let limits = match self.soft_limits.get(&id) {
+            Some(limits) => *limits,
+            None => return Ok(position),
+        };
+        if (limits.min..=limits.max).contains(&position) {
+            return Ok(position);
+        }
+        match limits.policy {
+            LimitPolicy::Reject => {
+                self.record_violation(id, LimitKind::Position, (position, time_ms), None);
+                Err(BusError::SoftLimit { id, kind: LimitKind::Position }.into())
+            }
+            LimitPolicy::Clamp => {
+                let clamped = position.clamp(limits.min, limits.max);
+                log::warn!(
+                    "Servo {}: position {} outside soft limits {}..={}, clamped to {}",
+                    id, position, limits.min, limits.max, clamped
+                );
+                self.record_violation(id, LimitKind::Position, (position, time_ms), Some((clamped, time_ms)));
+                Ok(clamped)
+            }
+        }
+    }
+
+    fn record_violation(&mut self, id: u8, kind: LimitKind, requested: (u16, u16), sent: Option<(u16, u16)>) {
+        if self.violations.len() == VIOLATION_LOG_LEN {
+            self.violations.pop_front();
+        }
+        self.violations.push_back(LimitViolation {
+            id,
+            kind,
+            requested,
+            sent,
+            at: Instant::now(),
+        });
+    }
+}
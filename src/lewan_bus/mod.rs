@@ -4,6 +4,19 @@ use esp_idf_hal::peripheral::Peripheral;
 use esp_idf_hal::gpio::{OutputPin, InputPin, AnyIOPin};
 use esp_idf_sys::esp_timer_get_time;
 use anyhow::Error;
+use std::collections::{HashMap, VecDeque};
+use crate::estop::EStop;
+use crate::servo_bus::{check_position, check_position_limits, ServoBusProtocol, SpeedOutOfRange, MOTOR_SPEED_RANGE};
+use serde::{Deserialize, Serialize};
+
+pub mod config;
+pub mod limits;
+pub mod model;
+mod packet;
+use limits::{LimitKind, LimitViolation, SoftLimits};
+// Every profile is re-exported so callers can pick one for `set_model` without reaching into the module
+#[allow(unused_imports)]
+pub use model::{ServoModel, HTS_35H, LX_15D, LX_16A, LX_224};
 
 /// Constants for servo command codes (from LewanSoul LX-16A protocol)
 const CMD_MOVE_TIME_WRITE: u8 = 1;       // Move servo to position with time
@@ -28,6 +41,25 @@ const CMD_OR_MOTOR_MODE_WRITE: u8 = 29;   // Switch servo (position) or motor (c
 const CMD_OR_MOTOR_MODE_READ: u8 = 30;    // Read servo/motor mode status
 const CMD_LOAD_OR_UNLOAD_WRITE: u8 = 31;  // Load or unload motor (enable/disable torque)
 const CMD_LOAD_OR_UNLOAD_READ: u8 = 32;   // Read torque enable status
+const CMD_LED_ERROR_WRITE: u8 = 35;       // Set which faults make the LED flash (alarm mask)
+const CMD_LED_ERROR_READ: u8 = 36;        // Read LED alarm mask
+
+/// Broadcast ID: every servo on the bus executes the command but none of them replies.
+pub const BROADCAST_ID: u8 = 254;
+
+/// Highest ID that can be assigned to a servo.
+pub const MAX_SERVO_ID: u8 = 253;
+
+/// Angle offset range accepted by `ANGLE_OFFSET_ADJUST` (±125 units ≈ ±30°).
+pub const ANGLE_OFFSET_RANGE: core::ops::RangeInclusive<i8> = -125..=125;
+
+/// Input voltage limit range accepted by `VIN_LIMIT_WRITE`, in millivolts.
+pub const VIN_LIMIT_RANGE_MV: core::ops::RangeInclusive<u16> = 4500..=12000;
+
+/// LED alarm mask bits for [`LewanSoulBus::set_led_alarm`].
+pub const LED_ALARM_OVER_TEMPERATURE: u8 = 0x01;
+pub const LED_ALARM_OVER_VOLTAGE: u8 = 0x02;
+pub const LED_ALARM_LOCKED_ROTOR: u8 = 0x04;
 
 const MAX_PKT: usize = 16;          // fits every documented command   ﹡
 
@@ -37,6 +69,94 @@ const WIRE_LATENCY_MS: u32 = 30;
 
 
 
+/// Protocol-level errors reported by [`LewanSoulBus`].
+///
+/// These are returned wrapped in an [`anyhow::Error`]; use `err.downcast_ref::<BusError>()` to match on them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusError {
+    /// A read command was addressed to the broadcast ID (254). No servo answers a broadcast,
+    /// so the request is rejected before anything is put on the wire instead of timing out.
+    BroadcastRead { command: u8 },
+    /// The servo model attached to `id` does not implement `command`.
+    UnsupportedCommand { id: u8, command: u8, model: &'static str },
+    /// A configuration parameter is outside the range the servo accepts.
+    InvalidParameter { id: u8, parameter: &'static str },
+    /// Another servo already answers at the ID a servo was about to be given.
+    IdInUse { id: u8 },
+    /// A move broke the servo's [`SoftLimits`] and its policy is [`Reject`](limits::LimitPolicy::Reject).
+    SoftLimit { id: u8, kind: LimitKind },
+}
+
+impl core::fmt::Display for BusError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BusError::BroadcastRead { command } => write!(
+                f,
+                "read command {} cannot be sent to broadcast ID {}",
+                command, BROADCAST_ID
+            ),
+            BusError::UnsupportedCommand { id, command, model } => write!(
+                f,
+                "servo {} ({}) does not support command {}",
+                id, model, command
+            ),
+            BusError::InvalidParameter { id, parameter } => write!(
+                f,
+                "{} for servo {} is outside the accepted range",
+                parameter, id
+            ),
+            BusError::IdInUse { id } => write!(f, "another servo already answers at ID {}", id),
+            BusError::SoftLimit { id, kind } => write!(
+                f,
+                "move of servo {} rejected by its soft {} limit",
+                id,
+                match kind {
+                    LimitKind::Position => "position",
+                    LimitKind::Velocity => "velocity",
+                }
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BusError {}
+
+/// Operating mode of a servo, as written by [`LewanSoulBus::set_mode`] and returned by [`LewanSoulBus::read_mode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ServoMode {
+    /// Standard servo mode: the servo holds the commanded position.
+    Position,
+    /// Continuous rotation mode at `speed` (-1000 to 1000, sign gives direction).
+    Motor { speed: i16 },
+}
+
+impl ServoMode {
+    /// Encode the mode as the 4 parameter bytes of `SERVO_OR_MOTOR_MODE_WRITE`: mode (0 or 1), an unused zero byte, speed low, speed high.
+    fn to_params(self) -> Result<[u8; 4], Error> {
+        let (mode_byte, speed) = match self {
+            ServoMode::Position => (0u8, 0i16),
+            ServoMode::Motor { speed } => {
+                if !MOTOR_SPEED_RANGE.contains(&speed) {
+                    return Err(SpeedOutOfRange { speed }.into());
+                }
+                (1u8, speed)
+            }
+        };
+        // The speed goes on the wire as two's complement little-endian
+        let [speed_low, speed_high] = speed.to_le_bytes();
+        Ok([mode_byte, 0u8, speed_low, speed_high])
+    }
+
+    /// Decode the 4 parameter bytes of a `SERVO_OR_MOTOR_MODE_READ` reply.
+    fn from_params(params: &[u8]) -> Result<Self, Error> {
+        match params {
+            [0, _, _, _] => Ok(ServoMode::Position),
+            [1, _, low, high] => Ok(ServoMode::Motor { speed: i16::from_le_bytes([*low, *high]) }),
+            [mode, ..] => Err(anyhow::anyhow!("Unknown servo mode byte {}", mode)),
+            [] => Err(anyhow::anyhow!("Empty servo mode reply")),
+        }
+    }
+}
 
 /// A controller for LewanSoul serial bus servos (e.g. LX-16A, LX-15D) on a half-duplex UART bus.
 /// 
@@ -45,9 +165,20 @@ const WIRE_LATENCY_MS: u32 = 30;
 /// and switching between servo (position) mode and motor (continuous rotation) mode.
 /// 
 /// The bus supports up to 253 servos with IDs 0-253, plus a broadcast ID 254 (0xFE) for addressing all servos&#8203;:contentReference[oaicite:3]{index=3}.
+/// Each ID has a [`ServoModel`] profile (LX-16A unless set otherwise with [`set_model`](Self::set_model)) that drives
+/// degree conversions and parameter validation, so LX-16A, LX-224, LX-15D and HTS-series servos can share one bus.
+/// Moves are checked against per-ID [`SoftLimits`] (see [`set_soft_limits`](Self::set_soft_limits)) before they are sent.
 /// All communication uses 115200 baud, with a packet format of two 0x55 header bytes followed by length, command, ID, parameters, and checksum&#8203;:contentReference[oaicite:4]{index=4}.
 pub struct LewanSoulBus<'a> {
     uart: UartDriver<'a>,
+    default_model: ServoModel,
+    models: HashMap<u8, ServoModel>,
+    soft_limits: HashMap<u8, SoftLimits>,
+    /// Last position sent to each servo while it holds position, for the soft velocity limit.
+    last_commanded: HashMap<u8, u16>,
+    violations: VecDeque<LimitViolation>,
+    /// Blocks motion commands while latched.
+    estop: Option<EStop>,
 }
 
 impl<'a> LewanSoulBus<'a> {
@@ -87,33 +218,65 @@ impl<'a> LewanSoulBus<'a> {
             Option::<AnyIOPin>::None,  // RTS pin not used 
             config
         )?;
-        Ok(LewanSoulBus { uart: driver })
+        Ok(LewanSoulBus {
+            uart: driver,
+            default_model: LX_16A,
+            models: HashMap::new(),
+            soft_limits: HashMap::new(),
+            last_commanded: HashMap::new(),
+            violations: VecDeque::new(),
+            estop: None,
+        })
+    }
+
+    /// Attach a model profile to a servo ID. IDs without an explicit model use the default model (LX-16A).
+    pub fn set_model(&mut self, id: u8, model: ServoModel) {
+        self.models.insert(id, model);
+    }
+
+    /// Change the model used for IDs that have no explicit profile (and for broadcasts).
+    pub fn set_default_model(&mut self, model: ServoModel) {
+        self.default_model = model;
+    }
+
+    /// Refuse moves, motor mode and torque enable with [`EStopActive`](crate::estop::EStopActive) while `estop` is latched.
+    pub fn attach_estop(&mut self, estop: EStop) {
+        self.estop = Some(estop);
+    }
+
+    /// The model profile used for the given servo ID.
+    pub fn model(&self, id: u8) -> &ServoModel {
+        self.models.get(&id).unwrap_or(&self.default_model)
     }
 
     /// Move a servo to a specified angle (position) within a given time.
     /// 
     /// # Arguments
     /// * `id` - Servo ID (0-253 for specific servo, or 254 for broadcast to all servos).
-    /// * `angle` - Target angle in degrees (approximately 0° to 240° range corresponds to 0-1000 position units&#8203;:contentReference[oaicite:5]{index=5}).
+    /// * `angle` - Target angle in degrees (for an LX-16A, 0° to 240° corresponds to 0-1000 position units&#8203;:contentReference[oaicite:5]{index=5}).
     /// * `time_ms` - Movement time in milliseconds. If nonzero, the servo will move to the target angle in this time (uniform speed). If 0, the servo moves as fast as possible.
     /// 
     /// # Returns
     /// `Ok(())` on success, or an error if the command failed to send.
     /// 
-    /// The servo's internal position units range from 0 to 1000 for approximately 0° to 240°&#8203;:contentReference[oaicite:6]{index=6}. This function converts the given `angle` (in degrees) to the nearest position unit and sends a move command. 
+    /// The angle is converted to the nearest position unit using the servo's [`ServoModel`] and clamped to its range&#8203;:contentReference[oaicite:6]{index=6}, then a move command is sent. 
     /// If broadcast ID 254 is used, all servos will move but none will return a response (to avoid bus conflict)&#8203;:contentReference[oaicite:7]{index=7}.
     pub fn move_to_angle(&mut self, id: u8, angle: f32, time_ms: u16) -> Result<(), Error> {
-        // Constrain and convert angle to position units (0-1000 corresponds to 0-240 degrees approximately)
-        let mut pos = (angle / 240.0 * 1000.0).round() as i16;
-        if pos < 0 { pos = 0; }
-        if pos > 1000 { pos = 1000; }
-        self.move_to_position(id, pos as u16, time_ms)
+        // Constrain and convert angle to position units of this servo model
+        let pos = self.model(id).angle_to_position(angle);
+        self.move_to_position(id, pos, time_ms)
     }
 
-    /// Move a servo to a specified position (0-1000 units) within a given time (ms).
+    /// Move a servo to a specified position (0-1000 units on an LX-16A) within a given time (ms).
     /// 
     /// This is similar to [`move_to_angle`](Self::move_to_angle) but uses raw position units instead of degrees.
+    /// Positions beyond the model's `position_max` are rejected with
+    /// [`PositionOutOfRange`](crate::servo_bus::PositionOutOfRange), then the move is
+    /// checked against the servo's [`SoftLimits`], which may reject it with [`BusError::SoftLimit`] or clamp it.
     pub fn move_to_position(&mut self, id: u8, position: u16, time_ms: u16) -> Result<(), Error> {
+        self.check_estop()?;
+        check_position(id, position, self.model(id).position_max)?;
+        let (position, time_ms) = self.check_move(id, position, time_ms)?;
         // Prepare 4-byte parameters: position (little-endian 2 bytes) + time (little-endian 2 bytes)
         let pos_low = (position & 0x00FF) as u8;
         let pos_high = (position >> 8) as u8;
@@ -121,7 +284,13 @@ impl<'a> LewanSoulBus<'a> {
         let time_high = (time_ms >> 8) as u8;
         let params = [pos_low, pos_high, time_low, time_high];
         // Send command (no response expected for a move command)
-        self.send_packet(id, CMD_MOVE_TIME_WRITE, &params, false).map(|_| ())
+        self.write_command(id, CMD_MOVE_TIME_WRITE, &params)?;
+        if id == BROADCAST_ID {
+            self.last_commanded.values_mut().for_each(|last| *last = position);
+        } else {
+            self.last_commanded.insert(id, position);
+        }
+        Ok(())
     }
 
     /// Read the current position of a servo.
@@ -131,12 +300,12 @@ impl<'a> LewanSoulBus<'a> {
     /// 
     /// # Returns
     /// On success, returns the current position as a value 0-1000 (which corresponds to 0° to 240° range).
-    /// Returns an error if the read fails or times out.
+    /// Returns an error if the read fails or times out, or [`BusError::BroadcastRead`] if `id` is [`BROADCAST_ID`].
     /// 
-    /// The position value returned can be converted to degrees if needed (approximately `position * 0.24` degrees per unit).
+    /// The position value returned can be converted to degrees with [`ServoModel::position_to_angle`], or use [`read_angle`](Self::read_angle).
     pub fn read_position(&mut self, id: u8) -> Result<u16, Error> {
         // Send position read command and expect a response packet with 2-byte position
-        let response = self.send_packet(id, CMD_POS_READ, &[], true)?;
+        let response = self.read_command(id, CMD_POS_READ, &[])?;
         // The response packet format: [0x55, 0x55, LENGTH, CMD, ID, pos_low, pos_high, CHECKSUM]
         // We parse the position from the response.
         if response.len() >= 7 {
@@ -150,6 +319,12 @@ impl<'a> LewanSoulBus<'a> {
         }
     }
 
+    /// Read the current position of a servo, converted to degrees using its [`ServoModel`].
+    pub fn read_angle(&mut self, id: u8) -> Result<f32, Error> {
+        let position = self.read_position(id)?;
+        Ok(self.model(id).position_to_angle(position))
+    }
+
     /// Enable or disable the servo motor torque (power).
     /// 
     /// # Arguments
@@ -162,69 +337,293 @@ impl<'a> LewanSoulBus<'a> {
     /// Disabling torque (unload) will stop driving the motor, letting the servo freewheel (no holding force), whereas enabling torque will allow the servo to hold position&#8203;:contentReference[oaicite:8]{index=8}.
     /// This setting does not persist after power-off.
     pub fn set_torque(&mut self, id: u8, enable: bool) -> Result<(), Error> {
+        if enable {
+            self.check_estop()?;
+        }
         let param = if enable { 1u8 } else { 0u8 };
-        self.send_packet(id, CMD_LOAD_OR_UNLOAD_WRITE, &[param], false).map(|_| ())
+        self.write_command(id, CMD_LOAD_OR_UNLOAD_WRITE, &[param])?;
+        if !enable {
+            // An unloaded servo can be moved by hand, so its last commanded position no longer says where it is
+            self.forget_position(id);
+        }
+        Ok(())
     }
 
     /// Set the minimum and maximum angle limits for a servo.
     /// 
     /// # Arguments
     /// * `id` - Servo ID to configure (0-253, 254 broadcast is not recommended for this command).
-    /// * `min_angle` - Minimum allowed angle in degrees (0-240).
-    /// * `max_angle` - Maximum allowed angle in degrees (0-240).
+    /// * `min_angle` - Minimum allowed angle in degrees (0-240 on an LX-16A).
+    /// * `max_angle` - Maximum allowed angle in degrees (0-240 on an LX-16A).
     /// 
     /// # Returns
     /// `Ok(())` on success, or an error if the command failed.
     /// 
-    /// The servo will constrain its movement within the specified angle range. The provided angles will be converted to the servo's internal units (0-1000). 
-    /// If either angle is out of range, it will be clamped to the valid 0-240° range.
+    /// The servo will constrain its movement within the specified angle range. The provided angles will be converted to the servo's internal units using its [`ServoModel`]. 
+    /// If either angle is out of range, it will be clamped to the model's angular range.
     pub fn set_angle_limits(&mut self, id: u8, min_angle: f32, max_angle: f32) -> Result<(), Error> {
-        // Convert degrees to 0-1000 units and clamp
-        let mut min_pos = (min_angle / 240.0 * 1000.0).round() as i32;
-        let mut max_pos = (max_angle / 240.0 * 1000.0).round() as i32;
-        if min_pos < 0 { min_pos = 0; }
-        if max_pos < 0 { max_pos = 0; }
-        if min_pos > 1000 { min_pos = 1000; }
-        if max_pos > 1000 { max_pos = 1000; }
-        if min_pos > max_pos {
-            core::mem::swap(&mut min_pos, &mut max_pos);
+        // Convert degrees to position units and clamp to the model's range
+        let model = self.model(id);
+        let mut min = model.angle_to_position(min_angle);
+        let mut max = model.angle_to_position(max_angle);
+        if min > max {
+            core::mem::swap(&mut min, &mut max);
         }
-        let min = min_pos as u16;
-        let max = max_pos as u16;
+        self.set_position_limits(id, min, max)
+    }
+
+    /// Set the minimum and maximum angle limits for a servo in raw position units.
+    /// 
+    /// This is similar to [`set_angle_limits`](Self::set_angle_limits) but takes raw units; both must be within the model's range and `min <= max`.
+    pub fn set_position_limits(&mut self, id: u8, min: u16, max: u16) -> Result<(), Error> {
+        check_position_limits(id, min, max, self.model(id).position_max)?;
         let params = [
             (min & 0xFF) as u8,
             (min >> 8) as u8,
             (max & 0xFF) as u8,
             (max >> 8) as u8,
         ];
-        self.send_packet(id, CMD_ANGLE_LIMIT_WRITE, &params, false).map(|_| ())
+        self.write_command(id, CMD_ANGLE_LIMIT_WRITE, &params)
     }
 
     /// Set the operating mode of the servo: positional (servo) mode or continuous rotation (motor) mode.
     /// 
     /// # Arguments
-    /// * `id` - Servo ID to configure.
-    /// * `motor_mode` - If true, enable continuous rotation mode (motor mode). If false, enable standard servo position mode.
-    /// * `speed` - In motor mode, the speed value (-1000 to 1000) for rotation. Positive values for one direction, negative for the opposite. Ignored in servo mode.
+    /// * `id` - Servo ID to configure (254 broadcasts the mode to all servos).
+    /// * `mode` - [`ServoMode::Position`] for standard servo mode, or [`ServoMode::Motor`] with a speed of -1000 to 1000 for continuous rotation.
     /// 
     /// # Returns
-    /// `Ok(())` on success, or an error if the command failed.
+    /// `Ok(())` on success, [`SpeedOutOfRange`] if the motor speed is outside [`MOTOR_SPEED_RANGE`], or an error if the command failed.
     /// 
-    /// In motor mode, the servo will not hold position but rotate continuously at the given speed. In servo mode, the servo holds its target position and the speed parameter is ignored.
-    /// The speed is specified as a signed value; it will be converted to the protocol format (two's complement) for transmission.    
-    pub fn set_mode(&mut self, id: u8, motor_mode: bool, speed: i16) -> Result<(), Error> {
-        let mode_byte = if motor_mode { 1u8 } else { 0u8 };
-        // The protocol expects a 4-byte parameter sequence: mode (0 or 1), a "zero" byte (unused), speed low, speed high.
-        let speed_value = speed as u16; // interpret the i16 as unsigned 16-bit (two's complement representation for negative values)
-        let params = [
-            mode_byte,
-            0u8, 
-            (speed_value & 0xFF) as u8,
-            (speed_value >> 8) as u8
-        ];
-        self.send_packet(id, CMD_OR_MOTOR_MODE_WRITE, &params, false).map(|_| ())
+    /// In motor mode, the servo will not hold position but rotate continuously at the given speed. Positive values rotate one direction, negative the opposite.
+    pub fn set_mode(&mut self, id: u8, mode: ServoMode) -> Result<(), Error> {
+        if let ServoMode::Motor { .. } = mode {
+            self.check_estop()?;
+        }
+        let params = mode.to_params()?;
+        self.write_command(id, CMD_OR_MOTOR_MODE_WRITE, &params)?;
+        if let ServoMode::Motor { .. } = mode {
+            self.forget_position(id);
+        }
+        Ok(())
+    }
+
+    /// Put a servo in motor (continuous rotation) mode at the given speed (-1000 to 1000).
+    /// 
+    /// Shorthand for `set_mode(id, ServoMode::Motor { speed })`, convenient for wheel-drive robots. A speed of 0 stops the wheel.
+    pub fn set_motor_speed(&mut self, id: u8, speed: i16) -> Result<(), Error> {
+        self.set_mode(id, ServoMode::Motor { speed })
+    }
+
+    /// Read the operating mode of a servo.
+    /// 
+    /// # Arguments
+    /// * `id` - Servo ID to read (0-253).
+    /// 
+    /// # Returns
+    /// The current [`ServoMode`], including the configured speed when in motor mode.
+    pub fn read_mode(&mut self, id: u8) -> Result<ServoMode, Error> {
+        let response = self.read_command(id, CMD_OR_MOTOR_MODE_READ, &[])?;
+        // The response packet format: [0x55, 0x55, ID, LENGTH, CMD, mode, 0, speed_low, speed_high, CHECKSUM]
+        if response.len() >= 10 {
+            ServoMode::from_params(&response[5..9])
+        } else {
+            Err(anyhow::anyhow!("Malformed response from servo response.len() == {}", response.len()))
+        }
+    }
+
+    /// Read the ID of a servo. Mostly useful as a presence check, since the ID has to be known to address it.
+    pub fn read_id(&mut self, id: u8) -> Result<u8, Error> {
+        let params = self.read_params(id, CMD_ID_READ, 1)?;
+        Ok(params[0])
+    }
+
+    /// Change the ID of a servo (`new_id` 0-253). The new ID is saved to the servo's flash and used from the next command on.
+    ///
+    /// `new_id` is pinged first: if a servo already answers there the change is refused with [`BusError::IdInUse`], since
+    /// two servos sharing an ID can no longer be told apart.
+    pub fn set_id(&mut self, id: u8, new_id: u8) -> Result<(), Error> {
+        if new_id > MAX_SERVO_ID {
+            return Err(BusError::InvalidParameter { id, parameter: "servo ID" }.into());
+        }
+        if new_id != id && self.read_id(new_id).is_ok() {
+            return Err(BusError::IdInUse { id: new_id }.into());
+        }
+        self.write_command(id, CMD_ID_WRITE, &[new_id])?;
+        if let Some(model) = self.models.remove(&id) {
+            self.models.insert(new_id, model);
+        }
+        if let Some(limits) = self.soft_limits.remove(&id) {
+            self.soft_limits.insert(new_id, limits);
+        }
+        if let Some(position) = self.last_commanded.remove(&id) {
+            self.last_commanded.insert(new_id, position);
+        }
+        Ok(())
+    }
+
+    /// Read the angle offset of a servo (-125 to 125 units).
+    pub fn read_angle_offset(&mut self, id: u8) -> Result<i8, Error> {
+        let params = self.read_params(id, CMD_ANGLE_OFFSET_READ, 1)?;
+        Ok(params[0] as i8)
+    }
+
+    /// Set the angle offset of a servo (-125 to 125 units) and save it to the servo's flash.
+    pub fn set_angle_offset(&mut self, id: u8, offset: i8) -> Result<(), Error> {
+        if !ANGLE_OFFSET_RANGE.contains(&offset) {
+            return Err(BusError::InvalidParameter { id, parameter: "angle offset" }.into());
+        }
+        // The adjust command takes effect immediately but is lost at power-off; the write command persists it
+        self.write_command(id, CMD_ANGLE_OFFSET_ADJUST, &[offset as u8])?;
+        self.write_command(id, CMD_ANGLE_OFFSET_WRITE, &[])
+    }
+
+    /// Read the angle limits of a servo in raw position units, as `(min, max)`.
+    pub fn read_angle_limits(&mut self, id: u8) -> Result<(u16, u16), Error> {
+        let params = self.read_params(id, CMD_ANGLE_LIMIT_READ, 4)?;
+        Ok((
+            u16::from_le_bytes([params[0], params[1]]),
+            u16::from_le_bytes([params[2], params[3]]),
+        ))
     }
 
+    /// Read the input voltage limits of a servo in millivolts, as `(min, max)`.
+    pub fn read_vin_limits(&mut self, id: u8) -> Result<(u16, u16), Error> {
+        let params = self.read_params(id, CMD_VIN_LIMIT_READ, 4)?;
+        Ok((
+            u16::from_le_bytes([params[0], params[1]]),
+            u16::from_le_bytes([params[2], params[3]]),
+        ))
+    }
+
+    /// Set the input voltage limits of a servo in millivolts (4500-12000). Outside them the servo unloads and flashes its LED.
+    /// 
+    /// Limits outside the model's rated supply are accepted, but logged as a warning.
+    pub fn set_vin_limits(&mut self, id: u8, min_mv: u16, max_mv: u16) -> Result<(), Error> {
+        if min_mv > max_mv || !VIN_LIMIT_RANGE_MV.contains(&min_mv) || !VIN_LIMIT_RANGE_MV.contains(&max_mv) {
+            return Err(BusError::InvalidParameter { id, parameter: "input voltage limits" }.into());
+        }
+        let model = self.model(id);
+        if !model.vin_limits_in_range(min_mv, max_mv) {
+            log::warn!(
+                "Servo {} ({}) voltage limits {}-{} mV are outside its rated supply {}-{} mV",
+                id, model.name, min_mv, max_mv, model.vin_min_mv, model.vin_max_mv
+            );
+        }
+        let [min_low, min_high] = min_mv.to_le_bytes();
+        let [max_low, max_high] = max_mv.to_le_bytes();
+        self.write_command(id, CMD_VIN_LIMIT_WRITE, &[min_low, min_high, max_low, max_high])
+    }
+
+    /// Read the over-temperature limit of a servo in °C.
+    pub fn read_temp_max_limit(&mut self, id: u8) -> Result<u8, Error> {
+        let params = self.read_params(id, CMD_TEMP_MAX_LIMIT_READ, 1)?;
+        Ok(params[0])
+    }
+
+    /// Set the over-temperature limit of a servo in °C, within the range accepted by its model (50-100 °C on an LX-16A).
+    pub fn set_temp_max_limit(&mut self, id: u8, temp_c: u8) -> Result<(), Error> {
+        if !self.model(id).temp_limit_in_range(temp_c) {
+            return Err(BusError::InvalidParameter { id, parameter: "temperature limit" }.into());
+        }
+        self.write_command(id, CMD_TEMP_MAX_LIMIT_WRITE, &[temp_c])
+    }
+
+    /// Read the current internal temperature of a servo in °C.
+    pub fn read_temperature(&mut self, id: u8) -> Result<u8, Error> {
+        let params = self.read_params(id, CMD_TEMP_READ, 1)?;
+        Ok(params[0])
+    }
+
+    /// Read the current input voltage of a servo in millivolts.
+    pub fn read_vin(&mut self, id: u8) -> Result<u16, Error> {
+        let params = self.read_params(id, CMD_VIN_READ, 2)?;
+        Ok(u16::from_le_bytes([params[0], params[1]]))
+    }
+
+    /// Read whether the motor of a servo is loaded (torque enabled).
+    pub fn read_load_state(&mut self, id: u8) -> Result<bool, Error> {
+        let params = self.read_params(id, CMD_LOAD_OR_UNLOAD_READ, 1)?;
+        Ok(params[0] != 0)
+    }
+
+    /// Read the LED alarm mask of a servo (see the `LED_ALARM_*` constants).
+    pub fn read_led_alarm(&mut self, id: u8) -> Result<u8, Error> {
+        let params = self.read_params(id, CMD_LED_ERROR_READ, 1)?;
+        Ok(params[0])
+    }
+
+    /// Select which faults make the servo's LED flash, as a mask of the `LED_ALARM_*` constants.
+    pub fn set_led_alarm(&mut self, id: u8, mask: u8) -> Result<(), Error> {
+        if mask > LED_ALARM_OVER_TEMPERATURE | LED_ALARM_OVER_VOLTAGE | LED_ALARM_LOCKED_ROTOR {
+            return Err(BusError::InvalidParameter { id, parameter: "LED alarm mask" }.into());
+        }
+        self.write_command(id, CMD_LED_ERROR_WRITE, &[mask])
+    }
+
+    /// Stop the motion of a servo where it is (`MOVE_STOP`). Torque stays on.
+    pub fn stop(&mut self, id: u8) -> Result<(), Error> {
+        self.write_command(id, CMD_MOVE_STOP, &[])?;
+        self.forget_position(id);
+        Ok(())
+    }
+
+    fn check_estop(&self) -> Result<(), Error> {
+        match &self.estop {
+            Some(estop) => estop.check(),
+            None => Ok(()),
+        }
+    }
+
+    /// Forget the last commanded position of `id` (of every servo for a broadcast).
+    fn forget_position(&mut self, id: u8) {
+        if id == BROADCAST_ID {
+            self.last_commanded.clear();
+        } else {
+            self.last_commanded.remove(&id);
+        }
+    }
+
+    /// Send a read command and return exactly `count` parameter bytes from the reply.
+    fn read_params(&mut self, id: u8, command: u8, count: usize) -> Result<Vec<u8>, Error> {
+        let response = self.read_command(id, command, &[])?;
+        // The response packet format: [0x55, 0x55, ID, LENGTH, CMD, params.., CHECKSUM]
+        if response.len() < 6 + count {
+            return Err(anyhow::anyhow!("Malformed response from servo response.len() == {}", response.len()));
+        }
+        Ok(response[5..5 + count].to_vec())
+    }
+
+    /// Send a write command. Writes never produce a reply, so this works for unicast and broadcast IDs alike.
+    fn write_command(&mut self, id: u8, command: u8, params: &[u8]) -> Result<(), Error> {
+        self.check_supported(id, command)?;
+        self.send_packet(id, command, params, false).map(|_| ())
+    }
+
+    /// Send a read command and return the validated reply packet.
+    ///
+    /// Rejects [`BROADCAST_ID`] with [`BusError::BroadcastRead`] since no servo would answer.
+    fn read_command(&mut self, id: u8, command: u8, params: &[u8]) -> Result<Vec<u8>, Error> {
+        if id == BROADCAST_ID {
+            return Err(BusError::BroadcastRead { command }.into());
+        }
+        self.check_supported(id, command)?;
+        self.send_packet(id, command, params, true)
+    }
+
+    /// Reject commands the servo's model does not implement. Broadcasts may reach mixed models, so they are not checked.
+    fn check_supported(&self, id: u8, command: u8) -> Result<(), Error> {
+        let model = self.model(id);
+        if id != BROADCAST_ID && !model.supports(command) {
+            return Err(BusError::UnsupportedCommand { id, command, model: model.name }.into());
+        }
+        Ok(())
+    }
+
+    /// Send a raw packet and, if `want_reply` is set, wait for and validate the servo's reply.
+    ///
+    /// Asking for a reply from [`BROADCAST_ID`] fails immediately with [`BusError::BroadcastRead`].
+    /// Broadcast writes return as soon as the packet is written: there is no reply to wait for and no echo handling is done.
     pub fn send_packet(
         &mut self,
         id: u8,
@@ -233,35 +632,22 @@ impl<'a> LewanSoulBus<'a> {
         want_reply: bool,
     ) -> anyhow::Result<Vec<u8>> {
         /* ---------- 1 · Format packet ---------- */
-        let mut tx: [u8; MAX_PKT] = [0; MAX_PKT];
-        let len: u8 = 3 + params.len() as u8;   // CMD+ID+PARAMS+CHK
-        
-        // Build the packet: header + ID + length + command + params + checksum
-        let mut idx = 0;
-        tx[idx..idx + 2].copy_from_slice(&[0x55, 0x55]); idx += 2; // Header bytes
-        tx[idx] = id;                  idx += 1;
-        tx[idx] = len;                 idx += 1;
-        tx[idx] = command;             idx += 1;
-        tx[idx..idx + params.len()].copy_from_slice(params); idx += params.len();
+        let tx = packet::encode(id, command, params, want_reply)?;
+        let tx_len = tx.len();
     
-        // Calculate checksum: bitwise-NOT of sum(ID+LEN+CMD+PARAMS)
-        let chk = !(tx[2..idx].iter().fold(0u8, |s, b| s.wrapping_add(*b)));
-        tx[idx] = chk; idx += 1;
-        let tx_len = idx;
-    
-        println!("Sending packet: {:?}", &tx[..tx_len]);
+        println!("Sending packet: {:?}", tx);
         
         /* ---------- 2 · Send packet with flush ---------- */
         // Clear RX buffer to remove any stale data
         self.uart.clear_rx()?;
         
         // Write data and ensure it's sent completely
-        self.uart.write(&tx[..tx_len])?;
+        self.uart.write(&tx)?;
         
         // For daisy-chained servos with a single TX/RX cable, we need to handle echo
         // Since we're using the same wire for TX and RX, we'll see an echo of what we send
         
-        // If no reply expected, we're done after sending
+        // If no reply expected (every write, including all broadcasts), we're done after sending
         if !want_reply {
             return Ok(Vec::new());
         }
@@ -346,12 +732,59 @@ impl<'a> LewanSoulBus<'a> {
         println!("Received response: {:?}", &rx[..frame_len]);
         
         /* ---------- 4 · Validate checksum ---------- */
-        let chk_rx = rx[frame_len - 1];
-        let chk_calc = !(rx[2..frame_len - 1].iter().fold(0u8, |s, b| s.wrapping_add(*b)));
-        if chk_rx != chk_calc {
-            anyhow::bail!("Checksum error: received 0x{:02X}, calculated 0x{:02X}", chk_rx, chk_calc);
-        }
+        packet::validate_reply(&rx[..frame_len])?;
     
         Ok(rx[..frame_len].to_vec())
     }
-}
\ No newline at end of file
+}
+
+impl ServoBusProtocol for LewanSoulBus<'_> {
+    fn position_max(&self, id: u8) -> u16 {
+        self.model(id).position_max
+    }
+
+    fn units_per_degree(&self, id: u8) -> f32 {
+        self.model(id).units_per_degree()
+    }
+
+    fn move_to_position(&mut self, id: u8, position: u16, time_ms: u16) -> Result<(), Error> {
+        LewanSoulBus::move_to_position(self, id, position, time_ms)
+    }
+
+    fn ping(&mut self, id: u8) -> Result<(), Error> {
+        // There is no ping command; reading the ID back is the cheapest request a servo answers
+        let reported = self.read_id(id)?;
+        if reported != id {
+            anyhow::bail!("Servo {} answered with ID {}", id, reported);
+        }
+        Ok(())
+    }
+
+    fn read_position(&mut self, id: u8) -> Result<u16, Error> {
+        LewanSoulBus::read_position(self, id)
+    }
+
+    fn set_torque(&mut self, id: u8, enable: bool) -> Result<(), Error> {
+        LewanSoulBus::set_torque(self, id, enable)
+    }
+
+    fn set_mode(&mut self, id: u8, mode: ServoMode) -> Result<(), Error> {
+        LewanSoulBus::set_mode(self, id, mode)
+    }
+
+    fn read_mode(&mut self, id: u8) -> Result<ServoMode, Error> {
+        LewanSoulBus::read_mode(self, id)
+    }
+
+    fn set_position_limits(&mut self, id: u8, min: u16, max: u16) -> Result<(), Error> {
+        LewanSoulBus::set_position_limits(self, id, min, max)
+    }
+
+    fn emergency_stop(&mut self) -> Result<(), Error> {
+        // Send both even if the first fails: unloading alone still stops the servos
+        let stopped = self.stop(BROADCAST_ID);
+        self.write_command(BROADCAST_ID, CMD_LOAD_OR_UNLOAD_WRITE, &[0])?;
+        self.forget_position(BROADCAST_ID);
+        stopped
+    }
+}
@@ -0,0 +1,144 @@
+use super::*;
+
+/// Every command of the LewanSoul/Hiwonder bus protocol driven by [`LewanSoulBus`].
+///
+/// The LX-16A, LX-15D, LX-224 and HTS-35H all implement the whole set. Profiles of servos that only speak part of the
+/// protocol (third-party clones, older firmware) list their own commands, and the bus then refuses the others with
+/// [`BusError::UnsupportedCommand`] instead of waiting for a reply that never comes.
+pub const FULL_PROTOCOL: &[u8] = &[
+    CMD_MOVE_TIME_WRITE,
+    CMD_MOVE_TIME_READ,
+    CMD_MOVE_START,
+    CMD_MOVE_STOP,
+    CMD_ID_WRITE,
+    CMD_ID_READ,
+    CMD_ANGLE_OFFSET_ADJUST,
+    CMD_ANGLE_OFFSET_WRITE,
+    CMD_ANGLE_OFFSET_READ,
+    CMD_ANGLE_LIMIT_WRITE,
+    CMD_ANGLE_LIMIT_READ,
+    CMD_VIN_LIMIT_WRITE,
+    CMD_VIN_LIMIT_READ,
+    CMD_TEMP_MAX_LIMIT_WRITE,
+    CMD_TEMP_MAX_LIMIT_READ,
+    CMD_TEMP_READ,
+    CMD_VIN_READ,
+    CMD_POS_READ,
+    CMD_OR_MOTOR_MODE_WRITE,
+    CMD_OR_MOTOR_MODE_READ,
+    CMD_LOAD_OR_UNLOAD_WRITE,
+    CMD_LOAD_OR_UNLOAD_READ,
+    CMD_LED_ERROR_WRITE,
+    CMD_LED_ERROR_READ,
+];
+
+/// Profile of one servo model of the LewanSoul/Hiwonder bus-servo family.
+///
+/// All models share the packet format and the 115200 baud bus, but may differ in position resolution, angular range,
+/// supply voltage and the commands they understand. [`LewanSoulBus`] keeps one profile per servo ID and uses it to
+/// convert degrees to position units and to validate commands.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ServoModel {
+    /// Human readable model name (e.g. "LX-16A").
+    pub name: &'static str,
+    /// Highest position value; position 0 is the start of the angular range.
+    pub position_max: u16,
+    /// Angle in degrees covered by positions `0..=position_max`.
+    pub angle_range_deg: f32,
+    /// Lowest rated supply voltage in millivolts.
+    pub vin_min_mv: u16,
+    /// Highest rated supply voltage in millivolts.
+    pub vin_max_mv: u16,
+    /// Lowest over-temperature limit the servo accepts, in °C.
+    pub temp_limit_min_c: u8,
+    /// Highest over-temperature limit the servo accepts, in °C.
+    pub temp_limit_max_c: u8,
+    /// Command codes this model understands.
+    pub commands: &'static [u8],
+}
+
+impl ServoModel {
+    /// Position units per degree of rotation.
+    pub fn units_per_degree(&self) -> f32 {
+        self.position_max as f32 / self.angle_range_deg
+    }
+
+    /// Convert an angle in degrees to the nearest position unit, clamped to the model's range.
+    pub fn angle_to_position(&self, angle: f32) -> u16 {
+        let pos = (angle * self.units_per_degree()).round();
+        pos.clamp(0.0, self.position_max as f32) as u16
+    }
+
+    /// Convert a position value to degrees.
+    pub fn position_to_angle(&self, position: u16) -> f32 {
+        position as f32 / self.units_per_degree()
+    }
+
+    /// True if `position` lies within `0..=position_max`.
+    pub fn position_in_range(&self, position: u16) -> bool {
+        position <= self.position_max
+    }
+
+    /// True if `[min_mv, max_mv]` is an ordered range within the model's rated supply voltage.
+    pub fn vin_limits_in_range(&self, min_mv: u16, max_mv: u16) -> bool {
+        min_mv <= max_mv && min_mv >= self.vin_min_mv && max_mv <= self.vin_max_mv
+    }
+
+    /// True if `temp_c` is an accepted over-temperature limit.
+    pub fn temp_limit_in_range(&self, temp_c: u8) -> bool {
+        (self.temp_limit_min_c..=self.temp_limit_max_c).contains(&temp_c)
+    }
+
+    /// True if this model understands the given command code.
+    pub fn supports(&self, command: u8) -> bool {
+        self.commands.contains(&command)
+    }
+}
+
+/// LX-16A: 0-1000 over 240°, 6.0-8.4 V, 17 kg·cm.
+pub const LX_16A: ServoModel = ServoModel {
+    name: "LX-16A",
+    position_max: 1000,
+    angle_range_deg: 240.0,
+    vin_min_mv: 6000,
+    vin_max_mv: 8400,
+    temp_limit_min_c: 50,
+    temp_limit_max_c: 100,
+    commands: FULL_PROTOCOL,
+};
+
+/// LX-15D: dual-shaft version of the LX-16A with the same position range and electrical ratings.
+pub const LX_15D: ServoModel = ServoModel {
+    name: "LX-15D",
+    position_max: 1000,
+    angle_range_deg: 240.0,
+    vin_min_mv: 6000,
+    vin_max_mv: 8400,
+    temp_limit_min_c: 50,
+    temp_limit_max_c: 100,
+    commands: FULL_PROTOCOL,
+};
+
+/// LX-224: 0-1000 over 240°, 9.0-12.6 V, 20 kg·cm.
+pub const LX_224: ServoModel = ServoModel {
+    name: "LX-224",
+    position_max: 1000,
+    angle_range_deg: 240.0,
+    vin_min_mv: 9000,
+    vin_max_mv: 12600,
+    temp_limit_min_c: 50,
+    temp_limit_max_c: 100,
+    commands: FULL_PROTOCOL,
+};
+
+/// HTS-35H: high-voltage HTS-series servo, 0-1000 over 240°, 9.0-12.6 V, 35 kg·cm.
+pub const HTS_35H: ServoModel = ServoModel {
+    name: "HTS-35H",
+    position_max: 1000,
+    angle_range_deg: 240.0,
+    vin_min_mv: 9000,
+    vin_max_mv: 12600,
+    temp_limit_min_c: 50,
+    temp_limit_max_c: 100,
+    commands: FULL_PROTOCOL,
+};
@@ -0,0 +1,84 @@
+//! Framing of the LewanSoul serial protocol, kept free of UART access so it can be checked on the host.
+use anyhow::Error;
+
+use super::{BusError, BROADCAST_ID, MAX_PKT};
+
+/// Checksum of a packet: bitwise-NOT of sum(ID+LEN+CMD+PARAMS).
+pub fn checksum(body: &[u8]) -> u8 {
+    !(body.iter().fold(0u8, |s, b| s.wrapping_add(*b)))
+}
+
+/// Build a request packet: [0x55, 0x55, ID, LEN, CMD, params.., CHK] with LEN = params + 3.
+///
+/// Fails with [`BusError::BroadcastRead`] if `want_reply` is set for [`BROADCAST_ID`], since no servo would answer.
+pub fn encode(id: u8, command: u8, params: &[u8], want_reply: bool) -> Result<Vec<u8>, Error> {
+    if want_reply && id == BROADCAST_ID {
+        return Err(BusError::BroadcastRead { command }.into());
+    }
+    if params.len() + 6 > MAX_PKT {
+        anyhow::bail!("Packet size {} exceeds buffer size {}", params.len() + 6, MAX_PKT);
+    }
+    let mut frame = Vec::with_capacity(params.len() + 6);
+    frame.extend_from_slice(&[0x55, 0x55, id, params.len() as u8 + 3, command]);
+    frame.extend_from_slice(params);
+    frame.push(checksum(&frame[2..]));
+    Ok(frame)
+}
+
+/// Check the checksum of a complete reply packet.
+pub fn validate_reply(frame: &[u8]) -> Result<(), Error> {
+    if frame.len() < 6 || frame[0] != 0x55 || frame[1] != 0x55 {
+        anyhow::bail!("Malformed reply packet: {:?}", frame);
+    }
+    let chk_rx = frame[frame.len() - 1];
+    let chk_calc = checksum(&frame[2..frame.len() - 1]);
+    if chk_rx != chk_calc {
+        anyhow::bail!("Checksum error: received 0x{:02X}, calculated 0x{:02X}", chk_rx, chk_calc);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_move_command() {
+        // Servo 1 to position 500 in 1000 ms, as in the LX-16A protocol manual
+        let frame = encode(1, 1, &[0xF4, 0x01, 0xE8, 0x03], false).unwrap();
+        assert_eq!(frame, vec![0x55, 0x55, 0x01, 0x07, 0x01, 0xF4, 0x01, 0xE8, 0x03, 0x16]);
+    }
+
+    #[test]
+    fn encodes_read_without_params() {
+        let frame = encode(3, 28, &[], true).unwrap();
+        assert_eq!(frame, vec![0x55, 0x55, 0x03, 0x03, 0x1C, !(0x03u8 + 0x03 + 0x1C)]);
+        validate_reply(&frame).unwrap();
+    }
+
+    #[test]
+    fn broadcast_write_is_allowed() {
+        let frame = encode(BROADCAST_ID, 31, &[0], false).unwrap();
+        assert_eq!(frame[2], BROADCAST_ID);
+    }
+
+    #[test]
+    fn broadcast_read_is_rejected() {
+        let err = encode(BROADCAST_ID, 28, &[], true).unwrap_err();
+        assert_eq!(err.downcast_ref::<BusError>(), Some(&BusError::BroadcastRead { command: 28 }));
+    }
+
+    #[test]
+    fn oversized_packet_is_rejected() {
+        assert!(encode(1, 1, &[0; MAX_PKT], false).is_err());
+    }
+
+    #[test]
+    fn corrupted_reply_is_rejected() {
+        let mut frame = encode(1, 28, &[0xF4, 0x01], false).unwrap();
+        validate_reply(&frame).unwrap();
+        frame[5] ^= 0x01;
+        assert!(validate_reply(&frame).is_err());
+        assert!(validate_reply(&[0x55, 0x55, 0x01]).is_err());
+    }
+}
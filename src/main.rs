@@ -1,8 +1,11 @@
 use esp_idf_sys as _; // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
 use esp_idf_svc::log::EspLogger;
 use log::*;
+use std::cell::Cell;
+use std::rc::Rc;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use esp_idf_hal::gpio::Gpio4;
 use esp_idf_hal::peripherals::Peripherals;
 
 
@@ -11,15 +14,114 @@ use esp_idf_hal::peripherals::Peripherals;
 mod lewan_bus;
 use crate::lewan_bus::LewanSoulBus;
 
+// Protocol-independent servo bus trait (LewanSoul, Feetech SCS/STS, Dynamixel 1.0)
+mod servo_bus;
+
 // Import wifi module
 mod wifi;
+use crate::wifi::credentials::CredentialStore;
+use crate::wifi::manager::{WifiManager, WifiManagerConfig, WifiState};
+use crate::wifi::portal::PortalConfig;
 use crate::wifi::wifi_init;
 
 mod servos;
 use crate::servos::init_servos;
 
+mod servo_backup;
+use crate::servo_backup::ServoBackup;
+
+mod health;
+use crate::health::{HealthConfig, HealthMonitor};
+
+// Trapezoidal and spline motion profiles streamed to the servos
+mod trajectory;
+
+// Keyframe pose sequences authored as JSON and stored on flash
+mod sequence;
+use crate::sequence::Sequence;
+use crate::sequence::player::SequencePlayer;
+use crate::sequence::store::SequenceStore;
+
+// Teach-and-playback: record motions demonstrated by hand
+mod teach;
+
+// Joint calibration: servo positions <-> joint radians
+mod joint;
+use crate::joint::{JointCalibration, JointStore, Joints};
+
+// Forward and inverse kinematics of the 2-link planar arm on servos 1 and 2
+mod kinematics;
+
+// Stall and obstruction detection from commanded vs measured position
+mod stall;
+use crate::stall::{StallConfig, StallSupervisor};
+
+// Over-temperature and over-voltage protection
+mod protection;
+use crate::protection::{ProtectionConfig, ProtectionLevel, ProtectionSupervisor};
+
+// Latching emergency stop: button input and HTTP trigger
+mod estop;
+use crate::estop::{EStop, EStopButton};
+
+// Closed-loop velocity control for wheels in motor mode
+mod velocity;
+
+// Multi-turn tracking and wheel odometry for continuous-rotation servos
+mod odometry;
+
+// Telemetry polling at per-signal rates within a share of the bus time
+mod telemetry;
+use crate::telemetry::cache::TelemetryCache;
+use crate::telemetry::{Signal, TelemetryConfig, TelemetryScheduler, Value};
+
 // Import EspWifi
 use esp_idf_svc::wifi::EspWifi;
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::http::server::{Configuration as HttpConfiguration, EspHttpServer};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+
+/// Time the provisioning portal stays up, when networks are known, before restarting to retry them
+const PORTAL_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Servos driven by the demo loop
+const SERVO_IDS: [u8; 2] = [1, 2];
+
+/// An e-stop button is wired as a normally-closed contact between GPIO4 and GND (a slide switch in diagram.json).
+/// Without it the e-stop is only triggered over HTTP; with it fitted, an open circuit keeps the e-stop latched.
+const ESTOP_BUTTON_FITTED: bool = true;
+
+/// Debounce time of the e-stop button
+const ESTOP_DEBOUNCE: Duration = Duration::from_millis(20);
+
+/// Telemetry polled on every servo, with the rate of each signal in Hz. The stall and protection supervisors read
+/// their positions, temperatures and voltages from it.
+const TELEMETRY_RATES: [(Signal, f32); 4] = [
+    (Signal::Position, 5.0),
+    (Signal::Temperature, 0.5),
+    (Signal::Vin, 0.5),
+    (Signal::LoadState, 0.2),
+];
+
+/// Setpoint rate of the sequence player
+const SEQUENCE_RATE_HZ: f32 = 25.0;
+
+/// Sequence played at boot, unless one named "demo" has been uploaded to flash
+const DEMO_SEQUENCE: &str = r#"{
+    "version": 1,
+    "name": "demo",
+    "poses": {
+        "a": { "1": 0, "2": 748 },
+        "b": { "1": 806, "2": 554 },
+        "c": { "1": 612, "2": 360 }
+    },
+    "keyframes": [
+        { "pose": "a", "duration_ms": 1000, "hold_ms": 1000 },
+        { "pose": "b", "duration_ms": 1000, "hold_ms": 1000 },
+        { "pose": "c", "duration_ms": 1000, "hold_ms": 1000 }
+    ],
+    "loops": 0
+}"#;
 
 fn main() -> anyhow::Result<()> {
     // Initialize ESP-IDF patches
@@ -32,29 +134,223 @@ fn main() -> anyhow::Result<()> {
     let peripherals = Peripherals::take().unwrap();
 
     
-    // Set servo ID 1 to 90 degrees in 1 second
-    let _wifi: EspWifi = wifi_init("fliacaro", "50344212")?;
+    // The default NVS partition can only be taken once; Wi-Fi, the servo backup and the sequence store share it
+    let nvs = EspDefaultNvsPartition::take()?;
+
+    // Known networks come from NVS, or from WIFI_SSID / WIFI_PASSWORD at build time on development boards.
+    // If none of them can be joined the robot opens a setup access point and restarts once it has been configured.
+    // Once connected, the manager rescans and joins the best known network whenever the connection is lost.
+    let sysloop = EspSystemEventLoop::take()?;
+    let credential_store = CredentialStore::new(nvs.clone())?;
+    let wifi: EspWifi = wifi_init(peripherals.modem, sysloop.clone(), nvs.clone())?;
+    let mut wifi = match credential_store.load_or_build_env() {
+        Some((networks, source)) => {
+            info!("{} known Wi-Fi networks from {:?}", networks.len(), source);
+            let mut manager = WifiManager::new(wifi, networks, WifiManagerConfig::default())?;
+            manager.subscribe(&sysloop)?;
+            // Give the first round time to try every known network before falling back to the portal
+            if !manager.wait_online(manager.round_timeout()) {
+                warn!("None of the known Wi-Fi networks could be joined");
+                // The networks may only be down for a while: give up on the portal after a few minutes and retry them
+                let config = PortalConfig {
+                    timeout: Some(PORTAL_TIMEOUT),
+                    ..Default::default()
+                };
+                match wifi::portal::provision(manager.into_inner(), credential_store, &config)? {}
+            }
+            manager
+        }
+        None => {
+            warn!("No Wi-Fi credentials stored");
+            match wifi::portal::provision(wifi, credential_store, &PortalConfig::default())? {}
+        }
+    };
+
+    // The HTTP e-stop cannot be reached while the network is down, so motion pauses until it is back
+    let network_up = Rc::new(Cell::new(wifi.is_online()));
+    let flag = network_up.clone();
+    wifi.on_change(move |state| flag.set(state == WifiState::GotIp));
 
     let mut bus: LewanSoulBus = init_servos(peripherals.uart1, peripherals.pins.gpio32, peripherals.pins.gpio33)?;
 
+    // The e-stop blocks every motion command until it is reset, and motion only restarts on POST /estop/resume
+    let estop = EStop::new();
+    bus.attach_estop(estop.clone());
+    let mut estop_button = if ESTOP_BUTTON_FITTED {
+        Some(EStopButton::new(peripherals.pins.gpio4, estop.clone(), ESTOP_DEBOUNCE)?)
+    } else {
+        warn!("No e-stop button fitted; the emergency stop can only be triggered over HTTP");
+        None
+    };
+    let mut server = EspHttpServer::new(&HttpConfiguration::default())?;
+    estop::register_http(&mut server, &estop)?;
+
+    // Keep a configuration backup of every servo so a replacement can be restored to match
+    let mut backup = ServoBackup::new(nvs.clone())?;
+    backup.backup_missing(&mut bus, &SERVO_IDS)?;
+
+    // Joints without a stored calibration default to a servo mounted directly on the joint
+    let mut joint_store = JointStore::new(nvs.clone())?;
+    let mut joints = match joint_store.load() {
+        Ok(joints) => joints,
+        Err(e) => {
+            error!("Stored joint calibration is invalid, treating every joint as directly mounted: {:?}", e);
+            Joints::new()
+        }
+    };
+    if SERVO_IDS.iter().any(|id| joints.calibration(*id).is_none()) {
+        for id in SERVO_IDS {
+            if joints.calibration(id).is_none() {
+                joints.set_calibration(id, JointCalibration::direct(&bus, id))?;
+            }
+        }
+        joint_store.save(&joints)?;
+    }
+
+    let sequences = SequenceStore::new(nvs)?;
+    let demo = match sequences.load("demo") {
+        Ok(Some(sequence)) => sequence,
+        Ok(None) => Sequence::from_json(DEMO_SEQUENCE)?,
+        Err(e) => {
+            error!("Stored demo sequence is invalid, using the built-in one: {:?}", e);
+            Sequence::from_json(DEMO_SEQUENCE)?
+        }
+    };
+
+    // Ping the servos in the background of the main loop; unplugged servos are skipped instead of erroring forever
+    let mut health = HealthMonitor::new(&SERVO_IDS, HealthConfig::default());
+
+    // Stop the sequence and unload a servo that cannot follow it instead of grinding its gearbox
+    let mut stall = StallSupervisor::new(StallConfig::default());
+
+    // Slow down hot or badly supplied servos, and unload them before their own protection trips
+    let mut protection = ProtectionSupervisor::new(&SERVO_IDS, ProtectionConfig::default());
+
+    let mut telemetry = TelemetryScheduler::new(TelemetryConfig::default());
+    for id in SERVO_IDS {
+        for (signal, rate_hz) in TELEMETRY_RATES {
+            if let Err(e) = telemetry.set_rate(id, signal, rate_hz) {
+                warn!("Telemetry rates will not be met: {}", e);
+            }
+        }
+    }
+
+    let cache = TelemetryCache::new();
+
+    let mut player = SequencePlayer::new(SEQUENCE_RATE_HZ)?;
+    player.play(demo.clone(), &mut joints.bus(&mut bus))?;
+
     // Main loop that runs indefinitely
-    let mut i = 0;  
+    let mut last_report = Instant::now();
+    let mut stopped = false;
     loop {
-        // Read current position
-        match bus.read_position(1) {
-            Ok(pos) => println!("Servo position (0-1000 units): {}", pos),
-            Err(e) => error!("Failed to read position: {:?}", e),
+        // Reconnect in the background; motion is paused while the network is down (see `network_up`)
+        wifi.poll();
+
+        if estop_checkpoint(&estop, &mut estop_button, &mut bus) {
+            player.stop();
+            stopped = true;
+            sleep(Duration::from_millis(5));
+            continue;
+        }
+        if stopped {
+            // A reset only releases the latch; the servos stay unloaded until a resume is requested
+            if !estop.take_resume() {
+                sleep(Duration::from_millis(5));
+                continue;
+            }
+            stopped = false;
+            for id in SERVO_IDS {
+                // A servo unloaded by a stall is supervised again once the motion restarts
+                stall.clear(id);
+                if let Err(e) = bus.set_torque(id, true) {
+                    error!("Failed to re-enable servo {}: {:?}", id, e);
+                }
+            }
+            if let Err(e) = player.play(demo.clone(), &mut joints.bus(&mut health.guard(&mut bus))) {
+                error!("Failed to restart the sequence: {:?}", e);
+            }
+        }
+
+        // The stages below each block on the bus; the e-stop is checked between them so a trigger waits for one stage
+        health.poll(&mut bus);
+        if estop_checkpoint(&estop, &mut estop_button, &mut bus) {
+            continue;
         }
-        // println!("Moving servo ID 1 to {}°...", i%240); 
-        bus.move_to_position(1, i%1000, 1000).expect("Cannot move!");
-        i += 403;
 
-        // println!("Moving servo ID 1 to {}°...", i%240); 
-        bus.move_to_position(2, (i+345)%1000, 1000).expect("Cannot move!");
-        i += 403;
+        // Hold the motion while any servo of the sequence is offline or the network is down
+        if network_up.get() && SERVO_IDS.iter().all(|id| health.is_online(*id)) {
+            player.resume();
+        } else {
+            player.pause();
+        }
+        // Hot or badly supplied servos slow the whole sequence down, so they are not left behind by the others
+        if let Err(e) = player.set_speed(protection.speed_scale()) {
+            error!("Failed to derate the sequence: {:?}", e);
+        }
+        // Sequences are authored for directly mounted servos; the calibration maps them onto this arm, and the health
+        // guard makes sure an offline servo is never commanded
+        if let Err(e) = player.tick(&mut joints.bus(&mut health.guard(&mut bus))) {
+            error!("Sequence playback failed: {:?}", e);
+        }
+        if let Some(setpoints) = player.take_setpoints() {
+            // The stall supervisor compares with the measured (raw) positions
+            for (id, position) in setpoints {
+                match joints.raw_position(&bus, id, position) {
+                    Ok(raw) => stall.expect(id, raw, player.period_ms()),
+                    Err(e) => warn!("Servo {}: setpoint {} not supervised: {}", id, position, e),
+                }
+            }
+        }
+        if estop_checkpoint(&estop, &mut estop_button, &mut bus) {
+            continue;
+        }
 
-        // Give it some time to move
-        sleep(Duration::from_millis(2000));
+        // The supervisors work from the cached telemetry instead of polling the bus themselves
+        let samples = telemetry.poll(&mut bus);
+        cache.record_all(&samples);
+        // Telemetry reads count towards servo health like the pings, so a servo that stops answering them is caught
+        // between ping rounds
+        for sample in &samples {
+            health.record(sample.id, true, sample.at);
+        }
+        for failure in telemetry.take_failures() {
+            health.record(failure.id, false, failure.at);
+        }
+        if estop_checkpoint(&estop, &mut estop_button, &mut bus) {
+            continue;
+        }
+        if !stall.check(&mut bus, &cache).is_empty() {
+            player.stop();
+        }
+        if protection.poll(&mut bus, &cache).iter().any(|event| event.to == ProtectionLevel::Critical) {
+            player.stop();
+        }
+
+        // Report the angle of joint 1 every couple of seconds
+        if last_report.elapsed() >= Duration::from_millis(2000) {
+            if let Some(Value::Position(pos)) = cache.fresh(1, Signal::Position, Duration::from_millis(1000)) {
+                match joints.angle_of(&bus, 1, pos) {
+                    Ok(angle) => println!("Joint 1 angle: {:.1} deg", angle.to_degrees()),
+                    Err(e) => warn!("Joint 1: {}", e),
+                }
+            }
+            last_report = Instant::now();
+        }
+
+        sleep(Duration::from_millis(5));
+    }
+}
 
+/// Poll the e-stop button and stop the bus if the e-stop is latched. Returns true while it is latched.
+///
+/// The HTTP trigger runs on the server thread, which has no access to the bus, so a network e-stop is only sent to the
+/// servos at the next checkpoint. The main loop calls this between its blocking stages, which bounds the delay to the
+/// longest single stage. A servo that does not answer holds a transaction for up to 300 ms (the reply timeouts of
+/// [`LewanSoulBus`]); the health monitor pings one servo per pass, and a telemetry burst is a few reads at most.
+fn estop_checkpoint(estop: &EStop, button: &mut Option<EStopButton<'_, Gpio4>>, bus: &mut LewanSoulBus) -> bool {
+    if let Some(button) = button.as_mut() {
+        button.poll();
     }
+    estop.service(&mut [bus])
 }
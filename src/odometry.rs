@@ -0,0 +1,181 @@
+#![allow(dead_code)]
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use log::*;
+
+use crate::servo_bus::ServoBusProtocol;
+use crate::velocity::{in_blind_zone, units_per_revolution, wrapped_delta, VelocityEstimator};
+
+/// Tuning of the [`OdometryTracker`].
+#[derive(Debug, Clone, Copy)]
+pub struct OdometryConfig {
+    /// Time between two position samples. At full speed an LX-16A turns about 7.5° per 20 ms.
+    pub sample_period: Duration,
+    /// Gap between two valid samples above which a turn may have been missed; logged as a warning.
+    /// Half a revolution at full speed is about 480 ms on an LX-16A, blind zone included.
+    pub max_gap: Duration,
+}
+
+impl Default for OdometryConfig {
+    fn default() -> Self {
+        OdometryConfig {
+            sample_period: Duration::from_millis(20),
+            max_gap: Duration::from_millis(400),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Turns {
+    /// Total travel since the last reset, in position units (f64 so long runs do not lose resolution).
+    travel: f64,
+    /// Last valid (out of blind zone) reading and when it was taken.
+    last: Option<(u16, Instant)>,
+    /// The latest reading was in the blind zone.
+    blind: bool,
+    estimator: VelocityEstimator,
+}
+
+impl Turns {
+    fn new() -> Self {
+        Turns {
+            travel: 0.0,
+            last: None,
+            blind: false,
+            estimator: VelocityEstimator::new(0.3),
+        }
+    }
+
+    /// Accumulate one sample and return the travel it added.
+    ///
+    /// Across the blind zone a single-turn reading is ambiguous by whole revolutions; the change closest to what the
+    /// measured velocity predicts over the gap is taken, so crossing the blind zone in either direction counts right.
+    fn update(&mut self, position: u16, position_max: u16, units_per_rev: f32, now: Instant, max_gap: Duration) -> f32 {
+        if in_blind_zone(position, position_max) {
+            self.blind = true;
+            return 0.0;
+        }
+        self.blind = false;
+        // Predict from the velocity measured before this sample, which may itself be ambiguous
+        let velocity = self.estimator.velocity();
+        self.estimator.update(position, position_max, units_per_rev, now);
+        let (last, at) = match self.last.replace((position, now)) {
+            Some(last) => last,
+            None => return 0.0,
+        };
+        let gap = now.duration_since(at);
+        if gap > max_gap {
+            warn!("Odometry: {} ms without a valid position, turns may have been missed", gap.as_millis());
+        }
+        let raw = wrapped_delta(last, position, units_per_rev);
+        let predicted = velocity.unwrap_or(0.0) * gap.as_secs_f32();
+        let delta = raw + units_per_rev * ((predicted - raw) / units_per_rev).round();
+        self.travel += delta as f64;
+        delta
+    }
+
+    /// Travel including the motion extrapolated through the blind zone, where no reading is available.
+    fn estimated_travel(&self, now: Instant) -> f64 {
+        match (self.blind, self.last, self.estimator.velocity()) {
+            (true, Some((_, at)), Some(velocity)) => self.travel + (velocity * now.duration_since(at).as_secs_f32()) as f64,
+            _ => self.travel,
+        }
+    }
+}
+
+/// Multi-turn position tracking of continuously rotating servos.
+///
+/// In motor mode the LX-16A only reports its position over 240° of the turn. The tracker samples fast enough to unwrap
+/// every turn, bridges the blind zone with the measured velocity, and accumulates the total travel per servo.
+/// Call [`update`](Self::update) from the main loop at least once per sample period.
+pub struct OdometryTracker {
+    config: OdometryConfig,
+    servos: BTreeMap<u8, Turns>,
+    /// Units per revolution of each servo, cached from the bus.
+    units_per_rev: BTreeMap<u8, f32>,
+    last_sample: Option<Instant>,
+}
+
+impl OdometryTracker {
+    pub fn new(config: OdometryConfig) -> Self {
+        OdometryTracker {
+            config,
+            servos: BTreeMap::new(),
+            units_per_rev: BTreeMap::new(),
+            last_sample: None,
+        }
+    }
+
+    /// Start tracking servo `id` from zero.
+    pub fn add<B: ServoBusProtocol>(&mut self, bus: &B, id: u8) {
+        self.servos.insert(id, Turns::new());
+        self.units_per_rev.insert(id, units_per_revolution(bus, id));
+    }
+
+    /// Stop tracking servo `id`.
+    pub fn remove(&mut self, id: u8) {
+        self.servos.remove(&id);
+        self.units_per_rev.remove(&id);
+    }
+
+    /// Zero the accumulated travel of servo `id`.
+    pub fn reset(&mut self, id: u8) {
+        if let Some(turns) = self.servos.get_mut(&id) {
+            turns.travel = 0.0;
+        }
+    }
+
+    /// Sample every tracked servo if a sample period has elapsed.
+    pub fn update<B: ServoBusProtocol>(&mut self, bus: &mut B) {
+        let now = Instant::now();
+        if let Some(last) = self.last_sample {
+            if now.duration_since(last) < self.config.sample_period {
+                return;
+            }
+        }
+        self.last_sample = Some(now);
+
+        let ids: Vec<u8> = self.servos.keys().copied().collect();
+        for id in ids {
+            let position = match bus.read_position(id) {
+                Ok(position) => position,
+                Err(e) => {
+                    debug!("Odometry: servo {} read failed: {:?}", id, e);
+                    continue;
+                }
+            };
+            let position_max = bus.position_max(id);
+            let units_per_rev = self.units_per_rev[&id];
+            if let Some(turns) = self.servos.get_mut(&id) {
+                turns.update(position, position_max, units_per_rev, now, self.config.max_gap);
+            }
+        }
+    }
+
+    /// Total revolutions of servo `id` since it was added or reset (signed, fractional), extrapolated through the blind zone.
+    pub fn revolutions(&self, id: u8) -> Option<f64> {
+        let turns = self.servos.get(&id)?;
+        Some(turns.estimated_travel(Instant::now()) / self.units_per_rev[&id] as f64)
+    }
+
+    /// Total angle of servo `id` in degrees.
+    pub fn angle_deg(&self, id: u8) -> Option<f64> {
+        Some(self.revolutions(id)? * 360.0)
+    }
+
+    /// Distance rolled by a wheel of `wheel_radius` on servo `id`, in the unit of the radius.
+    pub fn distance(&self, id: u8, wheel_radius: f64) -> Option<f64> {
+        Some(self.revolutions(id)? * core::f64::consts::TAU * wheel_radius)
+    }
+
+    /// Measured velocity of servo `id` in revolutions per second.
+    pub fn revolutions_per_second(&self, id: u8) -> Option<f32> {
+        let velocity = self.servos.get(&id)?.estimator.velocity()?;
+        Some(velocity / self.units_per_rev[&id])
+    }
+
+    /// True if the last reading of servo `id` was in the blind zone.
+    pub fn in_blind_zone(&self, id: u8) -> bool {
+        self.servos.get(&id).is_some_and(|turns| turns.blind)
+    }
+}
@@ -0,0 +1,334 @@
+#![allow(dead_code)]
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+use log::*;
+
+use crate::lewan_bus::limits::{LimitPolicy, SoftLimits};
+use crate::lewan_bus::LewanSoulBus;
+use crate::telemetry::cache::TelemetryCache;
+use crate::telemetry::{self, Value};
+
+/// Number of faults kept by [`ProtectionSupervisor::faults`]; older ones are dropped.
+const FAULT_LOG_LEN: usize = 32;
+
+/// Protection level of one servo, from the worst of its temperature and supply voltage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProtectionLevel {
+    Normal,
+    /// Motion is derated to [`ProtectionConfig::derate_speed`] (see [`ProtectionSupervisor::speed_scale`]).
+    Warning,
+    /// Torque is unloaded. Latched until [`ProtectionSupervisor::reset`].
+    Critical,
+}
+
+/// The measurement that caused a level change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    /// Internal temperature in °C.
+    Temperature,
+    /// Input voltage in millivolts.
+    Voltage,
+}
+
+/// Thresholds of the [`ProtectionSupervisor`]. They sit well inside the servos' own shutdown limits, which trip too
+/// late to save the mechanism.
+#[derive(Debug, Clone, Copy)]
+pub struct ProtectionConfig {
+    /// Time between two checks of the telemetry.
+    pub poll_interval: Duration,
+    /// Oldest cached reading still acted upon; servos with older readings are skipped.
+    pub max_age: Duration,
+    pub temp_warning_c: u8,
+    pub temp_critical_c: u8,
+    /// A level is only left once the temperature is this far back below its threshold.
+    pub temp_hysteresis_c: u8,
+    /// Voltage window `(low, high)` outside which a servo is at warning level, in millivolts.
+    pub vin_warning_mv: (u16, u16),
+    /// Voltage window `(low, high)` outside which a servo is at critical level, in millivolts.
+    pub vin_critical_mv: (u16, u16),
+    pub vin_hysteresis_mv: u16,
+    /// Fraction of the normal speed motion sources play at while any servo is at warning level
+    /// (see [`ProtectionSupervisor::speed_scale`]).
+    pub derate_speed: f32,
+    /// Soft velocity limit applied at warning level, in raw units per second. It catches moves from motion sources
+    /// that are not slowed down, and should sit above the speed of the derated motion.
+    pub derate_velocity: f32,
+}
+
+impl Default for ProtectionConfig {
+    /// Suited to LX-16A servos on a 2S LiPo (rated 6-8.4 V, firmware over-temperature limit 85 °C).
+    fn default() -> Self {
+        ProtectionConfig {
+            poll_interval: Duration::from_millis(2000),
+            max_age: Duration::from_millis(5000),
+            temp_warning_c: 60,
+            temp_critical_c: 70,
+            temp_hysteresis_c: 5,
+            vin_warning_mv: (6600, 8400),
+            vin_critical_mv: (6200, 8800),
+            vin_hysteresis_mv: 200,
+            derate_speed: 0.5,
+            derate_velocity: 500.0,
+        }
+    }
+}
+
+/// A protection level change of one servo, as recorded in the fault log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtectionEvent {
+    pub id: u8,
+    pub from: ProtectionLevel,
+    pub to: ProtectionLevel,
+    /// Signal and value that set the new level.
+    pub signal: Signal,
+    pub value: u16,
+    pub at: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ServoProtection {
+    level: ProtectionLevel,
+    /// Level of each signal on its own, so each one's hysteresis follows its own history.
+    temp_level: ProtectionLevel,
+    vin_level: ProtectionLevel,
+    temp_c: Option<u8>,
+    vin_mv: Option<u16>,
+    /// Soft limits in force before derating, restored when the servo is back to normal.
+    saved_limits: Option<Option<SoftLimits>>,
+}
+
+/// Level of a value that is bad when high, with hysteresis: a level is entered at its threshold and only left
+/// `hysteresis` below it. Values that are bad when low are passed negated.
+fn level_for(value: i32, warning: i32, critical: i32, hysteresis: i32, previous: ProtectionLevel) -> ProtectionLevel {
+    if value >= critical || (previous == ProtectionLevel::Critical && value > critical - hysteresis) {
+        ProtectionLevel::Critical
+    } else if value >= warning || (previous >= ProtectionLevel::Warning && value > warning - hysteresis) {
+        ProtectionLevel::Warning
+    } else {
+        ProtectionLevel::Normal
+    }
+}
+
+/// Checks the temperature and input voltage of every servo in the [`TelemetryCache`], derates motion at warning level and unloads torque at
+/// critical level.
+///
+/// Derating happens at the motion source: the main loop slows its sequence player down to
+/// [`speed_scale`](Self::speed_scale), so the setpoints and the stall supervisor's expectations both follow the
+/// slower motion. A soft velocity limit with [`LimitPolicy::Clamp`] is also installed on the bus as a backstop for
+/// other motion sources; it is never [`LimitPolicy::Reject`], which would refuse the moves of a servo that is only
+/// warm. Critical faults latch: the servo stays unloaded until [`reset`](Self::reset) is called, and the caller is
+/// expected to stop its motion (see the events returned by [`poll`](Self::poll)).
+pub struct ProtectionSupervisor {
+    config: ProtectionConfig,
+    servos: BTreeMap<u8, ServoProtection>,
+    faults: VecDeque<ProtectionEvent>,
+    last_poll: Option<Instant>,
+}
+
+impl ProtectionSupervisor {
+    pub fn new(ids: &[u8], config: ProtectionConfig) -> Self {
+        let servo = ServoProtection {
+            level: ProtectionLevel::Normal,
+            temp_level: ProtectionLevel::Normal,
+            vin_level: ProtectionLevel::Normal,
+            temp_c: None,
+            vin_mv: None,
+            saved_limits: None,
+        };
+        ProtectionSupervisor {
+            config,
+            servos: ids.iter().map(|id| (*id, servo)).collect(),
+            faults: VecDeque::new(),
+            last_poll: None,
+        }
+    }
+
+    /// Current level of servo `id`; unknown IDs are reported normal.
+    pub fn level(&self, id: u8) -> ProtectionLevel {
+        self.servos.get(&id).map_or(ProtectionLevel::Normal, |servo| servo.level)
+    }
+
+    /// Last temperature (°C) and input voltage (mV) read from servo `id`.
+    pub fn readings(&self, id: u8) -> (Option<u8>, Option<u16>) {
+        self.servos.get(&id).map_or((None, None), |servo| (servo.temp_c, servo.vin_mv))
+    }
+
+    /// Fraction of the normal speed motion should run at: [`ProtectionConfig::derate_speed`] while any servo is at
+    /// warning level, 1 otherwise.
+    pub fn speed_scale(&self) -> f32 {
+        if self.servos.values().any(|servo| servo.level == ProtectionLevel::Warning) {
+            self.config.derate_speed
+        } else {
+            1.0
+        }
+    }
+
+    /// Every recorded level change, oldest first.
+    pub fn faults(&self) -> impl Iterator<Item = &ProtectionEvent> {
+        self.faults.iter()
+    }
+
+    /// Release a latched critical fault once the cause has been dealt with. The servo goes back to normal level (its
+    /// next reading decides the real level) and its torque is re-enabled.
+    pub fn reset(&mut self, bus: &mut LewanSoulBus, id: u8) -> Result<(), anyhow::Error> {
+        let servo = match self.servos.get_mut(&id) {
+            Some(servo) if servo.level == ProtectionLevel::Critical => servo,
+            _ => return Ok(()),
+        };
+        servo.level = ProtectionLevel::Normal;
+        servo.temp_level = ProtectionLevel::Normal;
+        servo.vin_level = ProtectionLevel::Normal;
+        if let Some(saved) = servo.saved_limits.take() {
+            Self::restore_limits(bus, id, saved)?;
+        }
+        info!("Servo {}: protection fault reset", id);
+        bus.set_torque(id, true)
+    }
+
+    /// Check the cached readings of every servo if `poll_interval` has elapsed and apply the protection levels. Returns
+    /// the level changes of this round. The readings come from the telemetry poller, which must include the temperature
+    /// and voltage of every supervised servo.
+    pub fn poll(&mut self, bus: &mut LewanSoulBus, cache: &TelemetryCache) -> Vec<ProtectionEvent> {
+        let now = Instant::now();
+        if let Some(last) = self.last_poll {
+            if now.duration_since(last) < self.config.poll_interval {
+                return Vec::new();
+            }
+        }
+        self.last_poll = Some(now);
+
+        let ids: Vec<u8> = self.servos.keys().copied().collect();
+        let mut events = Vec::new();
+        for id in ids {
+            let (temp_c, vin_mv) = match (
+                cache.fresh(id, telemetry::Signal::Temperature, self.config.max_age),
+                cache.fresh(id, telemetry::Signal::Vin, self.config.max_age),
+            ) {
+                (Some(Value::Temperature(temp_c)), Some(Value::Vin(vin_mv))) => (temp_c, vin_mv),
+                _ => {
+                    debug!("Protection: no recent readings of servo {}", id);
+                    continue;
+                }
+            };
+            if let Some(event) = self.update(bus, id, temp_c, vin_mv, now) {
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    fn update(&mut self, bus: &mut LewanSoulBus, id: u8, temp_c: u8, vin_mv: u16, now: Instant) -> Option<ProtectionEvent> {
+        let config = self.config;
+        let servo = self.servos.get_mut(&id)?;
+        servo.temp_c = Some(temp_c);
+        servo.vin_mv = Some(vin_mv);
+        let from = servo.level;
+        if from == ProtectionLevel::Critical {
+            return None;
+        }
+
+        let temp_level = level_for(
+            temp_c as i32,
+            config.temp_warning_c as i32,
+            config.temp_critical_c as i32,
+            config.temp_hysteresis_c as i32,
+            servo.temp_level,
+        );
+        let hysteresis = config.vin_hysteresis_mv as i32;
+        let low_level = level_for(
+            -(vin_mv as i32),
+            -(config.vin_warning_mv.0 as i32),
+            -(config.vin_critical_mv.0 as i32),
+            hysteresis,
+            servo.vin_level,
+        );
+        let high_level = level_for(
+            vin_mv as i32,
+            config.vin_warning_mv.1 as i32,
+            config.vin_critical_mv.1 as i32,
+            hysteresis,
+            servo.vin_level,
+        );
+        let vin_level = low_level.max(high_level);
+        servo.temp_level = temp_level;
+        servo.vin_level = vin_level;
+        let to = temp_level.max(vin_level);
+        if to == from {
+            return None;
+        }
+        let (signal, value) = if temp_level == to {
+            (Signal::Temperature, temp_c as u16)
+        } else {
+            (Signal::Voltage, vin_mv)
+        };
+        servo.level = to;
+        let event = ProtectionEvent { id, from, to, signal, value, at: now };
+        self.apply(bus, &event);
+        if self.faults.len() == FAULT_LOG_LEN {
+            self.faults.pop_front();
+        }
+        self.faults.push_back(event);
+        Some(event)
+    }
+
+    /// Act on a level change: derate on warning, unload on critical, restore the previous limits when back to normal.
+    fn apply(&mut self, bus: &mut LewanSoulBus, event: &ProtectionEvent) {
+        let id = event.id;
+        let unit = match event.signal {
+            Signal::Temperature => "°C",
+            Signal::Voltage => "mV",
+        };
+        let result = match event.to {
+            ProtectionLevel::Normal => {
+                info!("Servo {}: {:?} back to normal ({} {})", id, event.signal, event.value, unit);
+                match self.servos.get_mut(&id).and_then(|servo| servo.saved_limits.take()) {
+                    Some(saved) => Self::restore_limits(bus, id, saved),
+                    None => Ok(()),
+                }
+            }
+            ProtectionLevel::Warning => {
+                warn!("Servo {}: {:?} warning ({} {}), derating motion", id, event.signal, event.value, unit);
+                self.derate(bus, id)
+            }
+            ProtectionLevel::Critical => {
+                error!("Servo {}: {:?} critical ({} {}), unloading torque", id, event.signal, event.value, unit);
+                bus.set_torque(id, false)
+            }
+        };
+        if let Err(e) = result {
+            error!("Servo {}: failed to apply protection level {:?}: {:?}", id, event.to, e);
+        }
+    }
+
+    fn derate(&mut self, bus: &mut LewanSoulBus, id: u8) -> Result<(), anyhow::Error> {
+        let current = bus.soft_limits(id).copied();
+        let servo = match self.servos.get_mut(&id) {
+            Some(servo) => servo,
+            None => return Ok(()),
+        };
+        // Keep the limits from before the first derating, not an already derated set
+        let saved = *servo.saved_limits.get_or_insert(current);
+        let velocity = saved
+            .and_then(|limits| limits.max_velocity)
+            .map_or(self.config.derate_velocity, |velocity| velocity.min(self.config.derate_velocity));
+        bus.set_soft_limits(
+            id,
+            SoftLimits {
+                min: saved.map_or(0, |limits| limits.min),
+                max: saved.map_or(bus.model(id).position_max, |limits| limits.max),
+                max_velocity: Some(velocity),
+                policy: LimitPolicy::Clamp,
+            },
+        )
+    }
+
+    fn restore_limits(bus: &mut LewanSoulBus, id: u8, saved: Option<SoftLimits>) -> Result<(), anyhow::Error> {
+        match saved {
+            Some(limits) => bus.set_soft_limits(id, limits),
+            None => {
+                bus.clear_soft_limits(id);
+                Ok(())
+            }
+        }
+    }
+}
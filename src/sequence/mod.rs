@@ -0,0 +1,162 @@
+#![allow(dead_code)]
+use std::collections::BTreeMap;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use anyhow::Error;
+
+pub mod player;
+pub mod store;
+
+/// Version of the sequence file format written by this firmware.
+pub const SEQUENCE_FORMAT_VERSION: u32 = 1;
+
+/// A named pose: target position per servo ID, in the units of a servo mounted directly on its joint.
+/// Play through [`Joints::bus`](crate::joint::Joints::bus) so the joint calibration applies.
+pub type Pose = BTreeMap<u8, u16>;
+
+/// Interpolation curve between two keyframes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Easing {
+    /// Constant speed.
+    #[default]
+    Linear,
+    /// Start slowly, arrive at full speed.
+    EaseIn,
+    /// Start at full speed, arrive slowly.
+    EaseOut,
+    /// Start and arrive slowly (cubic smoothstep).
+    EaseInOut,
+}
+
+impl Easing {
+    /// Map linear progress `tau` (0 to 1) to eased progress (0 to 1).
+    pub fn apply(self, tau: f32) -> f32 {
+        let tau = tau.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear => tau,
+            Easing::EaseIn => tau * tau,
+            Easing::EaseOut => 1.0 - (1.0 - tau) * (1.0 - tau),
+            Easing::EaseInOut => tau * tau * (3.0 - 2.0 * tau),
+        }
+    }
+}
+
+/// One step of a sequence: move to `pose` over `duration_ms`, then hold it for `hold_ms`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Keyframe {
+    /// Name of a pose in [`Sequence::poses`].
+    pub pose: String,
+    pub duration_ms: u32,
+    #[serde(default)]
+    pub easing: Easing,
+    #[serde(default)]
+    pub hold_ms: u32,
+}
+
+impl Keyframe {
+    /// Time spent on the keyframe: the move and the hold.
+    pub fn length(&self) -> Duration {
+        Duration::from_millis(self.duration_ms as u64 + self.hold_ms as u64)
+    }
+}
+
+/// A motion authored as named poses and keyframes, stored as versioned JSON.
+///
+/// ```json
+/// {
+///   "version": 1,
+///   "name": "wave",
+///   "poses": { "up": { "1": 300, "2": 700 }, "down": { "1": 700, "2": 300 } },
+///   "keyframes": [
+///     { "pose": "up", "duration_ms": 800, "easing": "ease_in_out", "hold_ms": 200 },
+///     { "pose": "down", "duration_ms": 800, "easing": "ease_in_out" }
+///   ],
+///   "loops": 0
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sequence {
+    pub version: u32,
+    pub name: String,
+    pub poses: BTreeMap<String, Pose>,
+    pub keyframes: Vec<Keyframe>,
+    /// Number of times the keyframes are played; 0 loops forever.
+    #[serde(default = "default_loops")]
+    pub loops: u32,
+}
+
+fn default_loops() -> u32 {
+    1
+}
+
+impl Sequence {
+    /// Parse and validate a sequence from JSON.
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let sequence: Sequence = serde_json::from_str(json)?;
+        sequence.validate()?;
+        Ok(sequence)
+    }
+
+    /// Serialize the sequence to pretty-printed JSON.
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Check the format version, that every keyframe refers to a defined, non-empty pose and that the sequence takes
+    /// some time to play.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.version != SEQUENCE_FORMAT_VERSION {
+            anyhow::bail!(
+                "Sequence '{}' has format version {} (expected {})",
+                self.name, self.version, SEQUENCE_FORMAT_VERSION
+            );
+        }
+        if self.keyframes.is_empty() {
+            anyhow::bail!("Sequence '{}' has no keyframes", self.name);
+        }
+        for (index, keyframe) in self.keyframes.iter().enumerate() {
+            match self.poses.get(&keyframe.pose) {
+                Some(pose) if !pose.is_empty() => {}
+                Some(_) => anyhow::bail!("Sequence '{}': pose '{}' is empty", self.name, keyframe.pose),
+                None => anyhow::bail!(
+                    "Sequence '{}': keyframe {} uses undefined pose '{}'",
+                    self.name, index, keyframe.pose
+                ),
+            }
+        }
+        if self.length().is_zero() {
+            anyhow::bail!("Sequence '{}' has a total length of zero", self.name);
+        }
+        Ok(())
+    }
+
+    /// Time taken by one pass over the keyframes.
+    pub fn length(&self) -> Duration {
+        self.keyframes.iter().map(Keyframe::length).sum()
+    }
+
+    /// Pose targeted by keyframe `index`.
+    pub fn pose_of(&self, index: usize) -> Option<&Pose> {
+        self.keyframes.get(index).and_then(|keyframe| self.poses.get(&keyframe.pose))
+    }
+
+    /// Every servo ID used by any pose.
+    pub fn joints(&self) -> Vec<u8> {
+        let mut ids: Vec<u8> = self.poses.values().flat_map(|pose| pose.keys().copied()).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+}
+
+/// Interpolate between two poses. Joints missing from `from` jump straight to their target.
+pub fn interpolate(from: &Pose, to: &Pose, progress: f32) -> Pose {
+    to.iter()
+        .map(|(id, target)| {
+            let start = *from.get(id).unwrap_or(target) as f32;
+            let position = start + (*target as f32 - start) * progress;
+            (*id, position.round().max(0.0) as u16)
+        })
+        .collect()
+}
@@ -0,0 +1,346 @@
+use std::time::{Duration, Instant};
+use anyhow::Error;
+use log::*;
+
+use super::{interpolate, Pose, Sequence};
+use crate::servo_bus::ServoBusProtocol;
+use crate::trajectory::rate_period;
+
+/// Playback state of a [`SequencePlayer`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlayerState {
+    Stopped,
+    Playing,
+    Paused,
+}
+
+/// Plays a [`Sequence`] by streaming interpolated setpoints to the servos.
+///
+/// The player never blocks: call [`tick`](Self::tick) from the main loop at least at the setpoint rate.
+/// Each tick sends the eased pose for the current time with a move time of one period, like
+/// [`TrajectoryStreamer`](crate::trajectory::TrajectoryStreamer). [`set_speed`](Self::set_speed) slows the playback
+/// clock down, so the setpoints themselves are spread out and anything that follows them (such as the
+/// [`StallSupervisor`](crate::stall::StallSupervisor)) sees the slower motion.
+pub struct SequencePlayer {
+    period: Duration,
+    sequence: Option<Sequence>,
+    state: PlayerState,
+    /// Pose the current keyframe starts from.
+    from: Pose,
+    keyframe: usize,
+    loop_index: u32,
+    /// Playback time into the current keyframe, at the playback speed.
+    keyframe_elapsed: Duration,
+    /// Time `keyframe_elapsed` was last advanced.
+    clock: Instant,
+    /// Fraction of the authored speed the sequence plays at.
+    speed: f32,
+    last_setpoint: Option<Instant>,
+    /// Setpoints sent since the last [`take_setpoints`](Self::take_setpoints).
+    sent: Option<Pose>,
+}
+
+impl SequencePlayer {
+    /// Create a stopped player sending setpoints at `rate_hz`.
+    pub fn new(rate_hz: f32) -> Result<Self, Error> {
+        Ok(SequencePlayer {
+            period: rate_period(rate_hz)?,
+            sequence: None,
+            state: PlayerState::Stopped,
+            from: Pose::new(),
+            keyframe: 0,
+            loop_index: 0,
+            keyframe_elapsed: Duration::ZERO,
+            clock: Instant::now(),
+            speed: 1.0,
+            last_setpoint: None,
+            sent: None,
+        })
+    }
+
+    /// Start playing `sequence` from its first keyframe, interpolating from the servos' current positions.
+    /// Servos whose position cannot be read move straight to the first pose.
+    pub fn play<B: ServoBusProtocol>(&mut self, sequence: Sequence, bus: &mut B) -> Result<(), Error> {
+        sequence.validate()?;
+        let mut from = Pose::new();
+        for id in sequence.joints() {
+            match bus.read_position(id) {
+                Ok(position) => {
+                    from.insert(id, position);
+                }
+                Err(e) => warn!("Sequence '{}': cannot read servo {}: {:?}", sequence.name, id, e),
+            }
+        }
+        info!("Playing sequence '{}'", sequence.name);
+        self.from = from;
+        self.sequence = Some(sequence);
+        self.keyframe = 0;
+        self.loop_index = 0;
+        self.keyframe_elapsed = Duration::ZERO;
+        self.clock = Instant::now();
+        self.last_setpoint = None;
+        self.state = PlayerState::Playing;
+        Ok(())
+    }
+
+    /// Freeze playback; the servos finish their last short move and hold.
+    pub fn pause(&mut self) {
+        if self.state == PlayerState::Playing {
+            self.advance(Instant::now());
+            self.state = PlayerState::Paused;
+        }
+    }
+
+    /// Continue a paused sequence where it left off.
+    pub fn resume(&mut self) {
+        if self.state == PlayerState::Paused {
+            self.clock = Instant::now();
+            self.state = PlayerState::Playing;
+        }
+    }
+
+    /// Stop playback and forget the sequence.
+    pub fn stop(&mut self) {
+        if self.state != PlayerState::Stopped {
+            info!("Sequence stopped");
+        }
+        self.state = PlayerState::Stopped;
+        self.sequence = None;
+    }
+
+    pub fn state(&self) -> PlayerState {
+        self.state
+    }
+
+    /// Play at `speed` times the authored speed from now on, e.g. 0.5 to derate a hot servo. Keyframe and hold
+    /// times stretch by `1 / speed`; the setpoint rate is unchanged. Fails unless `speed` is positive and finite.
+    pub fn set_speed(&mut self, speed: f32) -> Result<(), Error> {
+        if !(speed.is_finite() && speed > 0.0) {
+            anyhow::bail!("Invalid playback speed {}: must be a positive number", speed);
+        }
+        if speed != self.speed {
+            // Time played so far counts at the old speed
+            if self.state == PlayerState::Playing {
+                self.advance(Instant::now());
+            }
+            self.speed = speed;
+        }
+        Ok(())
+    }
+
+    /// Fraction of the authored speed the sequence plays at.
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Move time of each setpoint in milliseconds (one period).
+    pub fn period_ms(&self) -> u16 {
+        self.period.as_millis() as u16
+    }
+
+    /// The setpoints sent by the last tick that sent any, once. Lets supervisors follow the commanded motion.
+    pub fn take_setpoints(&mut self) -> Option<Pose> {
+        self.sent.take()
+    }
+
+    /// Name of the loaded sequence, if any.
+    pub fn sequence_name(&self) -> Option<&str> {
+        self.sequence.as_ref().map(|sequence| sequence.name.as_str())
+    }
+
+    /// Advance playback and send the setpoints that are due. Returns the state after the tick.
+    pub fn tick<B: ServoBusProtocol>(&mut self, bus: &mut B) -> Result<PlayerState, Error> {
+        self.tick_at(bus, Instant::now())
+    }
+
+    /// [`tick`](Self::tick) at time `now`.
+    pub fn tick_at<B: ServoBusProtocol>(&mut self, bus: &mut B, now: Instant) -> Result<PlayerState, Error> {
+        if self.state != PlayerState::Playing {
+            return Ok(self.state);
+        }
+        if let Some(last) = self.last_setpoint {
+            if now.duration_since(last) < self.period {
+                return Ok(self.state);
+            }
+        }
+        self.advance(now);
+        let sequence = match &self.sequence {
+            Some(sequence) => sequence,
+            None => {
+                self.state = PlayerState::Stopped;
+                return Ok(self.state);
+            }
+        };
+
+        // Skip over every keyframe (and hold) that has fully elapsed since the last tick
+        loop {
+            let keyframe = &sequence.keyframes[self.keyframe];
+            let length = keyframe.length();
+            if self.keyframe_elapsed < length {
+                break;
+            }
+            let reached = sequence.pose_of(self.keyframe).cloned().unwrap_or_default();
+            self.from.extend(reached);
+            self.keyframe_elapsed -= length;
+            self.keyframe += 1;
+            if self.keyframe == sequence.keyframes.len() {
+                self.keyframe = 0;
+                self.loop_index += 1;
+                if sequence.loops != 0 && self.loop_index >= sequence.loops {
+                    info!("Sequence '{}' finished", sequence.name);
+                    self.state = PlayerState::Stopped;
+                    self.sequence = None;
+                    return Ok(self.state);
+                }
+            }
+        }
+
+        let keyframe = &sequence.keyframes[self.keyframe];
+        let target = match sequence.pose_of(self.keyframe) {
+            Some(pose) => pose,
+            None => anyhow::bail!("Sequence '{}': keyframe {} has no pose", sequence.name, self.keyframe),
+        };
+        let progress = if keyframe.duration_ms == 0 {
+            1.0
+        } else {
+            // Aim at where the motion should be one period from now, since that is when the servo gets there
+            let ahead = self.keyframe_elapsed + self.period.mul_f32(self.speed);
+            keyframe.easing.apply(ahead.as_secs_f32() * 1000.0 / keyframe.duration_ms as f32)
+        };
+        let setpoints = interpolate(&self.from, target, progress);
+        let period_ms = self.period.as_millis() as u16;
+        self.last_setpoint = Some(now);
+        for (id, position) in &setpoints {
+            bus.move_to_position(*id, *position, period_ms)?;
+        }
+        self.sent = Some(setpoints);
+        Ok(self.state)
+    }
+
+    /// Move the playback clock up to `now` at the current speed.
+    fn advance(&mut self, now: Instant) {
+        self.keyframe_elapsed += now.saturating_duration_since(self.clock).mul_f32(self.speed);
+        self.clock = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::servo_bus::ServoMode;
+    use crate::stall::{StallConfig, StallSupervisor};
+
+    /// A single servo that moves towards its last target at no more than `max_velocity` units per second.
+    struct SlowServo {
+        position: f32,
+        target: f32,
+        max_velocity: f32,
+    }
+
+    impl SlowServo {
+        fn follow(&mut self, dt: Duration) {
+            let step = self.max_velocity * dt.as_secs_f32();
+            self.position += (self.target - self.position).clamp(-step, step);
+        }
+    }
+
+    impl ServoBusProtocol for SlowServo {
+        fn position_max(&self, _id: u8) -> u16 {
+            1000
+        }
+
+        fn units_per_degree(&self, _id: u8) -> f32 {
+            1000.0 / 240.0
+        }
+
+        fn move_to_position(&mut self, _id: u8, position: u16, _time_ms: u16) -> Result<(), Error> {
+            self.target = position as f32;
+            Ok(())
+        }
+
+        fn ping(&mut self, _id: u8) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn read_position(&mut self, _id: u8) -> Result<u16, Error> {
+            Ok(self.position.round() as u16)
+        }
+
+        fn set_torque(&mut self, _id: u8, _enable: bool) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn set_mode(&mut self, _id: u8, _mode: ServoMode) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn read_mode(&mut self, _id: u8) -> Result<ServoMode, Error> {
+            Ok(ServoMode::Position)
+        }
+
+        fn set_position_limits(&mut self, _id: u8, _min: u16, _max: u16) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn emergency_stop(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    /// 800 units in one second, faster than the servo's 500 units/s.
+    const SEQUENCE: &str = r#"{
+        "version": 1,
+        "name": "reach",
+        "poses": { "up": { "1": 800 } },
+        "keyframes": [{ "pose": "up", "duration_ms": 1000, "hold_ms": 500 }],
+        "loops": 1
+    }"#;
+
+    /// Play the sequence at `speed` on a simulated servo, supervised like in the main loop. Returns when the setpoint
+    /// first reached the target pose and whether the servo stalled.
+    fn play(speed: f32) -> (Duration, bool) {
+        let mut servo = SlowServo { position: 0.0, target: 0.0, max_velocity: 500.0 };
+        let mut stall = StallSupervisor::new(StallConfig::default());
+        let mut player = SequencePlayer::new(25.0).unwrap();
+        player.play(Sequence::from_json(SEQUENCE).unwrap(), &mut servo).unwrap();
+        player.set_speed(speed).unwrap();
+
+        let start = player.clock;
+        let step = Duration::from_millis(10);
+        let (mut reached, mut stalled) = (None, false);
+        let mut now = start;
+        while player.state() == PlayerState::Playing {
+            player.tick_at(&mut servo, now).unwrap();
+            if let Some(setpoints) = player.take_setpoints() {
+                let position = setpoints[&1];
+                stall.expect_at(1, position, player.period_ms(), now);
+                if position == 800 && reached.is_none() {
+                    reached = Some(now.duration_since(start));
+                }
+            }
+            servo.follow(step);
+            now += step;
+            stalled |= stall.record(1, servo.position.round() as u16, now).is_some();
+        }
+        (reached.unwrap(), stalled)
+    }
+
+    #[test]
+    fn derated_sequence_plays_slower_without_stalling() {
+        let (full_speed, stalled) = play(1.0);
+        assert!(stalled, "the servo cannot follow the sequence at full speed");
+
+        let (derated, stalled) = play(0.5);
+        assert!(!stalled, "the derated sequence tripped the stall check");
+        assert!(derated >= full_speed * 19 / 10, "{:?} derated against {:?}", derated, full_speed);
+    }
+
+    #[test]
+    fn invalid_speeds_are_refused() {
+        let mut player = SequencePlayer::new(25.0).unwrap();
+        for speed in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+            assert!(player.set_speed(speed).is_err(), "speed {} accepted", speed);
+        }
+        assert_eq!(player.speed(), 1.0);
+    }
+}
@@ -0,0 +1,89 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use anyhow::Error;
+
+use super::Sequence;
+
+/// NVS namespace holding the authored sequences.
+const NVS_NAMESPACE: &str = "sequences";
+/// Key of the list of stored sequence names.
+const INDEX_KEY: &str = "_index";
+/// NVS keys are limited to 15 characters.
+const MAX_NAME_LEN: usize = 15;
+
+/// Sequences stored on flash as JSON blobs, one NVS entry per sequence name.
+///
+/// Animators upload new motions as JSON (see [`Sequence`]) without recompiling the firmware.
+pub struct SequenceStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl SequenceStore {
+    /// Open (or create) the sequence namespace on the default NVS partition.
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self, Error> {
+        let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+        Ok(SequenceStore { nvs })
+    }
+
+    /// Validate and store a sequence under its name, replacing any previous version.
+    pub fn save(&mut self, sequence: &Sequence) -> Result<(), Error> {
+        sequence.validate()?;
+        Self::check_name(&sequence.name)?;
+        self.nvs.set_blob(&sequence.name, sequence.to_json()?.as_bytes())?;
+        let mut names = self.names()?;
+        if !names.contains(&sequence.name) {
+            names.push(sequence.name.clone());
+            names.sort();
+            self.nvs.set_blob(INDEX_KEY, serde_json::to_string(&names)?.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Parse, validate and store a sequence uploaded as JSON. Returns its name.
+    pub fn save_json(&mut self, json: &str) -> Result<String, Error> {
+        let sequence = Sequence::from_json(json)?;
+        self.save(&sequence)?;
+        Ok(sequence.name)
+    }
+
+    /// Load a stored sequence by name.
+    pub fn load(&self, name: &str) -> Result<Option<Sequence>, Error> {
+        Self::check_name(name)?;
+        match self.get_blob(name)? {
+            Some(bytes) => Ok(Some(Sequence::from_json(core::str::from_utf8(&bytes)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Delete a stored sequence.
+    pub fn remove(&mut self, name: &str) -> Result<(), Error> {
+        Self::check_name(name)?;
+        self.nvs.remove(name)?;
+        let names: Vec<String> = self.names()?.into_iter().filter(|stored| stored != name).collect();
+        self.nvs.set_blob(INDEX_KEY, serde_json::to_string(&names)?.as_bytes())?;
+        Ok(())
+    }
+
+    /// Names of every stored sequence.
+    pub fn names(&self) -> Result<Vec<String>, Error> {
+        match self.get_blob(INDEX_KEY)? {
+            Some(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn get_blob(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let len = match self.nvs.blob_len(key)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        let mut buf = vec![0u8; len];
+        Ok(self.nvs.get_blob(key, &mut buf)?.map(<[u8]>::to_vec))
+    }
+
+    fn check_name(name: &str) -> Result<(), Error> {
+        if name.is_empty() || name.len() > MAX_NAME_LEN || name == INDEX_KEY {
+            anyhow::bail!("Sequence name '{}' must be 1-{} characters and not '{}'", name, MAX_NAME_LEN, INDEX_KEY);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+#![allow(dead_code)]
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use serde::{Deserialize, Serialize};
+use anyhow::Error;
+use log::*;
+
+use crate::lewan_bus::config::{ConfigReport, ServoConfig};
+use crate::lewan_bus::LewanSoulBus;
+
+/// NVS namespace holding the servo configuration backups.
+const NVS_NAMESPACE: &str = "servo_cfg";
+/// Key of the list of backed-up IDs (NVS has no key iteration in `EspNvs`).
+const INDEX_KEY: &str = "ids";
+/// Version of the JSON export format.
+const BACKUP_FORMAT_VERSION: u32 = 1;
+
+/// JSON document produced by [`ServoBackup::export_json`] and accepted by [`ServoBackup::import_json`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupFile {
+    pub version: u32,
+    pub servos: Vec<ServoConfig>,
+}
+
+/// Backup of every servo's configuration in NVS, one JSON entry per servo ID.
+///
+/// After a servo swap or an accidental factory reset, [`restore`](Self::restore) writes the saved offsets, limits and ID
+/// back in one operation. The whole backup can be moved between robots with [`export_json`](Self::export_json) and
+/// [`import_json`](Self::import_json).
+pub struct ServoBackup {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl ServoBackup {
+    /// Open (or create) the backup namespace on the default NVS partition.
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self, Error> {
+        let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+        Ok(ServoBackup { nvs })
+    }
+
+    /// Read the configuration of every servo in `ids` from the bus and store it, replacing older backups.
+    pub fn backup(&mut self, bus: &mut LewanSoulBus, ids: &[u8]) -> Result<Vec<ServoConfig>, Error> {
+        let mut configs = Vec::with_capacity(ids.len());
+        for id in ids {
+            let config = bus.read_config(*id)?;
+            self.store(&config)?;
+            configs.push(config);
+        }
+        Ok(configs)
+    }
+
+    /// Back up the servos in `ids` that have no backup yet. Existing backups are kept, so a freshly swapped servo
+    /// does not overwrite the configuration it should be restored to. Servos that do not answer are skipped with a warning.
+    pub fn backup_missing(&mut self, bus: &mut LewanSoulBus, ids: &[u8]) -> Result<(), Error> {
+        for id in ids {
+            if self.load(*id)?.is_some() {
+                continue;
+            }
+            match bus.read_config(*id) {
+                Ok(config) => {
+                    self.store(&config)?;
+                    info!("Backed up configuration of servo {}", id);
+                }
+                Err(e) => warn!("Cannot back up servo {}: {:?}", id, e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Store one servo configuration under its ID.
+    pub fn store(&mut self, config: &ServoConfig) -> Result<(), Error> {
+        let json = serde_json::to_string(config)?;
+        self.nvs.set_str(&Self::key(config.id), &json)?;
+        let mut ids = self.ids()?;
+        if !ids.contains(&config.id) {
+            ids.push(config.id);
+            ids.sort_unstable();
+            self.nvs.set_str(INDEX_KEY, &serde_json::to_string(&ids)?)?;
+        }
+        Ok(())
+    }
+
+    /// Load the stored configuration for `id`, if any.
+    pub fn load(&self, id: u8) -> Result<Option<ServoConfig>, Error> {
+        match self.get_str(&Self::key(id))? {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Load every stored configuration, ordered by ID.
+    pub fn load_all(&self) -> Result<Vec<ServoConfig>, Error> {
+        let mut configs = Vec::new();
+        for id in self.ids()? {
+            if let Some(config) = self.load(id)? {
+                configs.push(config);
+            }
+        }
+        Ok(configs)
+    }
+
+    /// Delete the stored configuration for `id`.
+    pub fn remove(&mut self, id: u8) -> Result<(), Error> {
+        self.nvs.remove(&Self::key(id))?;
+        let ids: Vec<u8> = self.ids()?.into_iter().filter(|stored| *stored != id).collect();
+        self.nvs.set_str(INDEX_KEY, &serde_json::to_string(&ids)?)?;
+        Ok(())
+    }
+
+    /// Restore the backup of servo `backup_id` onto the servo currently answering at `current_id`.
+    ///
+    /// A replacement or factory-reset servo usually answers at ID 1; restoring also writes the saved ID,
+    /// so `restore(bus, 1, 7)` turns it into servo 7 with servo 7's offsets and limits.
+    pub fn restore(&self, bus: &mut LewanSoulBus, current_id: u8, backup_id: u8) -> Result<ConfigReport, Error> {
+        let config = self
+            .load(backup_id)?
+            .ok_or_else(|| anyhow::anyhow!("No backup stored for servo {}", backup_id))?;
+        let report = bus.apply_config(current_id, &config)?;
+        for change in &report.changes {
+            info!(
+                "Servo {}: {} {} -> {}{}",
+                backup_id,
+                change.field,
+                change.from,
+                change.to,
+                if change.verified { "" } else { " (NOT VERIFIED)" }
+            );
+        }
+        Ok(report)
+    }
+
+    /// Export every stored configuration as a versioned JSON document.
+    pub fn export_json(&self) -> Result<String, Error> {
+        let file = BackupFile {
+            version: BACKUP_FORMAT_VERSION,
+            servos: self.load_all()?,
+        };
+        Ok(serde_json::to_string_pretty(&file)?)
+    }
+
+    /// Import a JSON document produced by [`export_json`](Self::export_json), replacing the stored entries it contains.
+    /// Returns the number of configurations imported.
+    ///
+    /// Every configuration is checked with [`ServoConfig::validate`] against the model `bus` uses for its ID before
+    /// anything is stored, so a bad file changes nothing.
+    pub fn import_json(&mut self, bus: &LewanSoulBus, json: &str) -> Result<usize, Error> {
+        let file: BackupFile = serde_json::from_str(json)?;
+        if file.version != BACKUP_FORMAT_VERSION {
+            anyhow::bail!("Unsupported backup format version {} (expected {})", file.version, BACKUP_FORMAT_VERSION);
+        }
+        for config in &file.servos {
+            config.validate(bus.model(config.id))?;
+        }
+        for config in &file.servos {
+            self.store(config)?;
+        }
+        Ok(file.servos.len())
+    }
+
+    fn ids(&self) -> Result<Vec<u8>, Error> {
+        match self.get_str(INDEX_KEY)? {
+            Some(json) => Ok(serde_json::from_str(&json)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn get_str(&self, key: &str) -> Result<Option<String>, Error> {
+        let len = match self.nvs.str_len(key)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        let mut buf = vec![0u8; len + 1];
+        Ok(self.nvs.get_str(key, &mut buf)?.map(str::to_owned))
+    }
+
+    fn key(id: u8) -> String {
+        format!("servo_{}", id)
+    }
+}
@@ -0,0 +1,188 @@
+use esp_idf_hal::uart::{config::Config, Uart};
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_hal::gpio::{OutputPin, InputPin};
+use anyhow::Error;
+use std::collections::HashMap;
+
+use super::packet::{PacketBus, BROADCAST_ID};
+use super::{check_position, check_position_limits, ServoBusProtocol, ServoMode, SpeedOutOfRange, MOTOR_SPEED_RANGE};
+use crate::estop::EStop;
+
+/// Control table addresses of Protocol 1.0 servos (AX/MX series), all little-endian
+const REG_CW_ANGLE_LIMIT: u8 = 6;      // 2 bytes, followed by CCW angle limit (8)
+const REG_TORQUE_ENABLE: u8 = 24;      // 1 byte
+const REG_GOAL_POSITION: u8 = 30;      // 2 bytes, followed by moving speed (32)
+const REG_MOVING_SPEED: u8 = 32;       // 2 bytes, 0 = maximum speed in joint mode
+const REG_PRESENT_POSITION: u8 = 36;   // 2 bytes
+
+const POSITION_MAX: u16 = 1023;        // 0-1023 over 300°
+const ANGLE_RANGE_DEG: f32 = 300.0;
+const SPEED_MAX: u16 = 1023;           // Moving speed register full scale
+const SPEED_UNIT_DEG_S: f32 = 0.666;   // 0.111 rpm per unit
+const WHEEL_CW_BIT: u16 = 1 << 10;     // Direction bit of the wheel-mode speed
+
+/// A controller for Dynamixel Protocol 1.0 servos (AX-12A, AX-18A, MX in 1.0 mode) on a half-duplex UART bus.
+///
+/// Implements [`ServoBusProtocol`]. Move times are turned into a moving speed from the distance to the last commanded goal,
+/// since Protocol 1.0 servos have no move-in-time command. AX servos default to 1 Mbps.
+pub struct DynamixelBus<'a> {
+    bus: PacketBus<'a>,
+    last_goal: HashMap<u8, u16>,
+    /// Angle limits replaced by wheel mode, restored when the servo goes back to position mode.
+    saved_limits: HashMap<u8, (u16, u16)>,
+    /// Blocks motion commands while latched.
+    estop: Option<EStop>,
+}
+
+impl<'a> DynamixelBus<'a> {
+    /// Create a new DynamixelBus controller on the given UART and pins.
+    pub fn new<UART, U, TX, P1, RX, P2>(
+        uart: UART,
+        tx_pin: TX,
+        rx_pin: RX,
+        config: &Config,
+    ) -> Result<Self, Error>
+    where
+        UART: Peripheral<P = U> + 'a,
+        U: Uart,
+        TX: Peripheral<P = P1> + 'a,
+        P1: OutputPin,
+        RX: Peripheral<P = P2> + 'a,
+        P2: InputPin,
+    {
+        Ok(DynamixelBus {
+            bus: PacketBus::new(uart, tx_pin, rx_pin, config)?,
+            last_goal: HashMap::new(),
+            saved_limits: HashMap::new(),
+            estop: None,
+        })
+    }
+
+    /// Refuse moves, motor mode and torque enable with [`EStopActive`](crate::estop::EStopActive) while `estop` is latched.
+    pub fn attach_estop(&mut self, estop: EStop) {
+        self.estop = Some(estop);
+    }
+
+    fn read_word(&mut self, id: u8, address: u8) -> Result<u16, Error> {
+        let data = self.bus.read(id, address, 2)?;
+        Ok(u16::from_le_bytes([data[0], data[1]]))
+    }
+
+    fn write_word(&mut self, id: u8, address: u8, value: u16) -> Result<(), Error> {
+        self.bus.write(id, address, &value.to_le_bytes())
+    }
+
+    /// Read the CW and CCW angle limits; both 0 means wheel mode.
+    fn read_position_limits(&mut self, id: u8) -> Result<(u16, u16), Error> {
+        let data = self.bus.read(id, REG_CW_ANGLE_LIMIT, 4)?;
+        Ok((u16::from_le_bytes([data[0], data[1]]), u16::from_le_bytes([data[2], data[3]])))
+    }
+
+    fn check_estop(&self) -> Result<(), Error> {
+        match &self.estop {
+            Some(estop) => estop.check(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl ServoBusProtocol for DynamixelBus<'_> {
+    fn position_max(&self, _id: u8) -> u16 {
+        POSITION_MAX
+    }
+
+    fn units_per_degree(&self, _id: u8) -> f32 {
+        POSITION_MAX as f32 / ANGLE_RANGE_DEG
+    }
+
+    fn move_to_position(&mut self, id: u8, position: u16, time_ms: u16) -> Result<(), Error> {
+        self.check_estop()?;
+        check_position(id, position, POSITION_MAX)?;
+        let speed = if time_ms == 0 {
+            0
+        } else {
+            let from = match self.last_goal.get(&id) {
+                Some(goal) => *goal,
+                None => self.read_position(id)?,
+            };
+            let degrees = (position as f32 - from as f32).abs() / self.units_per_degree(id);
+            let deg_per_s = degrees * 1000.0 / time_ms as f32;
+            // 0 would mean "maximum speed", so never round a slow move down to it
+            ((deg_per_s / SPEED_UNIT_DEG_S).round() as u16).clamp(1, SPEED_MAX)
+        };
+        let [pos_low, pos_high] = position.to_le_bytes();
+        let [speed_low, speed_high] = speed.to_le_bytes();
+        self.bus.write(id, REG_GOAL_POSITION, &[pos_low, pos_high, speed_low, speed_high])?;
+        self.last_goal.insert(id, position);
+        Ok(())
+    }
+
+    fn ping(&mut self, id: u8) -> Result<(), Error> {
+        self.bus.ping(id)
+    }
+
+    fn read_position(&mut self, id: u8) -> Result<u16, Error> {
+        Ok(self.read_word(id, REG_PRESENT_POSITION)?.min(POSITION_MAX))
+    }
+
+    fn set_torque(&mut self, id: u8, enable: bool) -> Result<(), Error> {
+        if enable {
+            self.check_estop()?;
+        }
+        self.bus.write(id, REG_TORQUE_ENABLE, &[enable as u8])
+    }
+
+    fn set_mode(&mut self, id: u8, mode: ServoMode) -> Result<(), Error> {
+        match mode {
+            ServoMode::Position => {
+                // Limits unknown (wheel mode set before boot) fall back to the full range
+                let (min, max) = self.saved_limits.get(&id).copied().unwrap_or((0, POSITION_MAX));
+                self.set_position_limits(id, min, max)?;
+                self.saved_limits.remove(&id);
+                self.write_word(id, REG_MOVING_SPEED, 0)
+            }
+            ServoMode::Motor { speed } => {
+                if !MOTOR_SPEED_RANGE.contains(&speed) {
+                    return Err(SpeedOutOfRange { speed }.into());
+                }
+                self.check_estop()?;
+                if !self.saved_limits.contains_key(&id) {
+                    let limits = self.read_position_limits(id)?;
+                    if limits != (0, 0) {
+                        self.saved_limits.insert(id, limits);
+                    }
+                }
+                // Wheel mode is selected by setting both angle limits to 0
+                self.set_position_limits(id, 0, 0)?;
+                self.last_goal.remove(&id);
+                let magnitude = (speed.unsigned_abs() as u32 * SPEED_MAX as u32 / 1000) as u16;
+                let value = if speed < 0 { magnitude | WHEEL_CW_BIT } else { magnitude };
+                self.write_word(id, REG_MOVING_SPEED, value)
+            }
+        }
+    }
+
+    fn read_mode(&mut self, id: u8) -> Result<ServoMode, Error> {
+        if self.read_position_limits(id)? != (0, 0) {
+            return Ok(ServoMode::Position);
+        }
+        let value = self.read_word(id, REG_MOVING_SPEED)?;
+        let magnitude = ((value & (WHEEL_CW_BIT - 1)) as u32 * 1000 / SPEED_MAX as u32).min(1000) as i16;
+        let speed = if value & WHEEL_CW_BIT != 0 { -magnitude } else { magnitude };
+        Ok(ServoMode::Motor { speed })
+    }
+
+    fn set_position_limits(&mut self, id: u8, min: u16, max: u16) -> Result<(), Error> {
+        check_position_limits(id, min, max, POSITION_MAX)?;
+        let [min_low, min_high] = min.to_le_bytes();
+        let [max_low, max_high] = max.to_le_bytes();
+        self.bus.write(id, REG_CW_ANGLE_LIMIT, &[min_low, min_high, max_low, max_high])
+    }
+
+    fn emergency_stop(&mut self) -> Result<(), Error> {
+        // There is no stop instruction; unloading every servo stops it where it is
+        self.bus.write(BROADCAST_ID, REG_TORQUE_ENABLE, &[0])?;
+        self.last_goal.clear();
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+use esp_idf_hal::uart::{config::Config, Uart};
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_hal::gpio::{OutputPin, InputPin};
+use anyhow::Error;
+use std::collections::HashMap;
+
+use super::packet::{PacketBus, BROADCAST_ID};
+use super::{check_position, check_position_limits, ServoBusProtocol, ServoMode, SpeedOutOfRange, MOTOR_SPEED_RANGE};
+use crate::estop::EStop;
+
+/// Register addresses shared by the SCS and STS control tables
+const REG_MIN_ANGLE_LIMIT: u8 = 9;     // 2 bytes, EEPROM
+const REG_MAX_ANGLE_LIMIT: u8 = 11;    // 2 bytes, EEPROM
+const REG_MODE: u8 = 33;               // STS only: 0 = position, 1 = wheel (constant speed)
+const REG_TORQUE_ENABLE: u8 = 40;      // 1 byte, 0 = free, 1 = torque on
+const REG_GOAL_POSITION: u8 = 42;      // 2 bytes, followed by goal time (44) and goal speed (46)
+const REG_GOAL_SPEED: u8 = 46;         // 2 bytes, sign-magnitude in wheel mode
+const REG_PRESENT_POSITION: u8 = 56;   // 2 bytes
+
+/// Feetech servo series. SCS and STS share the packet format and most of the control table,
+/// but differ in resolution, byte order, EEPROM lock register and how wheel mode is selected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeetechSeries {
+    /// SCS series (e.g. SCS15): 0-1023 over 200°, big-endian registers, wheel mode when both angle limits are 0.
+    Scs,
+    /// STS/SMS series (e.g. STS3215): 0-4095 over 360°, little-endian registers, wheel mode via the mode register.
+    Sts,
+}
+
+impl FeetechSeries {
+    fn position_max(self) -> u16 {
+        match self {
+            FeetechSeries::Scs => 1023,
+            FeetechSeries::Sts => 4095,
+        }
+    }
+
+    fn angle_range_deg(self) -> f32 {
+        match self {
+            FeetechSeries::Scs => 200.0,
+            FeetechSeries::Sts => 360.0,
+        }
+    }
+
+    /// Register that write-protects the EEPROM area (angle limits live there).
+    fn lock_register(self) -> u8 {
+        match self {
+            FeetechSeries::Scs => 48,
+            FeetechSeries::Sts => 55,
+        }
+    }
+
+    /// Bit carrying the direction in sign-magnitude speed values.
+    fn direction_bit(self) -> u16 {
+        match self {
+            FeetechSeries::Scs => 1 << 10,
+            FeetechSeries::Sts => 1 << 15,
+        }
+    }
+
+    /// Speed register value corresponding to full speed in wheel mode.
+    fn max_wheel_speed(self) -> u16 {
+        match self {
+            FeetechSeries::Scs => 1023,
+            FeetechSeries::Sts => 3000, // steps/s
+        }
+    }
+
+    fn encode_word(self, value: u16) -> [u8; 2] {
+        match self {
+            FeetechSeries::Scs => value.to_be_bytes(),
+            FeetechSeries::Sts => value.to_le_bytes(),
+        }
+    }
+
+    fn decode_word(self, bytes: &[u8]) -> u16 {
+        match self {
+            FeetechSeries::Scs => u16::from_be_bytes([bytes[0], bytes[1]]),
+            FeetechSeries::Sts => u16::from_le_bytes([bytes[0], bytes[1]]),
+        }
+    }
+
+    /// Convert a -1000..=1000 motor speed to the sign-magnitude wheel speed register value.
+    fn encode_wheel_speed(self, speed: i16) -> u16 {
+        let magnitude = (speed.unsigned_abs() as u32 * self.max_wheel_speed() as u32 / 1000) as u16;
+        if speed < 0 { magnitude | self.direction_bit() } else { magnitude }
+    }
+
+    /// Convert a sign-magnitude wheel speed register value back to -1000..=1000.
+    fn decode_wheel_speed(self, value: u16) -> i16 {
+        let magnitude = value & (self.direction_bit() - 1);
+        let speed = (magnitude as u32 * 1000 / self.max_wheel_speed() as u32).min(1000) as i16;
+        if value & self.direction_bit() != 0 { -speed } else { speed }
+    }
+}
+
+/// A controller for Feetech SCS/STS serial bus servos on a half-duplex UART bus (0xFF 0xFF header, register reads/writes).
+///
+/// Implements [`ServoBusProtocol`], so code written against the trait runs unchanged on LewanSoul or Feetech servos.
+/// STS servos default to 1 Mbps; SCS servos to 1 Mbps or 500 kbps depending on the model.
+pub struct FeetechBus<'a> {
+    bus: PacketBus<'a>,
+    series: FeetechSeries,
+    /// Last commanded goal per ID, used to turn a move time into an STS goal speed.
+    last_goal: HashMap<u8, u16>,
+    /// SCS angle limits replaced by wheel mode, restored when the servo goes back to position mode.
+    saved_limits: HashMap<u8, (u16, u16)>,
+    /// Blocks motion commands while latched.
+    estop: Option<EStop>,
+}
+
+impl<'a> FeetechBus<'a> {
+    /// Create a new FeetechBus controller on the given UART and pins for servos of the given series.
+    pub fn new<UART, U, TX, P1, RX, P2>(
+        uart: UART,
+        tx_pin: TX,
+        rx_pin: RX,
+        config: &Config,
+        series: FeetechSeries,
+    ) -> Result<Self, Error>
+    where
+        UART: Peripheral<P = U> + 'a,
+        U: Uart,
+        TX: Peripheral<P = P1> + 'a,
+        P1: OutputPin,
+        RX: Peripheral<P = P2> + 'a,
+        P2: InputPin,
+    {
+        Ok(FeetechBus {
+            bus: PacketBus::new(uart, tx_pin, rx_pin, config)?,
+            series,
+            last_goal: HashMap::new(),
+            saved_limits: HashMap::new(),
+            estop: None,
+        })
+    }
+
+    /// Refuse moves, motor mode and torque enable with [`EStopActive`](crate::estop::EStopActive) while `estop` is latched.
+    pub fn attach_estop(&mut self, estop: EStop) {
+        self.estop = Some(estop);
+    }
+
+    fn read_word(&mut self, id: u8, address: u8) -> Result<u16, Error> {
+        let data = self.bus.read(id, address, 2)?;
+        Ok(self.series.decode_word(&data))
+    }
+
+    fn write_word(&mut self, id: u8, address: u8, value: u16) -> Result<(), Error> {
+        let data = self.series.encode_word(value);
+        self.bus.write(id, address, &data)
+    }
+
+    fn check_estop(&self) -> Result<(), Error> {
+        match &self.estop {
+            Some(estop) => estop.check(),
+            None => Ok(()),
+        }
+    }
+
+    /// Read the minimum and maximum angle limits; both 0 means wheel mode on SCS servos.
+    fn read_position_limits(&mut self, id: u8) -> Result<(u16, u16), Error> {
+        let data = self.bus.read(id, REG_MIN_ANGLE_LIMIT, 4)?;
+        Ok((self.series.decode_word(&data[0..2]), self.series.decode_word(&data[2..4])))
+    }
+
+    /// Run `write` with the EEPROM lock released, re-locking afterwards even if the write failed.
+    /// The error of `write` takes precedence over a failure to re-lock.
+    fn with_eeprom_unlocked<F>(&mut self, id: u8, write: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Self) -> Result<(), Error>,
+    {
+        let lock = self.series.lock_register();
+        self.bus.write(id, lock, &[0])?;
+        let result = write(self);
+        let relock = self.bus.write(id, lock, &[1]);
+        result.and(relock)
+    }
+}
+
+impl ServoBusProtocol for FeetechBus<'_> {
+    fn position_max(&self, _id: u8) -> u16 {
+        self.series.position_max()
+    }
+
+    fn units_per_degree(&self, _id: u8) -> f32 {
+        self.series.position_max() as f32 / self.series.angle_range_deg()
+    }
+
+    fn move_to_position(&mut self, id: u8, position: u16, time_ms: u16) -> Result<(), Error> {
+        self.check_estop()?;
+        check_position(id, position, self.series.position_max())?;
+        // SCS servos honour the goal time directly; STS servos ignore it and need a goal speed instead
+        let speed = match self.series {
+            FeetechSeries::Scs => 0,
+            FeetechSeries::Sts if time_ms == 0 => 0, // 0 = maximum speed
+            FeetechSeries::Sts => {
+                let from = match self.last_goal.get(&id) {
+                    Some(goal) => *goal,
+                    None => self.read_position(id)?,
+                };
+                let distance = (position as i32 - from as i32).unsigned_abs();
+                (distance * 1000 / time_ms as u32).clamp(1, self.series.max_wheel_speed() as u32) as u16
+            }
+        };
+        let mut data = [0u8; 6];
+        data[0..2].copy_from_slice(&self.series.encode_word(position));
+        data[2..4].copy_from_slice(&self.series.encode_word(time_ms));
+        data[4..6].copy_from_slice(&self.series.encode_word(speed));
+        self.bus.write(id, REG_GOAL_POSITION, &data)?;
+        self.last_goal.insert(id, position);
+        Ok(())
+    }
+
+    fn ping(&mut self, id: u8) -> Result<(), Error> {
+        self.bus.ping(id)
+    }
+
+    fn read_position(&mut self, id: u8) -> Result<u16, Error> {
+        // STS reports negative positions with bit 15 set in multi-turn setups; keep the single-turn part only
+        let position = self.read_word(id, REG_PRESENT_POSITION)? & 0x7FFF;
+        Ok(position.min(self.series.position_max()))
+    }
+
+    fn set_torque(&mut self, id: u8, enable: bool) -> Result<(), Error> {
+        if enable {
+            self.check_estop()?;
+        }
+        self.bus.write(id, REG_TORQUE_ENABLE, &[enable as u8])
+    }
+
+    fn set_mode(&mut self, id: u8, mode: ServoMode) -> Result<(), Error> {
+        let speed = match mode {
+            ServoMode::Position => 0,
+            ServoMode::Motor { speed } => {
+                if !MOTOR_SPEED_RANGE.contains(&speed) {
+                    return Err(SpeedOutOfRange { speed }.into());
+                }
+                self.check_estop()?;
+                speed
+            }
+        };
+        let motor = matches!(mode, ServoMode::Motor { .. });
+        match self.series {
+            FeetechSeries::Sts => {
+                self.with_eeprom_unlocked(id, |bus| bus.bus.write(id, REG_MODE, &[motor as u8]))?;
+            }
+            FeetechSeries::Scs if motor => {
+                if !self.saved_limits.contains_key(&id) {
+                    let limits = self.read_position_limits(id)?;
+                    if limits != (0, 0) {
+                        self.saved_limits.insert(id, limits);
+                    }
+                }
+                // SCS enters wheel mode when both angle limits are 0
+                self.set_position_limits(id, 0, 0)?;
+            }
+            FeetechSeries::Scs => {
+                // Limits unknown (wheel mode set before boot) fall back to the full range
+                let (min, max) = self.saved_limits.get(&id).copied().unwrap_or((0, self.series.position_max()));
+                self.set_position_limits(id, min, max)?;
+                self.saved_limits.remove(&id);
+            }
+        }
+        self.last_goal.remove(&id);
+        let value = self.series.encode_wheel_speed(speed);
+        self.write_word(id, REG_GOAL_SPEED, value)
+    }
+
+    fn read_mode(&mut self, id: u8) -> Result<ServoMode, Error> {
+        let motor = match self.series {
+            FeetechSeries::Sts => self.bus.read(id, REG_MODE, 1)?[0] == 1,
+            FeetechSeries::Scs => {
+                self.read_position_limits(id)? == (0, 0)
+            }
+        };
+        if !motor {
+            return Ok(ServoMode::Position);
+        }
+        let value = self.read_word(id, REG_GOAL_SPEED)?;
+        Ok(ServoMode::Motor { speed: self.series.decode_wheel_speed(value) })
+    }
+
+    fn set_position_limits(&mut self, id: u8, min: u16, max: u16) -> Result<(), Error> {
+        check_position_limits(id, min, max, self.series.position_max())?;
+        let mut data = [0u8; 4];
+        data[0..2].copy_from_slice(&self.series.encode_word(min));
+        data[2..4].copy_from_slice(&self.series.encode_word(max));
+        self.with_eeprom_unlocked(id, |bus| bus.bus.write(id, REG_MIN_ANGLE_LIMIT, &data))
+    }
+
+    fn emergency_stop(&mut self) -> Result<(), Error> {
+        // There is no stop instruction; unloading every servo stops it where it is
+        self.bus.write(BROADCAST_ID, REG_TORQUE_ENABLE, &[0])?;
+        self.last_goal.clear();
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+#![allow(dead_code)]
+use anyhow::Error;
+
+pub use crate::lewan_bus::ServoMode;
+
+pub mod dynamixel;
+pub mod feetech;
+mod packet;
+
+/// Motor-mode speed range of [`ServoBusProtocol::set_mode`] on every bus.
+pub const MOTOR_SPEED_RANGE: core::ops::RangeInclusive<i16> = -1000..=1000;
+
+/// A motor-mode speed outside [`MOTOR_SPEED_RANGE`] was passed to [`ServoBusProtocol::set_mode`].
+///
+/// Returned wrapped in an [`anyhow::Error`] by every bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpeedOutOfRange {
+    pub speed: i16,
+}
+
+impl core::fmt::Display for SpeedOutOfRange {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "motor speed {} is outside {}..={}",
+            self.speed,
+            MOTOR_SPEED_RANGE.start(),
+            MOTOR_SPEED_RANGE.end()
+        )
+    }
+}
+
+impl std::error::Error for SpeedOutOfRange {}
+
+/// A position beyond `position_max` was passed to [`ServoBusProtocol::move_to_position`] or
+/// [`ServoBusProtocol::set_position_limits`].
+///
+/// Returned wrapped in an [`anyhow::Error`] by every bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PositionOutOfRange {
+    pub id: u8,
+    pub position: u16,
+    pub max: u16,
+}
+
+impl core::fmt::Display for PositionOutOfRange {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "position {} for servo {} is outside 0..={}", self.position, self.id, self.max)
+    }
+}
+
+impl std::error::Error for PositionOutOfRange {}
+
+/// Position limits with `min` above `max` were passed to [`ServoBusProtocol::set_position_limits`].
+///
+/// Returned wrapped in an [`anyhow::Error`] by every bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvertedLimits {
+    pub id: u8,
+    pub min: u16,
+    pub max: u16,
+}
+
+impl core::fmt::Display for InvertedLimits {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "position limit minimum {} of servo {} is above maximum {}", self.min, self.id, self.max)
+    }
+}
+
+impl std::error::Error for InvertedLimits {}
+
+/// Check `position` against `0..=position_max` of servo `id`.
+pub fn check_position(id: u8, position: u16, position_max: u16) -> Result<(), Error> {
+    if position > position_max {
+        return Err(PositionOutOfRange { id, position, max: position_max }.into());
+    }
+    Ok(())
+}
+
+/// Check firmware position limits of servo `id` before they are written: both in range and `min <= max`.
+pub fn check_position_limits(id: u8, min: u16, max: u16, position_max: u16) -> Result<(), Error> {
+    check_position(id, min, position_max)?;
+    check_position(id, max, position_max)?;
+    if min > max {
+        return Err(InvertedLimits { id, min, max }.into());
+    }
+    Ok(())
+}
+
+/// Common operations of a half-duplex serial servo bus.
+///
+/// Implemented by [`LewanSoulBus`](crate::lewan_bus::LewanSoulBus), [`FeetechBus`](feetech::FeetechBus) (SCS/STS) and [`DynamixelBus`](dynamixel::DynamixelBus) (Protocol 1.0),
+/// so motion code can be written once and run on any of them.
+///
+/// Positions are in the servo's raw units (`0..=position_max(id)`); the provided methods convert to and from degrees.
+/// Motor-mode speeds use the LewanSoul convention of -1000 to 1000 (fraction of full speed, sign gives direction) on every bus;
+/// each implementation scales it to its own register format.
+pub trait ServoBusProtocol {
+    /// Highest raw position value of servo `id`.
+    fn position_max(&self, id: u8) -> u16;
+
+    /// Raw position units per degree of rotation of servo `id`.
+    fn units_per_degree(&self, id: u8) -> f32;
+
+    /// Move servo `id` to a raw position, arriving after `time_ms` milliseconds (0 = as fast as possible).
+    /// Positions beyond `position_max` are rejected with [`PositionOutOfRange`].
+    fn move_to_position(&mut self, id: u8, position: u16, time_ms: u16) -> Result<(), Error>;
+
+    /// Check that servo `id` is present and answering.
+    fn ping(&mut self, id: u8) -> Result<(), Error>;
+
+    /// Read the current raw position of servo `id`.
+    fn read_position(&mut self, id: u8) -> Result<u16, Error>;
+
+    /// Enable (load) or disable (unload) the motor torque of servo `id`.
+    fn set_torque(&mut self, id: u8, enable: bool) -> Result<(), Error>;
+
+    /// Switch servo `id` between position mode and motor (continuous rotation) mode. Buses that select motor mode
+    /// through the angle limits restore the limits in force before motor mode when switching back.
+    fn set_mode(&mut self, id: u8, mode: ServoMode) -> Result<(), Error>;
+
+    /// Read the operating mode of servo `id`.
+    fn read_mode(&mut self, id: u8) -> Result<ServoMode, Error>;
+
+    /// Set the firmware position limits of servo `id` in raw units. Validated with [`check_position_limits`].
+    fn set_position_limits(&mut self, id: u8, min: u16, max: u16) -> Result<(), Error>;
+
+    /// Stop every servo on the bus at once with broadcast commands and unload its torque.
+    /// Never blocked by an attached [`EStop`](crate::estop::EStop), since this is what it calls.
+    fn emergency_stop(&mut self) -> Result<(), Error>;
+
+    /// Convert degrees to the nearest raw position of servo `id`, clamped to its range.
+    fn angle_to_position(&self, id: u8, angle: f32) -> u16 {
+        let pos = (angle * self.units_per_degree(id)).round();
+        pos.clamp(0.0, self.position_max(id) as f32) as u16
+    }
+
+    /// Convert a raw position of servo `id` to degrees.
+    fn position_to_angle(&self, id: u8, position: u16) -> f32 {
+        position as f32 / self.units_per_degree(id)
+    }
+
+    /// Move servo `id` to an angle in degrees within `time_ms` milliseconds.
+    fn move_to_angle(&mut self, id: u8, angle: f32, time_ms: u16) -> Result<(), Error> {
+        let position = self.angle_to_position(id, angle);
+        self.move_to_position(id, position, time_ms)
+    }
+
+    /// Read the current angle of servo `id` in degrees.
+    fn read_angle(&mut self, id: u8) -> Result<f32, Error> {
+        let position = self.read_position(id)?;
+        Ok(self.position_to_angle(id, position))
+    }
+
+    /// Set the firmware angle limits of servo `id` in degrees.
+    fn set_angle_limits(&mut self, id: u8, min_angle: f32, max_angle: f32) -> Result<(), Error> {
+        let mut min = self.angle_to_position(id, min_angle);
+        let mut max = self.angle_to_position(id, max_angle);
+        if min > max {
+            core::mem::swap(&mut min, &mut max);
+        }
+        self.set_position_limits(id, min, max)
+    }
+}
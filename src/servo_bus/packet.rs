@@ -0,0 +1,218 @@
+use esp_idf_hal::uart::{UartDriver, config::Config, Uart};
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_hal::gpio::{OutputPin, InputPin, AnyIOPin};
+use esp_idf_sys::esp_timer_get_time;
+use anyhow::Error;
+
+/// Instruction codes shared by Feetech SCS/STS and Dynamixel Protocol 1.0
+pub const INST_PING: u8 = 0x01;       // Ping, replies with an empty status packet
+pub const INST_READ: u8 = 0x02;       // Read N bytes starting at a register address
+pub const INST_WRITE: u8 = 0x03;      // Write bytes starting at a register address
+pub const INST_REG_WRITE: u8 = 0x04;  // Buffered write, executed on ACTION
+pub const INST_ACTION: u8 = 0x05;     // Execute buffered writes
+pub const INST_SYNC_WRITE: u8 = 0x83; // Write the same registers on several servos at once
+
+/// Broadcast ID: every servo executes the instruction, none replies.
+pub const BROADCAST_ID: u8 = 0xFE;
+
+const MAX_FRAME: usize = 32;
+
+/// Errors reported by a servo in its status packet, or by the frame layer itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// A read was addressed to the broadcast ID, which never replies.
+    BroadcastRead { address: u8 },
+    /// The servo answered with a non-zero error byte (bit meanings depend on the servo family).
+    Status { id: u8, error: u8 },
+    /// The reply came from a different servo than the one addressed.
+    WrongId { expected: u8, received: u8 },
+}
+
+impl core::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FrameError::BroadcastRead { address } => write!(
+                f,
+                "read of register {} cannot be sent to broadcast ID {}",
+                address, BROADCAST_ID
+            ),
+            FrameError::Status { id, error } => write!(f, "servo {} reported error 0x{:02X}", id, error),
+            FrameError::WrongId { expected, received } => write!(
+                f,
+                "expected reply from servo {}, got servo {}",
+                expected, received
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Checksum of a 0xFF 0xFF frame: bitwise-NOT of sum(ID+LEN+INSTR/ERR+PARAMS).
+pub fn checksum(body: &[u8]) -> u8 {
+    !(body.iter().fold(0u8, |s, b| s.wrapping_add(*b)))
+}
+
+/// Build an instruction packet: [0xFF, 0xFF, ID, LEN, INSTR, params.., CHK] with LEN = params + 2.
+pub fn encode(id: u8, instruction: u8, params: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(params.len() + 6);
+    frame.extend_from_slice(&[0xFF, 0xFF, id, params.len() as u8 + 2, instruction]);
+    frame.extend_from_slice(params);
+    frame.push(checksum(&frame[2..]));
+    frame
+}
+
+/// Validate a complete status packet [0xFF, 0xFF, ID, LEN, ERR, params.., CHK] and return (ID, ERR, params).
+pub fn decode_status(frame: &[u8]) -> Result<(u8, u8, &[u8]), Error> {
+    if frame.len() < 6 || frame[0] != 0xFF || frame[1] != 0xFF {
+        anyhow::bail!("Malformed status packet: {:?}", frame);
+    }
+    let frame_len = 4 + frame[3] as usize;
+    if frame.len() != frame_len {
+        anyhow::bail!("Status packet length {} does not match LEN field ({} bytes expected)", frame.len(), frame_len);
+    }
+    let chk_rx = frame[frame_len - 1];
+    let chk_calc = checksum(&frame[2..frame_len - 1]);
+    if chk_rx != chk_calc {
+        anyhow::bail!("Checksum error: received 0x{:02X}, calculated 0x{:02X}", chk_rx, chk_calc);
+    }
+    Ok((frame[2], frame[4], &frame[5..frame_len - 1]))
+}
+
+/// Half-duplex UART transport for the 0xFF 0xFF register-based protocols (Feetech SCS/STS, Dynamixel 1.0).
+///
+/// TX and RX are tied to the single data line, so every instruction we send comes back as an echo that is discarded
+/// before the servo's status packet is read.
+pub struct PacketBus<'a> {
+    uart: UartDriver<'a>,
+}
+
+impl<'a> PacketBus<'a> {
+    /// Create the transport on the given UART and pins (no hardware flow control).
+    pub fn new<UART, U, TX, P1, RX, P2>(
+        uart: UART,
+        tx_pin: TX,
+        rx_pin: RX,
+        config: &Config,
+    ) -> Result<Self, Error>
+    where
+        UART: Peripheral<P = U> + 'a,
+        U: Uart,
+        TX: Peripheral<P = P1> + 'a,
+        P1: OutputPin,
+        RX: Peripheral<P = P2> + 'a,
+        P2: InputPin,
+    {
+        let driver = UartDriver::new(
+            uart,
+            tx_pin,
+            rx_pin,
+            Option::<AnyIOPin>::None,  // CTS pin not used
+            Option::<AnyIOPin>::None,  // RTS pin not used
+            config,
+        )?;
+        Ok(PacketBus { uart: driver })
+    }
+
+    /// Ping a servo; succeeds if it answers with an error-free status packet.
+    pub fn ping(&mut self, id: u8) -> Result<(), Error> {
+        self.transact(id, INST_PING, &[], true).map(|_| ())
+    }
+
+    /// Read `len` bytes of the register table starting at `address`.
+    pub fn read(&mut self, id: u8, address: u8, len: u8) -> Result<Vec<u8>, Error> {
+        if id == BROADCAST_ID {
+            return Err(FrameError::BroadcastRead { address }.into());
+        }
+        let data = self.transact(id, INST_READ, &[address, len], true)?;
+        if data.len() != len as usize {
+            anyhow::bail!("Expected {} register bytes from servo {}, got {}", len, id, data.len());
+        }
+        Ok(data)
+    }
+
+    /// Write `data` to the register table starting at `address`.
+    ///
+    /// Unicast writes wait for the status packet so the next instruction cannot collide with it; broadcasts return immediately.
+    pub fn write(&mut self, id: u8, address: u8, data: &[u8]) -> Result<(), Error> {
+        let mut params = Vec::with_capacity(data.len() + 1);
+        params.push(address);
+        params.extend_from_slice(data);
+        self.transact(id, INST_WRITE, &params, id != BROADCAST_ID).map(|_| ())
+    }
+
+    /// Send an instruction packet and, if `want_reply` is set, return the parameters of the validated status packet.
+    fn transact(&mut self, id: u8, instruction: u8, params: &[u8], want_reply: bool) -> Result<Vec<u8>, Error> {
+        /* ---------- 1 · Send packet ---------- */
+        let tx = encode(id, instruction, params);
+        if tx.len() > MAX_FRAME {
+            anyhow::bail!("Packet size {} exceeds buffer size {}", tx.len(), MAX_FRAME);
+        }
+        self.uart.clear_rx()?;
+        self.uart.write(&tx)?;
+
+        if !want_reply {
+            return Ok(Vec::new());
+        }
+
+        /* ---------- 2 · Discard our own echo ---------- */
+        let mut echo_buf = [0u8; MAX_FRAME];
+        let mut echo_bytes_read = 0;
+        while echo_bytes_read < tx.len() {
+            match self.uart.read(&mut echo_buf[echo_bytes_read..tx.len()], 5) {
+                Ok(n) if n > 0 => echo_bytes_read += n,
+                _ => break,
+            }
+        }
+
+        /* ---------- 3 · Read status packet ---------- */
+        let mut rx = [0u8; MAX_FRAME];
+        let mut bytes_read = 0;
+        let timeout_ms = 100;
+        let start_time = unsafe { esp_timer_get_time() } / 1000;
+
+        // Header first: 0xFF 0xFF ID LEN
+        while bytes_read < 4 {
+            if (unsafe { esp_timer_get_time() } / 1000) - start_time > timeout_ms {
+                anyhow::bail!("Timeout waiting for status packet header from servo {}", id);
+            }
+            match self.uart.read(&mut rx[bytes_read..4], 10) {
+                Ok(n) if n > 0 => {
+                    bytes_read += n;
+                    // Resynchronise on the 0xFF 0xFF header, dropping line noise in front of it
+                    while (bytes_read >= 1 && rx[0] != 0xFF) || (bytes_read >= 2 && rx[1] != 0xFF) {
+                        rx.copy_within(1..bytes_read, 0);
+                        bytes_read -= 1;
+                    }
+                }
+                Ok(_) => continue,
+                Err(e) => return Err(anyhow::anyhow!("UART read error: {}", e)),
+            }
+        }
+
+        let frame_len = 4 + rx[3] as usize;
+        if frame_len > MAX_FRAME {
+            anyhow::bail!("Status packet size {} exceeds buffer size {}", frame_len, MAX_FRAME);
+        }
+        while bytes_read < frame_len {
+            if (unsafe { esp_timer_get_time() } / 1000) - start_time > timeout_ms {
+                anyhow::bail!("Timeout reading status packet: got {} of {} bytes", bytes_read, frame_len);
+            }
+            match self.uart.read(&mut rx[bytes_read..frame_len], 5) {
+                Ok(n) if n > 0 => bytes_read += n,
+                Ok(_) => continue,
+                Err(e) => return Err(anyhow::anyhow!("UART read error: {}", e)),
+            }
+        }
+
+        /* ---------- 4 · Validate ---------- */
+        let (reply_id, error, data) = decode_status(&rx[..frame_len])?;
+        if reply_id != id {
+            return Err(FrameError::WrongId { expected: id, received: reply_id }.into());
+        }
+        if error != 0 {
+            return Err(FrameError::Status { id, error }.into());
+        }
+        Ok(data.to_vec())
+    }
+}
@@ -0,0 +1,225 @@
+#![allow(dead_code)]
+use std::collections::BTreeMap;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, Instant};
+use log::*;
+
+use crate::servo_bus::ServoBusProtocol;
+use crate::telemetry::cache::TelemetryCache;
+use crate::telemetry::{Signal, Value};
+
+/// Tuning of the [`StallSupervisor`].
+#[derive(Debug, Clone, Copy)]
+pub struct StallConfig {
+    /// Time between two position checks.
+    pub check_interval: Duration,
+    /// Oldest cached position still checked; servos with older readings are skipped.
+    pub max_age: Duration,
+    /// Tracking error (raw position units) above which a servo is suspected of being stalled.
+    pub threshold: u16,
+    /// How long the error must stay above `threshold` before a fault is raised.
+    pub duration: Duration,
+    /// Unload the servo's torque when it faults, so it stops pushing against the obstruction.
+    pub unload_on_fault: bool,
+}
+
+impl Default for StallConfig {
+    /// 30 units ≈ 7° on an LX-16A; servo lag during a normal move stays well below that at 25 Hz setpoints.
+    fn default() -> Self {
+        StallConfig {
+            check_interval: Duration::from_millis(100),
+            max_age: Duration::from_millis(500),
+            threshold: 30,
+            duration: Duration::from_millis(500),
+            unload_on_fault: true,
+        }
+    }
+}
+
+/// A servo that stopped following its commanded motion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StallEvent {
+    pub id: u8,
+    /// Position the servo should have reached, and the position it reported.
+    pub expected: u16,
+    pub measured: u16,
+    /// Time the tracking error first exceeded the threshold.
+    pub since: Instant,
+    pub at: Instant,
+    /// The servo's torque was unloaded in response.
+    pub unloaded: bool,
+}
+
+/// Linear motion segment the servo is expected to follow, as commanded with `MOVE_TIME_WRITE`.
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    from: f32,
+    to: f32,
+    start: Instant,
+    duration: Duration,
+}
+
+impl Segment {
+    fn position_at(&self, now: Instant) -> f32 {
+        let elapsed = now.duration_since(self.start);
+        if elapsed >= self.duration {
+            return self.to;
+        }
+        self.from + (self.to - self.from) * (elapsed.as_secs_f32() / self.duration.as_secs_f32())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Tracking {
+    segment: Segment,
+    /// Time the error went above the threshold, while it stays there.
+    over_since: Option<Instant>,
+    faulted: bool,
+}
+
+/// Compares the position each servo should be at with the position in the [`TelemetryCache`], and raises a [`StallEvent`] when the
+/// error stays above a threshold for too long: the servo is stalled, blocked by an obstacle or has slipped its gears.
+///
+/// Motion code reports every command with [`expect`](Self::expect); call [`check`](Self::check) from the main loop.
+/// A faulted servo is not checked again until [`clear`](Self::clear) is called.
+pub struct StallSupervisor {
+    config: StallConfig,
+    servos: BTreeMap<u8, Tracking>,
+    subscribers: Vec<Sender<StallEvent>>,
+    last_check: Option<Instant>,
+}
+
+impl StallSupervisor {
+    pub fn new(config: StallConfig) -> Self {
+        StallSupervisor {
+            config,
+            servos: BTreeMap::new(),
+            subscribers: Vec::new(),
+            last_check: None,
+        }
+    }
+
+    /// Record that servo `id` was commanded to `position` over `time_ms`. The expected motion starts from where the
+    /// previous command should have brought it, or at `position` for the first command.
+    pub fn expect(&mut self, id: u8, position: u16, time_ms: u16) {
+        self.expect_at(id, position, time_ms, Instant::now());
+    }
+
+    /// [`expect`](Self::expect) a command sent at `now`.
+    pub(crate) fn expect_at(&mut self, id: u8, position: u16, time_ms: u16, now: Instant) {
+        let from = self
+            .servos
+            .get(&id)
+            .map_or(position as f32, |tracking| tracking.segment.position_at(now));
+        let segment = Segment {
+            from,
+            to: position as f32,
+            start: now,
+            duration: Duration::from_millis(time_ms as u64),
+        };
+        let tracking = self.servos.entry(id).or_insert(Tracking {
+            segment,
+            over_since: None,
+            faulted: false,
+        });
+        tracking.segment = segment;
+    }
+
+    /// Stop supervising servo `id`, e.g. when it is unloaded or switched to motor mode.
+    pub fn forget(&mut self, id: u8) {
+        self.servos.remove(&id);
+    }
+
+    /// Re-arm a faulted servo once the obstruction has been removed. Supervision restarts with the next
+    /// [`expect`](Self::expect), from the new setpoint rather than where the servo was expected before the fault.
+    pub fn clear(&mut self, id: u8) {
+        self.servos.remove(&id);
+    }
+
+    /// True if servo `id` has raised a fault that has not been cleared.
+    pub fn is_faulted(&self, id: u8) -> bool {
+        self.servos.get(&id).is_some_and(|tracking| tracking.faulted)
+    }
+
+    /// Receive every fault on a channel. Dropped receivers are pruned automatically.
+    pub fn subscribe(&mut self) -> Receiver<StallEvent> {
+        let (tx, rx) = channel();
+        self.subscribers.push(tx);
+        rx
+    }
+
+    /// Check the cached position of every supervised servo if `check_interval` has elapsed. Returns the faults of this
+    /// round. Servos without a recent position are skipped; missing replies are the
+    /// [`HealthMonitor`](crate::health::HealthMonitor)'s job. The bus is only used to unload stalled servos.
+    pub fn check<B: ServoBusProtocol>(&mut self, bus: &mut B, cache: &TelemetryCache) -> Vec<StallEvent> {
+        let now = Instant::now();
+        if let Some(last) = self.last_check {
+            if now.duration_since(last) < self.config.check_interval {
+                return Vec::new();
+            }
+        }
+        self.last_check = Some(now);
+
+        let ids: Vec<u8> = self.servos.iter().filter(|(_, t)| !t.faulted).map(|(id, _)| *id).collect();
+        let mut events = Vec::new();
+        for id in ids {
+            let (measured, at) = match cache.get(id, Signal::Position) {
+                Some(entry) if entry.age() <= self.config.max_age => match entry.value {
+                    Value::Position(position) => (position, entry.at),
+                    _ => continue,
+                },
+                _ => {
+                    debug!("Stall check: no recent position of servo {}", id);
+                    continue;
+                }
+            };
+            // Compare with where the servo should have been when the position was read
+            if let Some(event) = self.record(id, measured, at) {
+                events.push(self.fault(bus, event));
+            }
+        }
+        events
+    }
+
+    /// Feed one position measurement, read at `at`, into the detector. Returns the fault if this measurement raised one.
+    pub(crate) fn record(&mut self, id: u8, measured: u16, at: Instant) -> Option<StallEvent> {
+        let config = self.config;
+        let tracking = self.servos.get_mut(&id)?;
+        let expected = tracking.segment.position_at(at).round() as u16;
+        if expected.abs_diff(measured) <= config.threshold {
+            tracking.over_since = None;
+            return None;
+        }
+        let since = *tracking.over_since.get_or_insert(at);
+        if at.duration_since(since) < config.duration {
+            return None;
+        }
+        tracking.faulted = true;
+        Some(StallEvent {
+            id,
+            expected,
+            measured,
+            since,
+            at,
+            unloaded: false,
+        })
+    }
+
+    fn fault<B: ServoBusProtocol>(&mut self, bus: &mut B, mut event: StallEvent) -> StallEvent {
+        error!(
+            "Servo {} stalled: expected {} but at {} for {} ms",
+            event.id,
+            event.expected,
+            event.measured,
+            event.at.duration_since(event.since).as_millis()
+        );
+        if self.config.unload_on_fault {
+            match bus.set_torque(event.id, false) {
+                Ok(()) => event.unloaded = true,
+                Err(e) => error!("Failed to unload stalled servo {}: {:?}", event.id, e),
+            }
+        }
+        self.subscribers.retain(|tx| tx.send(event).is_ok());
+        event
+    }
+}
@@ -0,0 +1,207 @@
+#![allow(dead_code)]
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use anyhow::Error;
+use log::*;
+
+use crate::sequence::player::SequencePlayer;
+use crate::sequence::{Easing, Keyframe, Pose, Sequence, SEQUENCE_FORMAT_VERSION};
+use crate::servo_bus::ServoBusProtocol;
+use crate::trajectory::rate_period;
+
+/// Time given to reach the first recorded pose on replay, since the servos are wherever the operator left them.
+const APPROACH_MS: u32 = 1000;
+
+/// One sample of a teach-mode recording.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TeachSample {
+    /// Milliseconds since the start of the recording.
+    pub t_ms: u32,
+    pub positions: Pose,
+}
+
+/// Positions sampled while an operator moved the unloaded servos by hand.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Recording {
+    pub rate_hz: f32,
+    pub joints: Vec<u8>,
+    pub samples: Vec<TeachSample>,
+}
+
+impl Recording {
+    /// Duration of the recording in milliseconds.
+    pub fn duration_ms(&self) -> u32 {
+        self.samples.last().map_or(0, |sample| sample.t_ms)
+    }
+
+    /// Convert the recording into a playable [`Sequence`] named `name`.
+    ///
+    /// With `tolerance` set, samples are reduced to the keyframes needed to stay within `tolerance` position units of the
+    /// recorded path on every joint (Ramer-Douglas-Peucker); otherwise every sample becomes a linear keyframe.
+    /// The first keyframe takes [`APPROACH_MS`] to move from wherever the servos are to the start of the recording.
+    pub fn to_sequence(&self, name: &str, tolerance: Option<f32>) -> Sequence {
+        let indices = match tolerance {
+            Some(tolerance) => self.simplify(tolerance),
+            None => (0..self.samples.len()).collect(),
+        };
+
+        let mut poses = BTreeMap::new();
+        let mut keyframes = Vec::with_capacity(indices.len());
+        let mut previous_t = 0;
+        for (n, index) in indices.iter().enumerate() {
+            let sample = &self.samples[*index];
+            let pose_name = format!("p{}", n);
+            poses.insert(pose_name.clone(), sample.positions.clone());
+            keyframes.push(Keyframe {
+                pose: pose_name,
+                duration_ms: if n == 0 { APPROACH_MS } else { sample.t_ms - previous_t },
+                easing: Easing::Linear,
+                hold_ms: 0,
+            });
+            previous_t = sample.t_ms;
+        }
+
+        Sequence {
+            version: SEQUENCE_FORMAT_VERSION,
+            name: name.to_string(),
+            poses,
+            keyframes,
+            loops: 1,
+        }
+    }
+
+    /// Indices of the samples kept by a Ramer-Douglas-Peucker simplification with the given tolerance.
+    /// The first and last samples are always kept.
+    pub fn simplify(&self, tolerance: f32) -> Vec<usize> {
+        let len = self.samples.len();
+        if len <= 2 {
+            return (0..len).collect();
+        }
+        let mut keep = vec![false; len];
+        keep[0] = true;
+        keep[len - 1] = true;
+        let mut stack = vec![(0, len - 1)];
+        while let Some((first, last)) = stack.pop() {
+            let mut worst = (0, 0.0f32);
+            for index in first + 1..last {
+                let deviation = self.deviation(first, last, index);
+                if deviation > worst.1 {
+                    worst = (index, deviation);
+                }
+            }
+            if worst.1 > tolerance {
+                keep[worst.0] = true;
+                stack.push((first, worst.0));
+                stack.push((worst.0, last));
+            }
+        }
+        (0..len).filter(|index| keep[*index]).collect()
+    }
+
+    /// Largest distance on any joint between sample `index` and the straight line (in time) from `first` to `last`.
+    fn deviation(&self, first: usize, last: usize, index: usize) -> f32 {
+        let (a, b, p) = (&self.samples[first], &self.samples[last], &self.samples[index]);
+        let span = (b.t_ms - a.t_ms).max(1) as f32;
+        let progress = (p.t_ms - a.t_ms) as f32 / span;
+        p.positions
+            .iter()
+            .filter_map(|(id, position)| {
+                let start = *a.positions.get(id)? as f32;
+                let end = *b.positions.get(id)? as f32;
+                Some((start + (end - start) * progress - *position as f32).abs())
+            })
+            .fold(0.0f32, f32::max)
+    }
+}
+
+/// Teach mode: unloads the selected servos so they can be moved by hand and samples their positions at a fixed rate.
+///
+/// LX-16A servos keep reporting their position with torque off, so the operator's demonstration is captured directly.
+/// Call [`tick`](Self::tick) from the main loop, then [`finish`](Self::finish) to get the [`Recording`].
+pub struct TeachRecorder {
+    period: Duration,
+    recording: Recording,
+    started: Instant,
+    last_sample: Option<Instant>,
+    /// Last good reading per joint, reused when a read fails.
+    last_positions: Pose,
+}
+
+impl TeachRecorder {
+    /// Unload torque on `joints` and start recording at `rate_hz`.
+    pub fn start<B: ServoBusProtocol>(bus: &mut B, joints: &[u8], rate_hz: f32) -> Result<Self, Error> {
+        let period = rate_period(rate_hz)?;
+        for id in joints {
+            bus.set_torque(*id, false)?;
+        }
+        info!("Teach mode: servos {:?} unloaded, recording at {} Hz", joints, rate_hz);
+        Ok(TeachRecorder {
+            period,
+            recording: Recording {
+                rate_hz,
+                joints: joints.to_vec(),
+                samples: Vec::new(),
+            },
+            started: Instant::now(),
+            last_sample: None,
+            last_positions: Pose::new(),
+        })
+    }
+
+    /// Take a sample if one is due. Joints that fail to answer keep their previous reading for this sample.
+    pub fn tick<B: ServoBusProtocol>(&mut self, bus: &mut B) {
+        let now = Instant::now();
+        if let Some(last) = self.last_sample {
+            if now.duration_since(last) < self.period {
+                return;
+            }
+        }
+        self.last_sample = Some(now);
+
+        for id in &self.recording.joints {
+            match bus.read_position(*id) {
+                Ok(position) => {
+                    self.last_positions.insert(*id, position);
+                }
+                Err(e) => debug!("Teach mode: servo {} read failed: {:?}", id, e),
+            }
+        }
+        // Nothing to record until every joint has answered at least once
+        if self.last_positions.len() == self.recording.joints.len() {
+            self.recording.samples.push(TeachSample {
+                t_ms: now.duration_since(self.started).as_millis() as u32,
+                positions: self.last_positions.clone(),
+            });
+        }
+    }
+
+    /// Number of samples recorded so far.
+    pub fn sample_count(&self) -> usize {
+        self.recording.samples.len()
+    }
+
+    /// Stop recording. The servos stay unloaded until the recording is replayed.
+    pub fn finish(self) -> Recording {
+        info!("Teach mode: recorded {} samples over {} ms", self.recording.samples.len(), self.recording.duration_ms());
+        self.recording
+    }
+}
+
+/// Re-enable torque on the recorded joints and play the recording back through `player`.
+///
+/// `tolerance` is passed to [`Recording::to_sequence`]; `Some(2.0)` typically removes most samples of a smooth motion.
+pub fn replay<B: ServoBusProtocol>(
+    bus: &mut B,
+    player: &mut SequencePlayer,
+    recording: &Recording,
+    tolerance: Option<f32>,
+) -> Result<(), Error> {
+    if recording.samples.is_empty() {
+        anyhow::bail!("Nothing to replay: the recording is empty");
+    }
+    for id in &recording.joints {
+        bus.set_torque(*id, true)?;
+    }
+    player.play(recording.to_sequence("teach", tolerance), bus)
+}
@@ -0,0 +1,159 @@
+#![allow(dead_code)]
+use std::collections::BTreeMap;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{Sample, Signal, Value};
+
+/// Where a cached value came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// The [`TelemetryScheduler`](super::TelemetryScheduler) polling the bus.
+    Telemetry,
+    /// Another part of the firmware that read the bus itself, e.g. a supervisor.
+    Firmware,
+    /// A value reported over the network.
+    Network,
+}
+
+/// Latest value of one signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    pub value: Value,
+    pub at: Instant,
+    pub source: Source,
+}
+
+impl Entry {
+    /// Time since the value was read.
+    pub fn age(&self) -> Duration {
+        self.at.elapsed()
+    }
+}
+
+/// A cached value that changed, sent to [`TelemetryCache::subscribe`] receivers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Change {
+    pub id: u8,
+    /// Previous value, or `None` for the first value of the signal.
+    pub previous: Option<Value>,
+    pub entry: Entry,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: BTreeMap<(u8, Signal), Entry>,
+    subscribers: Vec<Sender<Change>>,
+}
+
+/// Latest value of every polled servo signal, shared between the poller and its consumers.
+///
+/// Cloning gives another handle to the same store, so the web UI, MQTT or the supervisors read servo state from here
+/// instead of each hitting the bus. The main loop feeds it with the [`TelemetryScheduler`](super::TelemetryScheduler)'s
+/// samples through [`record_all`](Self::record_all).
+#[derive(Debug, Clone, Default)]
+pub struct TelemetryCache {
+    state: Arc<Mutex<CacheState>>,
+}
+
+impl TelemetryCache {
+    pub fn new() -> Self {
+        TelemetryCache::default()
+    }
+
+    /// Store a sample. Subscribers are notified if the value differs from the cached one.
+    pub fn record(&self, sample: Sample, source: Source) {
+        let entry = Entry {
+            value: sample.value,
+            at: sample.at,
+            source,
+        };
+        let mut state = self.lock();
+        let previous = state
+            .entries
+            .insert((sample.id, sample.value.signal()), entry)
+            .map(|previous| previous.value);
+        if previous != Some(entry.value) {
+            let change = Change {
+                id: sample.id,
+                previous,
+                entry,
+            };
+            state.subscribers.retain(|tx| tx.send(change).is_ok());
+        }
+    }
+
+    /// Store the samples of one [`TelemetryScheduler::poll`](super::TelemetryScheduler::poll).
+    pub fn record_all(&self, samples: &[Sample]) {
+        for sample in samples {
+            self.record(*sample, Source::Telemetry);
+        }
+    }
+
+    /// Latest entry of `signal` on servo `id`.
+    pub fn get(&self, id: u8, signal: Signal) -> Option<Entry> {
+        self.lock().entries.get(&(id, signal)).copied()
+    }
+
+    /// Latest value of `signal` on servo `id`, however old.
+    pub fn value(&self, id: u8, signal: Signal) -> Option<Value> {
+        self.get(id, signal).map(|entry| entry.value)
+    }
+
+    /// Latest value of `signal` on servo `id` if it was read within `max_age`.
+    pub fn fresh(&self, id: u8, signal: Signal, max_age: Duration) -> Option<Value> {
+        self.get(id, signal)
+            .filter(|entry| entry.age() <= max_age)
+            .map(|entry| entry.value)
+    }
+
+    /// True if `signal` on servo `id` has not been read within `max_age`, or never.
+    pub fn is_stale(&self, id: u8, signal: Signal, max_age: Duration) -> bool {
+        self.fresh(id, signal, max_age).is_none()
+    }
+
+    /// Cached signals older than `max_age`.
+    pub fn stale(&self, max_age: Duration) -> Vec<(u8, Signal)> {
+        self.lock()
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.age() > max_age)
+            .map(|(key, _)| *key)
+            .collect()
+    }
+
+    /// Every cached entry of servo `id`.
+    pub fn servo(&self, id: u8) -> Vec<(Signal, Entry)> {
+        self.lock()
+            .entries
+            .range((id, Signal::Position)..=(id, Signal::LoadState))
+            .map(|((_, signal), entry)| (*signal, *entry))
+            .collect()
+    }
+
+    /// Every cached entry, ordered by servo ID then signal.
+    pub fn snapshot(&self) -> Vec<(u8, Signal, Entry)> {
+        self.lock()
+            .entries
+            .iter()
+            .map(|((id, signal), entry)| (*id, *signal, *entry))
+            .collect()
+    }
+
+    /// Drop every cached value of servo `id`, e.g. after it was removed from the bus.
+    pub fn forget(&self, id: u8) {
+        self.lock().entries.retain(|(entry_id, _), _| *entry_id != id);
+    }
+
+    /// Receive every change of a cached value.
+    pub fn subscribe(&self) -> Receiver<Change> {
+        let (tx, rx) = channel();
+        self.lock().subscribers.push(tx);
+        rx
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CacheState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
@@ -0,0 +1,246 @@
+#![allow(dead_code)]
+use std::time::{Duration, Instant};
+use anyhow::Error;
+use log::*;
+
+use crate::lewan_bus::LewanSoulBus;
+use crate::trajectory::rate_period;
+
+pub mod cache;
+
+/// A servo signal the scheduler can poll.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Signal {
+    Position,
+    Temperature,
+    Vin,
+    LoadState,
+}
+
+/// A polled value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+    /// Raw position units.
+    Position(u16),
+    /// °C.
+    Temperature(u8),
+    /// Millivolts.
+    Vin(u16),
+    /// Torque enabled.
+    LoadState(bool),
+}
+
+/// One value read from the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    pub id: u8,
+    pub value: Value,
+    pub at: Instant,
+}
+
+/// A read that failed, from [`TelemetryScheduler::take_failures`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadFailure {
+    pub id: u8,
+    pub signal: Signal,
+    pub at: Instant,
+}
+
+impl Value {
+    pub fn signal(&self) -> Signal {
+        match self {
+            Value::Position(_) => Signal::Position,
+            Value::Temperature(_) => Signal::Temperature,
+            Value::Vin(_) => Signal::Vin,
+            Value::LoadState(_) => Signal::LoadState,
+        }
+    }
+}
+
+/// Bus time available to telemetry.
+#[derive(Debug, Clone, Copy)]
+pub struct TelemetryConfig {
+    /// Bus time of one read transaction: request, echo, reply and turnaround.
+    /// About 14 bytes at 115200 baud plus the servo's response delay.
+    pub transaction_cost: Duration,
+    /// Share of the bus time telemetry may use (0-1). The rest is kept free for motion commands.
+    pub bus_share: f32,
+    /// Most bus time telemetry may catch up with after an idle spell.
+    pub max_burst: Duration,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        TelemetryConfig {
+            transaction_cost: Duration::from_micros(2500),
+            bus_share: 0.3,
+            max_burst: Duration::from_millis(10),
+        }
+    }
+}
+
+/// Bus load of the requested polling rates, from [`TelemetryScheduler::load`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BusLoad {
+    /// Bus time share the requested rates need.
+    pub requested: f32,
+    /// Bus time share telemetry may use.
+    pub available: f32,
+}
+
+impl BusLoad {
+    /// True if the requested rates fit in the available share.
+    pub fn fits(&self) -> bool {
+        self.requested <= self.available
+    }
+}
+
+impl core::fmt::Display for BusLoad {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "telemetry needs {:.0}% of the bus but may use {:.0}%",
+            self.requested * 100.0,
+            self.available * 100.0
+        )
+    }
+}
+
+impl std::error::Error for BusLoad {}
+
+#[derive(Debug, Clone, Copy)]
+struct Task {
+    id: u8,
+    signal: Signal,
+    period: Duration,
+    next_due: Instant,
+    /// Polls that ran later than one full period after they were due.
+    late: u32,
+}
+
+/// Polls servo signals at independent per-signal rates within a share of the bus time.
+///
+/// Bus time is metered with a token bucket: credit accrues at `bus_share` of real time and every read spends
+/// `transaction_cost`, so telemetry never takes more than its share and motion commands always get through.
+/// Due reads run earliest deadline first. If the requested rates do not fit, every signal is polled less often than
+/// asked and [`load`](Self::load) reports the shortfall.
+pub struct TelemetryScheduler {
+    config: TelemetryConfig,
+    tasks: Vec<Task>,
+    credit: Duration,
+    last_poll: Option<Instant>,
+    /// Reads failed since the last [`take_failures`](Self::take_failures).
+    failures: Vec<ReadFailure>,
+}
+
+impl TelemetryScheduler {
+    pub fn new(config: TelemetryConfig) -> Self {
+        TelemetryScheduler {
+            config,
+            tasks: Vec::new(),
+            credit: Duration::ZERO,
+            last_poll: None,
+            failures: Vec::new(),
+        }
+    }
+
+    /// Poll `signal` of servo `id` at `rate_hz`, replacing any previous rate. A rate of 0 stops polling it.
+    /// Returns [`BusLoad`] as an error if the new set of rates no longer fits the bus share; polling continues regardless.
+    /// A negative or non-finite rate is refused and leaves the previous rate in place.
+    pub fn set_rate(&mut self, id: u8, signal: Signal, rate_hz: f32) -> Result<(), Error> {
+        let period = if rate_hz == 0.0 { None } else { Some(rate_period(rate_hz)?) };
+        self.tasks.retain(|task| !(task.id == id && task.signal == signal));
+        if let Some(period) = period {
+            self.tasks.push(Task {
+                id,
+                signal,
+                period,
+                next_due: Instant::now(),
+                late: 0,
+            });
+        }
+        let load = self.load();
+        if !load.fits() {
+            return Err(load.into());
+        }
+        Ok(())
+    }
+
+    /// Stop polling every signal of servo `id`.
+    pub fn remove(&mut self, id: u8) {
+        self.tasks.retain(|task| task.id != id);
+    }
+
+    /// Bus share needed by the requested rates compared with the share available.
+    pub fn load(&self) -> BusLoad {
+        let reads_per_second: f32 = self.tasks.iter().map(|task| 1.0 / task.period.as_secs_f32()).sum();
+        BusLoad {
+            requested: reads_per_second * self.config.transaction_cost.as_secs_f32(),
+            available: self.config.bus_share,
+        }
+    }
+
+    /// Number of polls of `signal` on servo `id` that ran more than a period late (a sign of an overloaded bus).
+    pub fn late_count(&self, id: u8, signal: Signal) -> u32 {
+        self.tasks
+            .iter()
+            .find(|task| task.id == id && task.signal == signal)
+            .map_or(0, |task| task.late)
+    }
+
+    /// Run the reads that are due and fit in the accumulated bus credit. Call it on every main loop iteration.
+    /// Reads that fail are logged and retried at their next period.
+    pub fn poll(&mut self, bus: &mut LewanSoulBus) -> Vec<Sample> {
+        let now = Instant::now();
+        let elapsed = self.last_poll.map_or(Duration::ZERO, |last| now.duration_since(last));
+        self.last_poll = Some(now);
+        self.credit = (self.credit + elapsed.mul_f32(self.config.bus_share)).min(self.config.max_burst);
+
+        let mut samples = Vec::new();
+        while self.credit >= self.config.transaction_cost {
+            // Earliest deadline first
+            let task = match self
+                .tasks
+                .iter_mut()
+                .filter(|task| task.next_due <= now)
+                .min_by_key(|task| task.next_due)
+            {
+                Some(task) => task,
+                None => break,
+            };
+            self.credit -= self.config.transaction_cost;
+            if now.duration_since(task.next_due) > task.period {
+                task.late += 1;
+            }
+            // Schedule from the deadline, not from now, so the average rate holds; skip periods we fell too far behind on
+            task.next_due += task.period;
+            if task.next_due < now {
+                task.next_due = now + task.period;
+            }
+            let (id, signal) = (task.id, task.signal);
+            match Self::read(bus, id, signal) {
+                Ok(value) => samples.push(Sample { id, value, at: Instant::now() }),
+                Err(e) => {
+                    debug!("Telemetry: servo {} {:?} read failed: {:?}", id, signal, e);
+                    self.failures.push(ReadFailure { id, signal, at: Instant::now() });
+                }
+            }
+        }
+        samples
+    }
+
+    /// The reads that failed since the last call, e.g. to feed them to the
+    /// [`HealthMonitor`](crate::health::HealthMonitor) like the samples that succeeded.
+    pub fn take_failures(&mut self) -> Vec<ReadFailure> {
+        core::mem::take(&mut self.failures)
+    }
+
+    fn read(bus: &mut LewanSoulBus, id: u8, signal: Signal) -> Result<Value, Error> {
+        Ok(match signal {
+            Signal::Position => Value::Position(bus.read_position(id)?),
+            Signal::Temperature => Value::Temperature(bus.read_temperature(id)?),
+            Signal::Vin => Value::Vin(bus.read_vin(id)?),
+            Signal::LoadState => Value::LoadState(bus.read_load_state(id)?),
+        })
+    }
+}
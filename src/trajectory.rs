@@ -0,0 +1,417 @@
+#![allow(dead_code)]
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use anyhow::Error;
+
+use crate::servo_bus::ServoBusProtocol;
+
+/// Velocity and acceleration limits of one joint, in raw position units per second (and per second²).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MotionLimits {
+    pub max_velocity: f32,
+    pub max_acceleration: f32,
+}
+
+impl MotionLimits {
+    /// Fail with [`InvalidMotionLimits`] unless both limits are positive and finite.
+    pub fn validate(&self) -> Result<(), Error> {
+        let valid = |limit: f32| limit.is_finite() && limit > 0.0;
+        if !(valid(self.max_velocity) && valid(self.max_acceleration)) {
+            return Err(InvalidMotionLimits { limits: *self }.into());
+        }
+        Ok(())
+    }
+}
+
+impl Default for MotionLimits {
+    /// Roughly half the no-load speed of an LX-16A (0.16 s/60° ≈ 1560 units/s) with a gentle ramp.
+    fn default() -> Self {
+        MotionLimits {
+            max_velocity: 800.0,
+            max_acceleration: 2000.0,
+        }
+    }
+}
+
+/// A [`MotionLimits`] with a zero, negative or non-finite limit, which would give an endless or undefined motion.
+///
+/// Returned wrapped in an [`anyhow::Error`] when a [`Profile`] or [`Trajectory`] is planned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InvalidMotionLimits {
+    pub limits: MotionLimits,
+}
+
+impl core::fmt::Display for InvalidMotionLimits {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "invalid motion limits: velocity {} and acceleration {} must be positive numbers",
+            self.limits.max_velocity, self.limits.max_acceleration
+        )
+    }
+}
+
+impl std::error::Error for InvalidMotionLimits {}
+
+/// Shape of the motion between two positions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileKind {
+    /// Constant acceleration, cruise at the velocity limit, constant deceleration (triangular for short moves).
+    Trapezoidal,
+    /// Cubic polynomial with zero start and end velocity.
+    Cubic,
+    /// Quintic polynomial with zero start and end velocity and acceleration (smoothest, no jerk spikes).
+    Quintic,
+}
+
+/// A planned single-joint motion from `start` to `goal`, sampled by time in seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Profile {
+    kind: ProfileKind,
+    start: f32,
+    goal: f32,
+    duration: f32,
+    /// Trapezoidal only: acceleration phase length and cruise velocity (both unsigned).
+    accel_time: f32,
+    cruise_velocity: f32,
+}
+
+impl Profile {
+    /// Plan the fastest motion of the given shape that respects `limits`.
+    /// Fails with [`InvalidMotionLimits`] if a limit is not a positive number.
+    pub fn plan(kind: ProfileKind, start: f32, goal: f32, limits: &MotionLimits) -> Result<Self, Error> {
+        limits.validate()?;
+        let duration = Self::min_duration(kind, (goal - start).abs(), limits);
+        Self::with_duration(kind, start, goal, duration, limits)
+    }
+
+    /// Shortest duration in seconds of a motion of `distance` units that respects `limits`.
+    pub fn min_duration(kind: ProfileKind, distance: f32, limits: &MotionLimits) -> f32 {
+        let (v, a) = (limits.max_velocity, limits.max_acceleration);
+        match kind {
+            ProfileKind::Trapezoidal => {
+                if distance * a <= v * v {
+                    // Triangular: never reaches the velocity limit
+                    2.0 * (distance / a).sqrt()
+                } else {
+                    distance / v + v / a
+                }
+            }
+            // Peak velocity 1.5·d/T, peak acceleration 6·d/T²
+            ProfileKind::Cubic => (1.5 * distance / v).max((6.0 * distance / a).sqrt()),
+            // Peak velocity 1.875·d/T, peak acceleration 5.7735·d/T²
+            ProfileKind::Quintic => (1.875 * distance / v).max((5.7735 * distance / a).sqrt()),
+        }
+    }
+
+    /// Plan a motion of the given shape lasting `duration` seconds, or longer if the limits do not allow it.
+    /// Fails with [`InvalidMotionLimits`] if a limit is not a positive number, and if a position is not finite.
+    pub fn with_duration(
+        kind: ProfileKind,
+        start: f32,
+        goal: f32,
+        duration: f32,
+        limits: &MotionLimits,
+    ) -> Result<Self, Error> {
+        limits.validate()?;
+        if !(start.is_finite() && goal.is_finite()) {
+            anyhow::bail!("Invalid motion from {} to {}: positions must be finite", start, goal);
+        }
+        let distance = (goal - start).abs();
+        let duration = duration.max(Self::min_duration(kind, distance, limits));
+        let mut profile = Profile {
+            kind,
+            start,
+            goal,
+            duration,
+            accel_time: 0.0,
+            cruise_velocity: 0.0,
+        };
+        if kind == ProfileKind::Trapezoidal && distance > 0.0 {
+            let a = limits.max_acceleration;
+            // Cruise velocity v solves d = v·(T - v/a): v = (a·T - sqrt(a²T² - 4·a·d)) / 2
+            let discriminant = (a * duration * a * duration - 4.0 * a * distance).max(0.0);
+            let v = (a * duration - discriminant.sqrt()) / 2.0;
+            profile.cruise_velocity = v;
+            profile.accel_time = v / a;
+        }
+        Ok(profile)
+    }
+
+    /// Total duration in seconds.
+    pub fn duration(&self) -> f32 {
+        self.duration
+    }
+
+    /// Target position.
+    pub fn goal(&self) -> f32 {
+        self.goal
+    }
+
+    /// Position at time `t` seconds after the start (clamped to the ends of the motion).
+    pub fn position(&self, t: f32) -> f32 {
+        self.sample(t).0
+    }
+
+    /// Position and velocity at time `t` seconds after the start.
+    pub fn sample(&self, t: f32) -> (f32, f32) {
+        let distance = self.goal - self.start;
+        if self.duration <= 0.0 || distance == 0.0 {
+            return (self.goal, 0.0);
+        }
+        let t = t.clamp(0.0, self.duration);
+        let sign = distance.signum();
+        match self.kind {
+            ProfileKind::Trapezoidal => {
+                let (ta, v, total) = (self.accel_time, self.cruise_velocity, self.duration);
+                let a = if ta > 0.0 { v / ta } else { 0.0 };
+                let (s, vel) = if t < ta {
+                    (0.5 * a * t * t, a * t)
+                } else if t < total - ta {
+                    (0.5 * a * ta * ta + v * (t - ta), v)
+                } else {
+                    let remaining = total - t;
+                    (distance.abs() - 0.5 * a * remaining * remaining, a * remaining)
+                };
+                (self.start + sign * s, sign * vel)
+            }
+            ProfileKind::Cubic => {
+                let tau = t / self.duration;
+                let s = 3.0 * tau * tau - 2.0 * tau * tau * tau;
+                let ds = (6.0 * tau - 6.0 * tau * tau) / self.duration;
+                (self.start + distance * s, distance * ds)
+            }
+            ProfileKind::Quintic => {
+                let tau = t / self.duration;
+                let (t3, t4, t5) = (tau.powi(3), tau.powi(4), tau.powi(5));
+                let s = 10.0 * t3 - 15.0 * t4 + 6.0 * t5;
+                let ds = (30.0 * tau * tau - 60.0 * t3 + 30.0 * t4) / self.duration;
+                (self.start + distance * s, distance * ds)
+            }
+        }
+    }
+}
+
+/// A coordinated multi-joint motion. All joints are planned to finish at the same time, so the slowest joint sets the pace.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trajectory {
+    joints: Vec<(u8, Profile)>,
+}
+
+impl Trajectory {
+    /// Plan a synchronized move of every `(id, start, goal)` with the given shape and per-joint limits.
+    /// Fails with [`InvalidMotionLimits`] if a limit is not a positive number.
+    pub fn plan(kind: ProfileKind, moves: &[(u8, f32, f32)], limits: &[MotionLimits]) -> Result<Self, Error> {
+        let limit = |i: usize| limits.get(i).or(limits.last()).copied().unwrap_or_default();
+        let mut duration = 0.0f32;
+        for (i, (_, start, goal)) in moves.iter().enumerate() {
+            duration = duration.max(Profile::plan(kind, *start, *goal, &limit(i))?.duration());
+        }
+        let mut joints = Vec::with_capacity(moves.len());
+        for (i, (id, start, goal)) in moves.iter().enumerate() {
+            joints.push((*id, Profile::with_duration(kind, *start, *goal, duration, &limit(i))?));
+        }
+        Ok(Trajectory { joints })
+    }
+
+    /// Duration of the whole motion in seconds.
+    pub fn duration(&self) -> f32 {
+        self.joints.iter().map(|(_, p)| p.duration()).fold(0.0f32, f32::max)
+    }
+
+    /// Per-joint profiles.
+    pub fn joints(&self) -> &[(u8, Profile)] {
+        &self.joints
+    }
+
+    /// Setpoints of every joint at time `t`, rounded to raw position units.
+    pub fn setpoints_at(&self, t: f32) -> Vec<(u8, u16)> {
+        self.joints
+            .iter()
+            .map(|(id, profile)| (*id, profile.position(t).round().max(0.0) as u16))
+            .collect()
+    }
+
+    /// Sample times at `rate_hz`, always ending exactly at the final setpoint. Produced lazily, so a very long
+    /// motion costs no memory up front.
+    pub fn sample_times(&self, rate_hz: f32) -> impl Iterator<Item = f32> {
+        let duration = self.duration();
+        let period = 1.0 / rate_hz;
+        let steps = (duration / period).ceil() as usize;
+        (1..=steps).map(move |k| (k as f32 * period).min(duration))
+    }
+}
+
+/// Period of a setpoint or sampling rate in Hz. Fails unless the rate is positive and finite.
+pub fn rate_period(rate_hz: f32) -> Result<Duration, Error> {
+    if !(rate_hz.is_finite() && rate_hz > 0.0) {
+        anyhow::bail!("Invalid rate {} Hz: must be a positive number", rate_hz);
+    }
+    Ok(Duration::try_from_secs_f32(1.0 / rate_hz)?)
+}
+
+/// Streams a [`Trajectory`] to the servos as a sequence of short `MOVE_TIME_WRITE` setpoints at a fixed rate.
+///
+/// Each setpoint is sent with a move time of one period, so the servo's own interpolation smooths between samples.
+pub struct TrajectoryStreamer {
+    rate_hz: f32,
+    period: Duration,
+}
+
+impl TrajectoryStreamer {
+    /// Create a streamer sending setpoints at `rate_hz` (20-50 Hz suits a 115200 baud LewanSoul bus with a few servos).
+    pub fn new(rate_hz: f32) -> Result<Self, Error> {
+        Ok(TrajectoryStreamer {
+            rate_hz,
+            period: rate_period(rate_hz)?,
+        })
+    }
+
+    pub fn rate_hz(&self) -> f32 {
+        self.rate_hz
+    }
+
+    /// Stream the whole trajectory, blocking until the last setpoint has been sent.
+    pub fn run<B: ServoBusProtocol>(&self, bus: &mut B, trajectory: &Trajectory) -> Result<(), Error> {
+        let period_ms = self.period.as_millis() as u16;
+        let start = Instant::now();
+        for t in trajectory.sample_times(self.rate_hz) {
+            for (id, position) in trajectory.setpoints_at(t) {
+                bus.move_to_position(id, position, period_ms)?;
+            }
+            // Sleep until the next tick on an absolute schedule so bus time does not accumulate as drift
+            let next = start + Duration::from_secs_f32(t);
+            let now = Instant::now();
+            if next > now {
+                sleep(next - now);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [ProfileKind; 3] = [ProfileKind::Trapezoidal, ProfileKind::Cubic, ProfileKind::Quintic];
+
+    fn assert_close(a: f32, b: f32, tolerance: f32) {
+        assert!((a - b).abs() <= tolerance, "{} is not within {} of {}", a, tolerance, b);
+    }
+
+    #[test]
+    fn profiles_start_and_end_at_rest() {
+        let limits = MotionLimits::default();
+        for kind in KINDS {
+            for (start, goal) in [(100.0, 900.0), (900.0, 100.0), (500.0, 520.0)] {
+                let profile = Profile::plan(kind, start, goal, &limits).unwrap();
+                assert_close(profile.sample(0.0).0, start, 1e-3);
+                assert_close(profile.sample(0.0).1, 0.0, 1e-3);
+                assert_close(profile.sample(profile.duration()).0, goal, 1e-2);
+                assert_close(profile.sample(profile.duration()).1, 0.0, 1e-2);
+                // Clamped outside the motion
+                assert_eq!(profile.position(-1.0), profile.position(0.0));
+                assert_eq!(profile.position(profile.duration() + 1.0), profile.position(profile.duration()));
+            }
+        }
+    }
+
+    #[test]
+    fn profiles_respect_limits() {
+        let limits = MotionLimits { max_velocity: 400.0, max_acceleration: 1000.0 };
+        for kind in KINDS {
+            for distance in [10.0, 100.0, 800.0] {
+                let profile = Profile::plan(kind, 0.0, distance, &limits).unwrap();
+                let steps = 1000;
+                let dt = profile.duration() / steps as f32;
+                let mut previous = profile.sample(0.0);
+                for k in 1..=steps {
+                    let (position, velocity) = profile.sample(k as f32 * dt);
+                    assert!(velocity.abs() <= limits.max_velocity * 1.001, "{:?}: velocity {}", kind, velocity);
+                    let acceleration = (velocity - previous.1) / dt;
+                    assert!(
+                        acceleration.abs() <= limits.max_acceleration * 1.01,
+                        "{:?}: acceleration {}",
+                        kind,
+                        acceleration
+                    );
+                    assert!(position >= previous.0 - 1e-2, "{:?}: moves backwards", kind);
+                    previous = (position, velocity);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn trapezoid_reaches_cruise_velocity_on_long_moves() {
+        let limits = MotionLimits { max_velocity: 400.0, max_acceleration: 1000.0 };
+        // 0.4 s ramps covering 80 units each, 840 units at 400 units/s
+        let profile = Profile::plan(ProfileKind::Trapezoidal, 0.0, 1000.0, &limits).unwrap();
+        assert_close(profile.duration(), 1000.0 / 400.0 + 400.0 / 1000.0, 1e-4);
+        assert_close(profile.sample(profile.duration() / 2.0).1, 400.0, 1e-2);
+        // Triangular below v²/a
+        let short = Profile::plan(ProfileKind::Trapezoidal, 0.0, 100.0, &limits).unwrap();
+        assert_close(short.duration(), 2.0 * (100.0f32 / 1000.0).sqrt(), 1e-4);
+        assert_close(short.position(short.duration() / 2.0), 50.0, 1e-2);
+    }
+
+    #[test]
+    fn with_duration_stretches_but_never_shortens() {
+        let limits = MotionLimits::default();
+        for kind in KINDS {
+            let fastest = Profile::plan(kind, 0.0, 500.0, &limits).unwrap().duration();
+            let slow = Profile::with_duration(kind, 0.0, 500.0, fastest * 2.0, &limits).unwrap();
+            assert_close(slow.duration(), fastest * 2.0, 1e-4);
+            assert_close(slow.position(slow.duration()), 500.0, 1e-2);
+            let hurried = Profile::with_duration(kind, 0.0, 500.0, fastest / 2.0, &limits).unwrap();
+            assert_close(hurried.duration(), fastest, 1e-4);
+        }
+    }
+
+    #[test]
+    fn zero_distance_stays_put() {
+        let profile = Profile::plan(ProfileKind::Quintic, 300.0, 300.0, &MotionLimits::default()).unwrap();
+        assert_eq!(profile.duration(), 0.0);
+        assert_eq!(profile.sample(0.5), (300.0, 0.0));
+    }
+
+    #[test]
+    fn trajectory_joints_finish_together() {
+        let trajectory = Trajectory::plan(
+            ProfileKind::Trapezoidal,
+            &[(1, 0.0, 800.0), (2, 500.0, 450.0)],
+            &[MotionLimits::default()],
+        )
+        .unwrap();
+        let duration = trajectory.duration();
+        for (_, profile) in trajectory.joints() {
+            assert_close(profile.duration(), duration, 1e-4);
+        }
+        assert_eq!(trajectory.setpoints_at(duration), vec![(1, 800), (2, 450)]);
+        let times: Vec<f32> = trajectory.sample_times(50.0).collect();
+        assert_eq!(times.last().copied(), Some(duration));
+        assert!(times.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn rate_period_rejects_invalid_rates() {
+        assert_eq!(rate_period(50.0).unwrap(), Duration::from_millis(20));
+        for rate in [0.0, -10.0, f32::NAN, f32::INFINITY, f32::MIN_POSITIVE] {
+            assert!(rate_period(rate).is_err(), "{} Hz accepted", rate);
+        }
+    }
+
+    #[test]
+    fn invalid_limits_are_rejected() {
+        let good = MotionLimits::default();
+        let invalid = [(0.0, 2000.0), (-800.0, 2000.0), (800.0, f32::NAN), (f32::INFINITY, 2000.0)];
+        for (max_velocity, max_acceleration) in invalid {
+            let limits = MotionLimits { max_velocity, max_acceleration };
+            let err = Profile::plan(ProfileKind::Trapezoidal, 0.0, 500.0, &limits).unwrap_err();
+            assert!(err.downcast_ref::<InvalidMotionLimits>().is_some(), "{:?} accepted", limits);
+            assert!(Trajectory::plan(ProfileKind::Cubic, &[(1, 0.0, 500.0), (2, 0.0, 10.0)], &[good, limits]).is_err());
+        }
+        assert!(Profile::plan(ProfileKind::Quintic, 0.0, f32::NAN, &good).is_err());
+    }
+}
@@ -0,0 +1,283 @@
+#![allow(dead_code)]
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use anyhow::Error;
+use log::*;
+
+use crate::servo_bus::{ServoBusProtocol, ServoMode, MOTOR_SPEED_RANGE};
+
+/// Position units in one full revolution of servo `id`. Only `0..=position_max` is measurable;
+/// the rest of the turn is the blind zone (120° on an LX-16A).
+pub fn units_per_revolution<B: ServoBusProtocol>(bus: &B, id: u8) -> f32 {
+    360.0 * bus.units_per_degree(id)
+}
+
+/// True if a position reading falls in the blind zone. There the LX-16A reports values beyond `position_max`
+/// (including small negative numbers, which read as large `u16` values), and they carry no information.
+pub fn in_blind_zone(position: u16, position_max: u16) -> bool {
+    position > position_max
+}
+
+/// Signed change between two positions on a circle of `units_per_rev` units, taking the shorter way round.
+/// Correct as long as the servo turns less than half a revolution between the two samples.
+pub fn wrapped_delta(from: u16, to: u16, units_per_rev: f32) -> f32 {
+    let delta = to as f32 - from as f32;
+    delta - units_per_rev * (delta / units_per_rev).round()
+}
+
+/// Estimates angular velocity from successive position samples of a continuously rotating servo.
+///
+/// Samples in the blind zone are skipped: the next valid sample is compared with the last valid one over the longer
+/// interval, so the estimate stays right as long as the wheel turns less than half a revolution in between.
+#[derive(Debug, Clone, Copy)]
+pub struct VelocityEstimator {
+    /// Weight of a new sample in the exponential filter (1.0 = no filtering).
+    alpha: f32,
+    last: Option<(u16, Instant)>,
+    /// Filtered velocity in position units per second.
+    velocity: Option<f32>,
+}
+
+impl VelocityEstimator {
+    pub fn new(alpha: f32) -> Self {
+        VelocityEstimator {
+            alpha: alpha.clamp(0.0, 1.0),
+            last: None,
+            velocity: None,
+        }
+    }
+
+    /// Feed a position sample. Returns the filtered velocity in units per second once two valid samples have been seen.
+    pub fn update(&mut self, position: u16, position_max: u16, units_per_rev: f32, now: Instant) -> Option<f32> {
+        if in_blind_zone(position, position_max) {
+            return self.velocity;
+        }
+        if let Some((last, at)) = self.last {
+            let dt = now.duration_since(at).as_secs_f32();
+            if dt > 0.0 {
+                let sample = wrapped_delta(last, position, units_per_rev) / dt;
+                self.velocity = Some(match self.velocity {
+                    Some(velocity) => velocity + self.alpha * (sample - velocity),
+                    None => sample,
+                });
+            }
+        }
+        self.last = Some((position, now));
+        self.velocity
+    }
+
+    /// Last filtered velocity in units per second.
+    pub fn velocity(&self) -> Option<f32> {
+        self.velocity
+    }
+
+    /// Forget the history, e.g. after the servo was stopped for a while.
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.velocity = None;
+    }
+}
+
+/// PID controller with output and integral clamping.
+#[derive(Debug, Clone, Copy)]
+pub struct Pid {
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+    /// Output is clamped to `-output_limit..=output_limit`; the integral stops growing when the output saturates.
+    pub output_limit: f32,
+    integral: f32,
+    previous_error: Option<f32>,
+}
+
+impl Pid {
+    pub fn new(kp: f32, ki: f32, kd: f32, output_limit: f32) -> Self {
+        Pid {
+            kp,
+            ki,
+            kd,
+            output_limit,
+            integral: 0.0,
+            previous_error: None,
+        }
+    }
+
+    /// Advance the controller by `dt` seconds with the given error and return the clamped output.
+    pub fn update(&mut self, error: f32, dt: f32) -> f32 {
+        let derivative = match self.previous_error {
+            Some(previous) if dt > 0.0 => (error - previous) / dt,
+            _ => 0.0,
+        };
+        self.previous_error = Some(error);
+        let integral = self.integral + error * dt;
+        let output = self.kp * error + self.ki * integral + self.kd * derivative;
+        let clamped = output.clamp(-self.output_limit, self.output_limit);
+        // Anti-windup: only accept the new integral if it does not push further into saturation
+        if output == clamped || output.signum() != error.signum() {
+            self.integral = integral;
+        }
+        clamped
+    }
+
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.previous_error = None;
+    }
+}
+
+/// Tuning of the [`VelocityController`].
+#[derive(Debug, Clone, Copy)]
+pub struct VelocityConfig {
+    /// Control period; also the position sampling period.
+    pub period: Duration,
+    /// PID gains, from velocity error in °/s to motor speed command.
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+    /// Open-loop motor speed command per °/s of target velocity.
+    pub feedforward: f32,
+    /// Smallest motor speed command that actually turns the wheel; non-zero commands are pushed out of the dead band.
+    pub deadband: i16,
+    /// Weight of a new sample in the velocity filter.
+    pub filter_alpha: f32,
+}
+
+impl Default for VelocityConfig {
+    /// An LX-16A turns at about 375 °/s at full speed (0.16 s/60°), hence a feedforward of 1000 / 375.
+    fn default() -> Self {
+        VelocityConfig {
+            period: Duration::from_millis(20),
+            kp: 1.5,
+            ki: 4.0,
+            kd: 0.0,
+            feedforward: 1000.0 / 375.0,
+            deadband: 40,
+            filter_alpha: 0.3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Loop {
+    target_deg_s: f32,
+    estimator: VelocityEstimator,
+    pid: Pid,
+    command: i16,
+}
+
+/// Closed-loop velocity control of servos in motor mode, for wheeled robots.
+///
+/// Motor-mode speed values are not in real units and vary with load and supply voltage. The controller measures the
+/// actual velocity from position samples and corrects the speed command with a PID on top of a feedforward term.
+/// Call [`update`](Self::update) from the main loop at least once per period.
+pub struct VelocityController {
+    config: VelocityConfig,
+    loops: BTreeMap<u8, Loop>,
+    last_update: Option<Instant>,
+}
+
+impl VelocityController {
+    pub fn new(config: VelocityConfig) -> Self {
+        VelocityController {
+            config,
+            loops: BTreeMap::new(),
+            last_update: None,
+        }
+    }
+
+    /// Turn servo `id` at `deg_per_s` (negative reverses). The servo is switched to motor mode on the first call.
+    pub fn set_velocity<B: ServoBusProtocol>(&mut self, bus: &mut B, id: u8, deg_per_s: f32) -> Result<(), Error> {
+        let config = self.config;
+        if let Some(control) = self.loops.get_mut(&id) {
+            control.target_deg_s = deg_per_s;
+            return Ok(());
+        }
+        let command = Self::feedforward(&config, deg_per_s);
+        bus.set_mode(id, ServoMode::Motor { speed: command })?;
+        self.loops.insert(
+            id,
+            Loop {
+                target_deg_s: deg_per_s,
+                estimator: VelocityEstimator::new(config.filter_alpha),
+                pid: Pid::new(config.kp, config.ki, config.kd, *MOTOR_SPEED_RANGE.end() as f32),
+                command,
+            },
+        );
+        Ok(())
+    }
+
+    /// Stop servo `id` and release it from velocity control (it stays in motor mode at speed 0).
+    pub fn stop<B: ServoBusProtocol>(&mut self, bus: &mut B, id: u8) -> Result<(), Error> {
+        self.loops.remove(&id);
+        bus.set_mode(id, ServoMode::Motor { speed: 0 })
+    }
+
+    /// Measured velocity of servo `id` in °/s, once known.
+    pub fn velocity<B: ServoBusProtocol>(&self, bus: &B, id: u8) -> Option<f32> {
+        let control = self.loops.get(&id)?;
+        Some(control.estimator.velocity()? / bus.units_per_degree(id))
+    }
+
+    /// Last motor speed command sent to servo `id`.
+    pub fn command(&self, id: u8) -> Option<i16> {
+        self.loops.get(&id).map(|control| control.command)
+    }
+
+    /// Sample every controlled servo and update its speed command, if a period has elapsed.
+    pub fn update<B: ServoBusProtocol>(&mut self, bus: &mut B) -> Result<(), Error> {
+        let now = Instant::now();
+        let dt = match self.last_update {
+            Some(last) if now.duration_since(last) < self.config.period => return Ok(()),
+            Some(last) => now.duration_since(last).as_secs_f32(),
+            None => self.config.period.as_secs_f32(),
+        };
+        self.last_update = Some(now);
+
+        let config = self.config;
+        let ids: Vec<u8> = self.loops.keys().copied().collect();
+        for id in ids {
+            let position = match bus.read_position(id) {
+                Ok(position) => position,
+                Err(e) => {
+                    debug!("Velocity control: servo {} read failed: {:?}", id, e);
+                    continue;
+                }
+            };
+            let (position_max, units_per_rev, units_per_degree) =
+                (bus.position_max(id), units_per_revolution(bus, id), bus.units_per_degree(id));
+            let control = match self.loops.get_mut(&id) {
+                Some(control) => control,
+                None => continue,
+            };
+            let measured = match control.estimator.update(position, position_max, units_per_rev, now) {
+                Some(velocity) => velocity / units_per_degree,
+                None => continue,
+            };
+            let correction = control.pid.update(control.target_deg_s - measured, dt);
+            let command = Self::feedforward(&config, control.target_deg_s) as f32 + correction;
+            let command = Self::apply_deadband(&config, control.target_deg_s, command);
+            if command != control.command {
+                control.command = command;
+                bus.set_mode(id, ServoMode::Motor { speed: command })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn feedforward(config: &VelocityConfig, deg_per_s: f32) -> i16 {
+        Self::apply_deadband(config, deg_per_s, deg_per_s * config.feedforward)
+    }
+
+    /// Round a speed command and push it out of the motor's dead band; a zero target always gives 0.
+    fn apply_deadband(config: &VelocityConfig, target: f32, command: f32) -> i16 {
+        if target == 0.0 {
+            return 0;
+        }
+        let command = (command.round() as i16).clamp(*MOTOR_SPEED_RANGE.start(), *MOTOR_SPEED_RANGE.end());
+        if command != 0 && command.abs() < config.deadband {
+            config.deadband * command.signum()
+        } else {
+            command
+        }
+    }
+}
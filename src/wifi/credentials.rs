@@ -0,0 +1,208 @@
+#![allow(dead_code)]
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use serde::{Deserialize, Serialize};
+use anyhow::Error;
+use log::*;
+
+/// NVS namespace holding the Wi-Fi credentials.
+const NVS_NAMESPACE: &str = "wifi";
+/// Key of the known networks, stored as one JSON list so SSIDs and passwords are always written together.
+const NETWORKS_KEY: &str = "networks";
+/// Key of the single network stored by earlier firmware; read as a known network of [`DEFAULT_PRIORITY`].
+const LEGACY_CREDENTIALS_KEY: &str = "credentials";
+/// Most known networks kept; the list has to fit in one NVS string.
+pub const MAX_NETWORKS: usize = 16;
+/// Priority of networks added without one, e.g. through the provisioning portal.
+pub const DEFAULT_PRIORITY: u8 = 10;
+
+/// Longest SSID accepted by the Wi-Fi driver, in bytes.
+pub const MAX_SSID_LEN: usize = 32;
+/// Shortest WPA passphrase; an empty password selects an open network.
+pub const MIN_PASSWORD_LEN: usize = 8;
+/// Longest WPA passphrase (or 64 hex digits PSK), in bytes.
+pub const MAX_PASSWORD_LEN: usize = 64;
+
+/// SSID and password of the network to join.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WifiCredentials {
+    pub ssid: String,
+    pub password: String,
+}
+
+impl core::fmt::Debug for WifiCredentials {
+    // Keep the password out of the logs
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("WifiCredentials")
+            .field("ssid", &self.ssid)
+            .field("password", &"<hidden>")
+            .finish()
+    }
+}
+
+impl WifiCredentials {
+    /// Credentials for `ssid`, checked against the lengths the Wi-Fi driver accepts.
+    pub fn new(ssid: &str, password: &str) -> Result<Self, Error> {
+        let credentials = WifiCredentials {
+            ssid: ssid.to_string(),
+            password: password.to_string(),
+        };
+        credentials.validate()?;
+        Ok(credentials)
+    }
+
+    /// Check the SSID and password lengths.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.ssid.is_empty() || self.ssid.len() > MAX_SSID_LEN {
+            anyhow::bail!("SSID must be 1 to {} bytes long", MAX_SSID_LEN);
+        }
+        if !self.password.is_empty() && !(MIN_PASSWORD_LEN..=MAX_PASSWORD_LEN).contains(&self.password.len()) {
+            anyhow::bail!(
+                "Password must be empty or {} to {} bytes long",
+                MIN_PASSWORD_LEN,
+                MAX_PASSWORD_LEN
+            );
+        }
+        Ok(())
+    }
+
+    /// Credentials from the `WIFI_SSID` and `WIFI_PASSWORD` environment variables at build time, if set.
+    ///
+    /// Meant for development boards: the values end up in the firmware image, so release builds should leave them
+    /// unset and rely on the credentials stored in NVS.
+    pub fn from_build_env() -> Option<Self> {
+        let ssid = option_env!("WIFI_SSID")?;
+        let password = option_env!("WIFI_PASSWORD").unwrap_or("");
+        match WifiCredentials::new(ssid, password) {
+            Ok(credentials) => Some(credentials),
+            Err(e) => {
+                warn!("Ignoring build-time Wi-Fi credentials: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// A network the robot may join. When several are in range, the highest priority wins, then the strongest signal.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KnownNetwork {
+    #[serde(flatten)]
+    pub credentials: WifiCredentials,
+    #[serde(default = "default_priority")]
+    pub priority: u8,
+}
+
+fn default_priority() -> u8 {
+    DEFAULT_PRIORITY
+}
+
+impl KnownNetwork {
+    pub fn new(credentials: WifiCredentials, priority: u8) -> Self {
+        KnownNetwork { credentials, priority }
+    }
+
+    pub fn ssid(&self) -> &str {
+        &self.credentials.ssid
+    }
+}
+
+/// Where the credentials in use came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CredentialSource {
+    Nvs,
+    BuildEnv,
+}
+
+/// Known Wi-Fi networks stored in NVS, so no network secret has to be compiled into the firmware.
+pub struct CredentialStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl CredentialStore {
+    /// Open (or create) the Wi-Fi namespace on the default NVS partition.
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self, Error> {
+        let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+        Ok(CredentialStore { nvs })
+    }
+
+    /// Load the known networks, highest priority first; empty if none are stored.
+    pub fn load(&self) -> Result<Vec<KnownNetwork>, Error> {
+        if let Some(json) = self.get_str(NETWORKS_KEY)? {
+            return Ok(serde_json::from_str(&json)?);
+        }
+        match self.get_str(LEGACY_CREDENTIALS_KEY)? {
+            Some(json) => Ok(vec![KnownNetwork::new(serde_json::from_str(&json)?, DEFAULT_PRIORITY)]),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Load the known networks, falling back to the build-time credentials (see [`WifiCredentials::from_build_env`]).
+    /// Stored networks that cannot be read are logged and skipped.
+    pub fn load_or_build_env(&self) -> Option<(Vec<KnownNetwork>, CredentialSource)> {
+        match self.load() {
+            Ok(networks) if !networks.is_empty() => return Some((networks, CredentialSource::Nvs)),
+            Ok(_) => {}
+            Err(e) => error!("Stored Wi-Fi networks are invalid: {:?}", e),
+        }
+        WifiCredentials::from_build_env()
+            .map(|credentials| (vec![KnownNetwork::new(credentials, DEFAULT_PRIORITY)], CredentialSource::BuildEnv))
+    }
+
+    /// Validate and add a known network, replacing the one with the same SSID. Used from the next connection on.
+    pub fn add(&mut self, network: &KnownNetwork) -> Result<(), Error> {
+        network.credentials.validate()?;
+        let mut networks = self.load()?;
+        networks.retain(|known| known.ssid() != network.ssid());
+        if networks.len() >= MAX_NETWORKS {
+            anyhow::bail!("Cannot store more than {} Wi-Fi networks", MAX_NETWORKS);
+        }
+        networks.push(network.clone());
+        self.store(networks)?;
+        info!("Stored Wi-Fi network \"{}\" (priority {})", network.ssid(), network.priority);
+        Ok(())
+    }
+
+    /// Store credentials as a known network, keeping the priority of a network already known under that SSID.
+    pub fn save(&mut self, credentials: &WifiCredentials) -> Result<(), Error> {
+        let priority = self
+            .load()?
+            .iter()
+            .find(|known| known.ssid() == credentials.ssid)
+            .map_or(DEFAULT_PRIORITY, |known| known.priority);
+        self.add(&KnownNetwork::new(credentials.clone(), priority))
+    }
+
+    /// Forget the network `ssid`. Returns false if it was not known.
+    pub fn remove(&mut self, ssid: &str) -> Result<bool, Error> {
+        let mut networks = self.load()?;
+        let count = networks.len();
+        networks.retain(|known| known.ssid() != ssid);
+        if networks.len() == count {
+            return Ok(false);
+        }
+        self.store(networks)?;
+        Ok(true)
+    }
+
+    /// Delete every known network. Returns false if none were stored.
+    pub fn erase(&mut self) -> Result<bool, Error> {
+        let networks = self.nvs.remove(NETWORKS_KEY)?;
+        let legacy = self.nvs.remove(LEGACY_CREDENTIALS_KEY)?;
+        Ok(networks || legacy)
+    }
+
+    fn store(&mut self, mut networks: Vec<KnownNetwork>) -> Result<(), Error> {
+        networks.sort_by_key(|known| core::cmp::Reverse(known.priority));
+        self.nvs.set_str(NETWORKS_KEY, &serde_json::to_string(&networks)?)?;
+        self.nvs.remove(LEGACY_CREDENTIALS_KEY)?;
+        Ok(())
+    }
+
+    fn get_str(&self, key: &str) -> Result<Option<String>, Error> {
+        let len = match self.nvs.str_len(key)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        let mut buf = vec![0u8; len + 1];
+        Ok(self.nvs.get_str(key, &mut buf)?.map(str::to_owned))
+    }
+}
@@ -0,0 +1,72 @@
+#![allow(dead_code)]
+use std::net::Ipv4Addr;
+use esp_idf_svc::wifi::config::ScanConfig;
+use esp_idf_svc::wifi::{AccessPointInfo, Configuration, EspWifi};
+use esp_idf_sys::EspError;
+
+/// The Wi-Fi driver operations used by the [`WifiManager`](super::manager::WifiManager) and the
+/// [provisioning portal](super::portal::provision).
+///
+/// Implemented by [`EspWifi`]; a scripted implementation lets them run without the modem, e.g. in tests.
+pub trait WifiDriver {
+    fn set_configuration(&mut self, configuration: &Configuration) -> Result<(), EspError>;
+    fn start(&mut self) -> Result<(), EspError>;
+    /// Start connecting the station; completion is reported by [`is_connected`](Self::is_connected) and by events.
+    fn connect(&mut self) -> Result<(), EspError>;
+    fn disconnect(&mut self) -> Result<(), EspError>;
+    /// True while the station is associated with an access point.
+    fn is_connected(&self) -> Result<bool, EspError>;
+    /// Scan and wait for the results.
+    fn scan(&mut self) -> Result<Vec<AccessPointInfo>, EspError>;
+    /// Start a scan without waiting; completion is reported by a scan-done event.
+    fn start_scan(&mut self) -> Result<(), EspError>;
+    /// Results of the last completed scan.
+    fn scan_result(&mut self) -> Result<Vec<AccessPointInfo>, EspError>;
+    /// Station address, once DHCP has assigned one.
+    fn sta_ip(&self) -> Result<Option<Ipv4Addr>, EspError>;
+    /// Address of the soft access point.
+    fn ap_ip(&self) -> Result<Ipv4Addr, EspError>;
+}
+
+impl WifiDriver for EspWifi<'_> {
+    fn set_configuration(&mut self, configuration: &Configuration) -> Result<(), EspError> {
+        EspWifi::set_configuration(self, configuration)
+    }
+
+    fn start(&mut self) -> Result<(), EspError> {
+        EspWifi::start(self)
+    }
+
+    fn connect(&mut self) -> Result<(), EspError> {
+        EspWifi::connect(self)
+    }
+
+    fn disconnect(&mut self) -> Result<(), EspError> {
+        EspWifi::disconnect(self)
+    }
+
+    fn is_connected(&self) -> Result<bool, EspError> {
+        EspWifi::is_connected(self)
+    }
+
+    fn scan(&mut self) -> Result<Vec<AccessPointInfo>, EspError> {
+        EspWifi::scan(self)
+    }
+
+    fn start_scan(&mut self) -> Result<(), EspError> {
+        EspWifi::start_scan(self, &ScanConfig::default(), false)
+    }
+
+    fn scan_result(&mut self) -> Result<Vec<AccessPointInfo>, EspError> {
+        EspWifi::get_scan_result(self)
+    }
+
+    fn sta_ip(&self) -> Result<Option<Ipv4Addr>, EspError> {
+        let ip = self.sta_netif().get_ip_info()?.ip;
+        Ok((!ip.is_unspecified()).then_some(ip))
+    }
+
+    fn ap_ip(&self) -> Result<Ipv4Addr, EspError> {
+        Ok(self.ap_netif().get_ip_info()?.ip)
+    }
+}
@@ -0,0 +1,568 @@
+#![allow(dead_code)]
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use esp_idf_svc::eventloop::{EspSubscription, EspSystemEventLoop, System};
+use esp_idf_svc::netif::IpEvent;
+use esp_idf_svc::wifi::{Configuration, EspWifi, WifiEvent};
+use log::*;
+
+use super::credentials::KnownNetwork;
+use super::driver::WifiDriver;
+use super::{client_configuration, rank_networks, WifiError};
+
+/// Pause between abandoning an attempt and the next one, so the disconnect event of the old attempt is not taken for
+/// a failure of the new one.
+const SWITCH_DELAY: Duration = Duration::from_millis(500);
+
+/// Connection state of the station.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WifiState {
+    Disconnected,
+    /// Association in progress.
+    Connecting,
+    /// Associated with the access point, waiting for DHCP.
+    Connected,
+    /// Associated and addressed: the network is usable.
+    GotIp,
+}
+
+/// Timeouts and reconnection backoff of the [`WifiManager`].
+#[derive(Debug, Clone, Copy)]
+pub struct WifiManagerConfig {
+    /// Time from a connection attempt to an IP address before the attempt is abandoned.
+    pub connect_timeout: Duration,
+    /// Time given to a scan to complete.
+    pub scan_timeout: Duration,
+    /// Delay before the first retry; doubled after every round in which no known network could be joined.
+    pub backoff_min: Duration,
+    /// Longest delay between two rounds.
+    pub backoff_max: Duration,
+}
+
+impl Default for WifiManagerConfig {
+    fn default() -> Self {
+        WifiManagerConfig {
+            connect_timeout: Duration::from_secs(10),
+            scan_timeout: Duration::from_secs(10),
+            backoff_min: Duration::from_secs(1),
+            backoff_max: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Driver events, recorded by the event handlers and consumed by [`WifiManager::poll`].
+#[derive(Debug)]
+struct Events {
+    state: WifiState,
+    scan_done: bool,
+}
+
+/// Station connection to the best of several known networks, kept up from Wi-Fi and IP events.
+///
+/// Every round scans first and tries the known networks in range by priority, then signal strength. A round starts
+/// at boot and after every disconnect, so a robot moved from the lab to the office joins the office network on its own.
+///
+/// The event handlers registered by [`subscribe`](Self::subscribe) only record the events (a scripted [`WifiDriver`]
+/// feeds [`report`](Self::report) instead); [`poll`](Self::poll), called from the main loop, times out stuck attempts,
+/// starts a new round with exponential backoff when no network could be joined, and runs the
+/// [`on_change`](Self::on_change) callbacks on the main loop, so they can safely stop motion or pause telemetry when
+/// the network goes away.
+pub struct WifiManager<W: WifiDriver = EspWifi<'static>> {
+    wifi: W,
+    config: WifiManagerConfig,
+    networks: Vec<KnownNetwork>,
+    events: Arc<Mutex<Events>>,
+    subscriptions: Vec<EspSubscription<'static, System>>,
+    /// State as last seen by `poll` and the callbacks.
+    reported: WifiState,
+    /// Networks of the current round not tried yet, best first.
+    candidates: VecDeque<KnownNetwork>,
+    /// Network of the current attempt or connection.
+    current: Option<KnownNetwork>,
+    scan_started: Option<Instant>,
+    attempt_started: Option<Instant>,
+    retry_at: Option<Instant>,
+    /// Rounds without a connection since the last successful one.
+    failures: u32,
+    callbacks: Vec<Box<dyn FnMut(WifiState)>>,
+}
+
+impl<W: WifiDriver> WifiManager<W> {
+    /// Start `wifi` in station mode to join the best of `networks`. The first [`poll`](Self::poll) scans.
+    pub fn new(mut wifi: W, networks: Vec<KnownNetwork>, config: WifiManagerConfig) -> Result<Self, WifiError> {
+        wifi.set_configuration(&Configuration::Client(Default::default()))?;
+        wifi.start()?;
+        Ok(WifiManager {
+            wifi,
+            config,
+            networks,
+            events: Arc::new(Mutex::new(Events {
+                state: WifiState::Disconnected,
+                scan_done: false,
+            })),
+            subscriptions: Vec::new(),
+            reported: WifiState::Disconnected,
+            candidates: VecDeque::new(),
+            current: None,
+            scan_started: None,
+            attempt_started: None,
+            retry_at: Some(Instant::now()),
+            failures: 0,
+            callbacks: Vec::new(),
+        })
+    }
+
+    /// Track the station through the Wi-Fi and IP events of `sysloop`.
+    pub fn subscribe(&mut self, sysloop: &EspSystemEventLoop) -> Result<(), WifiError> {
+        let events = self.events.clone();
+        self.subscriptions.push(sysloop.subscribe::<WifiEvent, _>(move |event| match event {
+            WifiEvent::ScanDone(_) => lock(&events).scan_done = true,
+            WifiEvent::StaConnected(_) => lock(&events).state = WifiState::Connected,
+            WifiEvent::StaDisconnected(_) => lock(&events).state = WifiState::Disconnected,
+            _ => {}
+        })?);
+        let events = self.events.clone();
+        self.subscriptions.push(sysloop.subscribe::<IpEvent, _>(move |event| match event {
+            IpEvent::DhcpIpAssigned(_) => lock(&events).state = WifiState::GotIp,
+            IpEvent::DhcpIpDeassigned(_) => {
+                let mut events = lock(&events);
+                if events.state == WifiState::GotIp {
+                    events.state = WifiState::Connected;
+                }
+            }
+            _ => {}
+        })?);
+        Ok(())
+    }
+
+    /// Record a state reported by the driver. Taken into account on the next [`poll`](Self::poll).
+    pub fn report(&self, state: WifiState) {
+        lock(&self.events).state = state;
+    }
+
+    /// Record that the scan started by the manager has completed.
+    pub fn report_scan_done(&self) {
+        lock(&self.events).scan_done = true;
+    }
+
+    /// Current state, as of the last [`poll`](Self::poll).
+    pub fn state(&self) -> WifiState {
+        self.reported
+    }
+
+    /// True once the station has an IP address.
+    pub fn is_online(&self) -> bool {
+        self.reported == WifiState::GotIp
+    }
+
+    /// Station IP address while online.
+    pub fn ip(&self) -> Option<Ipv4Addr> {
+        if !self.is_online() {
+            return None;
+        }
+        self.wifi.sta_ip().ok().flatten()
+    }
+
+    /// SSID of the network being joined or joined.
+    pub fn ssid(&self) -> Option<&str> {
+        self.current.as_ref().map(KnownNetwork::ssid)
+    }
+
+    /// Run `callback` with the new state on every state change seen by [`poll`](Self::poll).
+    pub fn on_change(&mut self, callback: impl FnMut(WifiState) + 'static) {
+        self.callbacks.push(Box::new(callback));
+    }
+
+    /// Replace the known networks and start a new round right away.
+    pub fn set_networks(&mut self, networks: Vec<KnownNetwork>) {
+        self.networks = networks;
+        if let Err(e) = self.wifi.disconnect() {
+            debug!("Wi-Fi disconnect failed: {:?}", e);
+        }
+        self.candidates.clear();
+        self.current = None;
+        self.scan_started = None;
+        self.attempt_started = None;
+        self.failures = 0;
+        self.retry_at = Some(Instant::now() + SWITCH_DELAY);
+        lock(&self.events).state = WifiState::Disconnected;
+    }
+
+    /// Track the connection: report state changes, abandon scans and attempts that time out, move on to the next
+    /// network after a failure and start a new round after a disconnect. Call it on every main loop iteration.
+    pub fn poll(&mut self) {
+        let now = Instant::now();
+        let (state, scan_done) = {
+            let mut events = lock(&self.events);
+            (events.state, core::mem::take(&mut events.scan_done))
+        };
+
+        if let Some(started) = self.scan_started {
+            if scan_done {
+                self.scan_started = None;
+                self.finish_scan(now);
+            } else if now.duration_since(started) >= self.config.scan_timeout {
+                warn!("Wi-Fi: scan did not complete");
+                self.scan_started = None;
+                self.fail(now);
+            }
+        } else if state == WifiState::GotIp {
+            if self.attempt_started.take().is_some() {
+                // Connected: the next disconnect starts a new round with a fresh scan
+                self.candidates.clear();
+                self.failures = 0;
+            }
+        } else if let Some(started) = self.attempt_started {
+            if now.duration_since(started) >= self.config.connect_timeout {
+                warn!(
+                    "Wi-Fi: no connection to \"{}\" after {} s",
+                    self.ssid().unwrap_or(""),
+                    self.config.connect_timeout.as_secs()
+                );
+                if let Err(e) = self.wifi.disconnect() {
+                    debug!("Wi-Fi disconnect failed: {:?}", e);
+                }
+                self.fail(now);
+            } else if state == WifiState::Disconnected {
+                self.fail(now);
+            }
+        } else if state == WifiState::Disconnected && self.retry_at.is_none() {
+            // Connection lost
+            self.fail(now);
+        }
+        if self.retry_at.is_some_and(|at| now >= at) {
+            self.retry_at = None;
+            if self.candidates.is_empty() {
+                self.start_scan(now);
+            } else {
+                self.connect_next(now);
+            }
+        }
+
+        let state = lock(&self.events).state;
+        if state != self.reported {
+            match state {
+                WifiState::GotIp => info!(
+                    "Wi-Fi online on \"{}\", IP: {:?}",
+                    self.ssid().unwrap_or(""),
+                    self.wifi.sta_ip()
+                ),
+                WifiState::Disconnected if self.reported == WifiState::GotIp => warn!("Wi-Fi connection lost"),
+                _ => debug!("Wi-Fi: {:?}", state),
+            }
+            self.reported = state;
+            for callback in self.callbacks.iter_mut() {
+                callback(state);
+            }
+        }
+    }
+
+    /// Longest time one round can take: the scan, then every known network tried in turn. Waiting this long with
+    /// [`wait_online`](Self::wait_online) gives every network in range a chance.
+    pub fn round_timeout(&self) -> Duration {
+        let attempts = self.networks.len() as u32;
+        self.config.scan_timeout + (self.config.connect_timeout + SWITCH_DELAY) * attempts
+    }
+
+    /// Poll until online or until `timeout` has elapsed. Returns true if online.
+    pub fn wait_online(&mut self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        loop {
+            self.poll();
+            if self.is_online() {
+                return true;
+            }
+            if start.elapsed() >= timeout {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// Give the driver back, e.g. to hand it to the provisioning portal. Event tracking stops.
+    pub fn into_inner(self) -> W {
+        self.wifi
+    }
+
+    fn start_scan(&mut self, now: Instant) {
+        lock(&self.events).scan_done = false;
+        match self.wifi.start_scan() {
+            Ok(()) => self.scan_started = Some(now),
+            Err(e) => {
+                warn!("Wi-Fi scan failed: {:?}", e);
+                self.fail(now);
+            }
+        }
+    }
+
+    fn finish_scan(&mut self, now: Instant) {
+        let scan = match self.wifi.scan_result() {
+            Ok(scan) => scan,
+            Err(e) => {
+                warn!("Wi-Fi scan failed: {:?}", e);
+                return self.fail(now);
+            }
+        };
+        let ranked = rank_networks(&self.networks, &scan);
+        if ranked.is_empty() {
+            warn!("Wi-Fi: no known network in range ({} networks seen)", scan.len());
+            return self.fail(now);
+        }
+        for (network, rssi) in &ranked {
+            debug!("Wi-Fi: \"{}\" in range, priority {}, {} dBm", network.ssid(), network.priority, rssi);
+        }
+        self.candidates = ranked.into_iter().map(|(network, _)| network).collect();
+        self.connect_next(now);
+    }
+
+    fn connect_next(&mut self, now: Instant) {
+        let network = match self.candidates.pop_front() {
+            Some(network) => network,
+            None => return self.fail(now),
+        };
+        info!("Wi-Fi: joining \"{}\"", network.ssid());
+        let configured = client_configuration(&network.credentials)
+            .and_then(|client| Ok(self.wifi.set_configuration(&Configuration::Client(client))?));
+        self.current = Some(network);
+        self.attempt_started = Some(now);
+        lock(&self.events).state = WifiState::Connecting;
+        if let Err(e) = configured.and_then(|()| Ok(self.wifi.connect()?)) {
+            warn!("Wi-Fi connect failed: {:?}", e);
+            self.fail(now);
+        }
+    }
+
+    /// Abandon the current scan or attempt. The next network of the round is tried after a short pause; when the
+    /// round is exhausted, a new one is scheduled with exponential backoff.
+    fn fail(&mut self, now: Instant) {
+        self.attempt_started = None;
+        lock(&self.events).state = WifiState::Disconnected;
+        if !self.candidates.is_empty() {
+            self.retry_at = Some(now + SWITCH_DELAY);
+            return;
+        }
+        let delay = self
+            .config
+            .backoff_min
+            .saturating_mul(1 << self.failures.min(16))
+            .min(self.config.backoff_max);
+        self.failures += 1;
+        self.retry_at = Some(now + delay);
+        info!("Wi-Fi: scanning again in {} ms", delay.as_millis());
+    }
+}
+
+fn lock(events: &Mutex<Events>) -> std::sync::MutexGuard<'_, Events> {
+    events.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::thread::sleep;
+    use esp_idf_svc::wifi::AccessPointInfo;
+    use esp_idf_sys::EspError;
+    use crate::wifi::credentials::WifiCredentials;
+
+    /// What the manager asked of the driver, and the access points a scan returns.
+    #[derive(Default)]
+    struct Script {
+        in_range: Vec<AccessPointInfo>,
+        scans: u32,
+        joined: Vec<String>,
+        disconnects: u32,
+    }
+
+    struct ScriptedWifi(Rc<RefCell<Script>>);
+
+    impl WifiDriver for ScriptedWifi {
+        fn set_configuration(&mut self, configuration: &Configuration) -> Result<(), EspError> {
+            if let Configuration::Client(client) = configuration {
+                if !client.ssid.is_empty() {
+                    self.0.borrow_mut().joined.push(client.ssid.to_string());
+                }
+            }
+            Ok(())
+        }
+
+        fn start(&mut self) -> Result<(), EspError> {
+            Ok(())
+        }
+
+        fn connect(&mut self) -> Result<(), EspError> {
+            Ok(())
+        }
+
+        fn disconnect(&mut self) -> Result<(), EspError> {
+            self.0.borrow_mut().disconnects += 1;
+            Ok(())
+        }
+
+        fn is_connected(&self) -> Result<bool, EspError> {
+            Ok(false)
+        }
+
+        fn scan(&mut self) -> Result<Vec<AccessPointInfo>, EspError> {
+            Ok(self.0.borrow().in_range.clone())
+        }
+
+        fn start_scan(&mut self) -> Result<(), EspError> {
+            self.0.borrow_mut().scans += 1;
+            Ok(())
+        }
+
+        fn scan_result(&mut self) -> Result<Vec<AccessPointInfo>, EspError> {
+            Ok(self.0.borrow().in_range.clone())
+        }
+
+        fn sta_ip(&self) -> Result<Option<Ipv4Addr>, EspError> {
+            Ok(None)
+        }
+
+        fn ap_ip(&self) -> Result<Ipv4Addr, EspError> {
+            Ok(Ipv4Addr::UNSPECIFIED)
+        }
+    }
+
+    const CONFIG: WifiManagerConfig = WifiManagerConfig {
+        connect_timeout: Duration::from_millis(50),
+        scan_timeout: Duration::from_millis(50),
+        backoff_min: Duration::from_millis(50),
+        backoff_max: Duration::from_millis(200),
+    };
+
+    fn network(ssid: &str, priority: u8) -> KnownNetwork {
+        KnownNetwork::new(WifiCredentials::new(ssid, "password").unwrap(), priority)
+    }
+
+    /// A manager knowing "lab" (priority 10) and "office" (priority 20), both in range, and its script.
+    fn manager() -> (WifiManager<ScriptedWifi>, Rc<RefCell<Script>>) {
+        let script = Rc::new(RefCell::new(Script::default()));
+        script.borrow_mut().in_range = ["lab", "office"]
+            .iter()
+            .map(|ssid| AccessPointInfo {
+                ssid: (*ssid).try_into().unwrap(),
+                signal_strength: -60,
+                ..Default::default()
+            })
+            .collect();
+        let networks = vec![network("lab", 10), network("office", 20)];
+        let manager = WifiManager::new(ScriptedWifi(script.clone()), networks, CONFIG).unwrap();
+        (manager, script)
+    }
+
+    /// Scan and start joining the best network.
+    fn start_round(manager: &mut WifiManager<ScriptedWifi>) {
+        manager.poll();
+        manager.report_scan_done();
+        manager.poll();
+    }
+
+    #[test]
+    fn joins_the_best_network_in_range() {
+        let (mut manager, script) = manager();
+        let states = Rc::new(RefCell::new(Vec::new()));
+        let seen = states.clone();
+        manager.on_change(move |state| seen.borrow_mut().push(state));
+
+        manager.poll();
+        assert_eq!(script.borrow().scans, 1);
+        assert_eq!(manager.state(), WifiState::Disconnected);
+        manager.report_scan_done();
+        manager.poll();
+        assert_eq!(script.borrow().joined, vec!["office"]);
+        assert_eq!(manager.state(), WifiState::Connecting);
+        assert_eq!(manager.ssid(), Some("office"));
+
+        manager.report(WifiState::Connected);
+        manager.poll();
+        manager.report(WifiState::GotIp);
+        manager.poll();
+        assert!(manager.is_online());
+        assert_eq!(
+            *states.borrow(),
+            vec![WifiState::Connecting, WifiState::Connected, WifiState::GotIp]
+        );
+    }
+
+    #[test]
+    fn tries_the_next_network_after_a_failure() {
+        let (mut manager, script) = manager();
+        start_round(&mut manager);
+        manager.report(WifiState::Disconnected);
+        manager.poll();
+        assert_eq!(manager.state(), WifiState::Disconnected);
+        sleep(SWITCH_DELAY);
+        manager.poll();
+        assert_eq!(script.borrow().joined, vec!["office", "lab"]);
+        assert_eq!(manager.ssid(), Some("lab"));
+        // No new scan within the round
+        assert_eq!(script.borrow().scans, 1);
+    }
+
+    #[test]
+    fn abandons_an_attempt_that_times_out() {
+        let (mut manager, script) = manager();
+        start_round(&mut manager);
+        sleep(CONFIG.connect_timeout);
+        manager.poll();
+        assert_eq!(script.borrow().disconnects, 1);
+        sleep(SWITCH_DELAY);
+        manager.poll();
+        assert_eq!(script.borrow().joined, vec!["office", "lab"]);
+    }
+
+    #[test]
+    fn scans_again_with_backoff_when_no_known_network_is_in_range() {
+        let (mut manager, script) = manager();
+        script.borrow_mut().in_range.clear();
+        start_round(&mut manager);
+        assert!(script.borrow().joined.is_empty());
+        manager.poll();
+        assert_eq!(script.borrow().scans, 1);
+        sleep(CONFIG.backoff_min);
+        manager.poll();
+        assert_eq!(script.borrow().scans, 2);
+        // The second empty round waits twice as long
+        manager.report_scan_done();
+        manager.poll();
+        sleep(CONFIG.backoff_min);
+        manager.poll();
+        assert_eq!(script.borrow().scans, 2);
+        sleep(CONFIG.backoff_min);
+        manager.poll();
+        assert_eq!(script.borrow().scans, 3);
+    }
+
+    #[test]
+    fn starts_a_new_round_when_the_connection_is_lost() {
+        let (mut manager, script) = manager();
+        start_round(&mut manager);
+        manager.report(WifiState::GotIp);
+        manager.poll();
+        assert!(manager.is_online());
+
+        manager.report(WifiState::Disconnected);
+        manager.poll();
+        assert!(!manager.is_online());
+        sleep(CONFIG.backoff_min);
+        manager.poll();
+        assert_eq!(script.borrow().scans, 2);
+        manager.report_scan_done();
+        manager.poll();
+        assert_eq!(script.borrow().joined, vec!["office", "office"]);
+    }
+
+    #[test]
+    fn round_timeout_covers_every_known_network() {
+        let (manager, _) = manager();
+        assert_eq!(
+            manager.round_timeout(),
+            CONFIG.scan_timeout + (CONFIG.connect_timeout + SWITCH_DELAY) * 2
+        );
+    }
+}
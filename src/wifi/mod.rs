@@ -0,0 +1,169 @@
+use std::time::{Duration, Instant};
+use esp_idf_svc::wifi::EspWifi;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_hal::modem::Modem;
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_svc::wifi::{AccessPointInfo, AuthMethod, ClientConfiguration};
+use esp_idf_sys::EspError;
+
+pub mod credentials;
+pub mod driver;
+pub mod manager;
+pub mod portal;
+use credentials::{KnownNetwork, WifiCredentials};
+use driver::WifiDriver;
+
+/// Errors of the Wi-Fi setup and connection.
+#[derive(Debug)]
+pub enum WifiError {
+    /// The ESP-IDF Wi-Fi driver or event loop failed.
+    Driver(EspError),
+    /// An SSID or password does not fit the driver configuration.
+    InvalidConfiguration(&'static str),
+    /// The station did not connect within the timeout.
+    Timeout { timeout: Duration },
+}
+
+impl core::fmt::Display for WifiError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            WifiError::Driver(e) => write!(f, "Wi-Fi driver error: {}", e),
+            WifiError::InvalidConfiguration(reason) => write!(f, "invalid Wi-Fi configuration: {}", reason),
+            WifiError::Timeout { timeout } => write!(f, "no Wi-Fi connection after {} s", timeout.as_secs()),
+        }
+    }
+}
+
+impl std::error::Error for WifiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WifiError::Driver(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<EspError> for WifiError {
+    fn from(e: EspError) -> Self {
+        WifiError::Driver(e)
+    }
+}
+
+/// Create the Wi-Fi driver on `modem`. It is not started; see [`manager::WifiManager`] and [`portal::provision`].
+///
+/// The modem, the system event loop and the NVS partition are singletons taken once in `main` and passed in.
+pub fn wifi_init<'d>(
+    modem: impl Peripheral<P = Modem> + 'd,
+    sys_loop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+) -> Result<EspWifi<'d>, WifiError> {
+    Ok(EspWifi::new(modem, sys_loop, Some(nvs))?)
+}
+
+/// Station configuration joining the network of `credentials`.
+pub fn client_configuration(credentials: &WifiCredentials) -> Result<ClientConfiguration, WifiError> {
+    Ok(ClientConfiguration {
+        ssid: credentials
+            .ssid
+            .as_str()
+            .try_into()
+            .map_err(|_| WifiError::InvalidConfiguration("SSID too long"))?,
+        password: credentials
+            .password
+            .as_str()
+            .try_into()
+            .map_err(|_| WifiError::InvalidConfiguration("password too long"))?,
+        auth_method: if credentials.password.is_empty() {
+            AuthMethod::None
+        } else {
+            AuthMethod::WPA2Personal
+        },
+        ..Default::default()
+    })
+}
+
+/// Known networks seen in `scan`, in the order to try them: highest priority first, then strongest signal.
+pub fn rank_networks(known: &[KnownNetwork], scan: &[AccessPointInfo]) -> Vec<(KnownNetwork, i8)> {
+    let mut candidates: Vec<(KnownNetwork, i8)> = known
+        .iter()
+        .filter_map(|network| {
+            // The same SSID is often served by several access points; rank it by the strongest
+            let rssi = scan
+                .iter()
+                .filter(|ap| ap.ssid.as_str() == network.ssid())
+                .map(|ap| ap.signal_strength)
+                .max()?;
+            Some((network.clone(), rssi))
+        })
+        .collect();
+    candidates.sort_by_key(|(network, rssi)| core::cmp::Reverse((network.priority, *rssi)));
+    candidates
+}
+
+/// Start connecting with the current configuration and wait up to `timeout` for the station to be connected.
+/// The connection attempt is abandoned on timeout.
+pub fn wait_connected<W: WifiDriver>(wifi: &mut W, timeout: Duration) -> Result<(), WifiError> {
+    wifi.connect()?;
+    let start = Instant::now();
+    while !wifi.is_connected()? {
+        if start.elapsed() >= timeout {
+            wifi.disconnect()?;
+            return Err(WifiError::Timeout { timeout });
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(ssid: &str, priority: u8) -> KnownNetwork {
+        KnownNetwork::new(WifiCredentials::new(ssid, "password").unwrap(), priority)
+    }
+
+    fn ap(ssid: &str, signal_strength: i8) -> AccessPointInfo {
+        AccessPointInfo {
+            ssid: ssid.try_into().unwrap(),
+            signal_strength,
+            ..Default::default()
+        }
+    }
+
+    fn ranked(known: &[KnownNetwork], scan: &[AccessPointInfo]) -> Vec<(String, i8)> {
+        rank_networks(known, scan)
+            .into_iter()
+            .map(|(network, rssi)| (network.ssid().to_string(), rssi))
+            .collect()
+    }
+
+    #[test]
+    fn ranks_by_priority_then_signal() {
+        let known = [network("lab", 10), network("office", 20), network("home", 10)];
+        let scan = [ap("lab", -40), ap("home", -70), ap("office", -80)];
+        assert_eq!(
+            ranked(&known, &scan),
+            vec![("office".to_string(), -80), ("lab".to_string(), -40), ("home".to_string(), -70)]
+        );
+    }
+
+    #[test]
+    fn skips_networks_out_of_range() {
+        let known = [network("lab", 10), network("office", 20)];
+        let scan = [ap("lab", -60), ap("neighbour", -30)];
+        assert_eq!(ranked(&known, &scan), vec![("lab".to_string(), -60)]);
+        assert!(rank_networks(&known, &[]).is_empty());
+    }
+
+    #[test]
+    fn uses_the_strongest_access_point_of_an_ssid() {
+        let known = [network("lab", 10), network("office", 10)];
+        let scan = [ap("lab", -85), ap("office", -60), ap("lab", -50)];
+        assert_eq!(
+            ranked(&known, &scan),
+            vec![("lab".to_string(), -50), ("office".to_string(), -60)]
+        );
+    }
+}
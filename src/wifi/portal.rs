@@ -0,0 +1,451 @@
+#![allow(dead_code)]
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use esp_idf_svc::http::server::{Configuration as HttpConfiguration, EspHttpServer};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::{Read, Write};
+use esp_idf_svc::wifi::{AccessPointConfiguration, AccessPointInfo, AuthMethod, ClientConfiguration, Configuration};
+use anyhow::Error;
+use log::*;
+
+use super::credentials::{CredentialStore, WifiCredentials};
+use super::driver::WifiDriver;
+use super::{client_configuration, wait_connected};
+
+/// Largest form body accepted by `POST /connect`.
+const MAX_FORM_LEN: usize = 512;
+/// Longest label of a DNS name; length bytes above it are compression pointers or reserved.
+const MAX_DNS_LABEL: usize = 63;
+/// Longest DNS name on the wire, length bytes included.
+const MAX_DNS_NAME: usize = 255;
+
+/// Settings of the provisioning access point.
+#[derive(Debug, Clone)]
+pub struct PortalConfig {
+    /// SSID of the open access point the user joins to enter the credentials.
+    pub ap_ssid: String,
+    pub ap_channel: u8,
+    /// Time given to the submitted credentials to connect before they are rejected.
+    pub connect_timeout: Duration,
+    /// Time without a submission after which the device restarts, e.g. to retry known networks that were only
+    /// temporarily down. `None` keeps the portal up until it has been configured.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for PortalConfig {
+    fn default() -> Self {
+        PortalConfig {
+            ap_ssid: "robot-setup".to_string(),
+            ap_channel: 1,
+            connect_timeout: Duration::from_secs(15),
+            timeout: None,
+        }
+    }
+}
+
+/// Progress of the credentials submitted through the form.
+#[derive(Debug, Clone)]
+enum Attempt {
+    /// Nothing submitted yet.
+    Idle,
+    /// Submitted, waiting to be tried by [`provision`]'s loop.
+    Pending(WifiCredentials),
+    /// Connecting to the SSID.
+    Trying(String),
+    Failed(String),
+    Connected,
+}
+
+/// Run the Wi-Fi provisioning portal until valid credentials are entered, then restart into station mode.
+///
+/// Starts an open access point with a captive portal: a DNS server answers every name with the portal's address, so
+/// phones and laptops open the setup page on their own. The page lists the scanned networks; submitted credentials are
+/// tried before they are stored in `store`, and the device restarts once they connect. The credentials are tried here
+/// rather than in the HTTP handler, which returns at once and lets the page poll for the outcome. Also restarts after
+/// [`PortalConfig::timeout`] without a submission. Only returns on error.
+pub fn provision<W: WifiDriver>(
+    mut wifi: W,
+    mut store: CredentialStore,
+    config: &PortalConfig,
+) -> Result<Infallible, Error> {
+    let ap = AccessPointConfiguration {
+        ssid: config
+            .ap_ssid
+            .as_str()
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Portal SSID too long"))?,
+        channel: config.ap_channel,
+        auth_method: AuthMethod::None,
+        ..Default::default()
+    };
+    // Mixed mode keeps the station side available for scanning and for trying the submitted credentials
+    wifi.set_configuration(&Configuration::Mixed(ClientConfiguration::default(), ap.clone()))?;
+    wifi.start()?;
+    let mut networks = wifi.scan().unwrap_or_else(|e| {
+        warn!("Wi-Fi scan failed: {:?}", e);
+        Vec::new()
+    });
+    // Strongest first, one entry per SSID
+    networks.sort_by_key(|network| core::cmp::Reverse(network.signal_strength));
+    let mut seen = HashSet::new();
+    networks.retain(|network| seen.insert(network.ssid.clone()));
+    let ip = wifi.ap_ip()?;
+    info!("Wi-Fi provisioning: join \"{}\" and open http://{}/", config.ap_ssid, ip);
+
+    std::thread::Builder::new()
+        .name("captive-dns".to_string())
+        .stack_size(4096)
+        .spawn(move || {
+            if let Err(e) = run_dns(ip) {
+                error!("Captive portal DNS stopped: {:?}", e);
+            }
+        })?;
+
+    let attempt = Arc::new(Mutex::new(Attempt::Idle));
+    let mut server = EspHttpServer::new(&HttpConfiguration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+
+    let handle = attempt.clone();
+    server.fn_handler("/", Method::Get, move |request| {
+        let page = status_page(&lock(&handle), &networks);
+        request
+            .into_response(200, None, &[("Content-Type", "text/html")])?
+            .write_all(page.as_bytes())?;
+        Ok::<(), Error>(())
+    })?;
+
+    let handle = attempt.clone();
+    server.fn_handler("/connect", Method::Post, move |mut request| {
+        let mut body = [0u8; MAX_FORM_LEN];
+        let mut len = 0;
+        while len < body.len() {
+            match request.read(&mut body[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        let credentials = WifiCredentials::new(
+            &form_field(&body[..len], "ssid").unwrap_or_default(),
+            &form_field(&body[..len], "password").unwrap_or_default(),
+        );
+        {
+            let mut attempt = lock(&handle);
+            // A submission while one is being tried (e.g. a double click) is dropped
+            if !matches!(*attempt, Attempt::Pending(_) | Attempt::Trying(_) | Attempt::Connected) {
+                *attempt = match credentials {
+                    Ok(credentials) => Attempt::Pending(credentials),
+                    Err(e) => Attempt::Failed(e.to_string()),
+                };
+            }
+        }
+        // The status page follows the attempt until it succeeds or fails
+        request.into_response(303, Some("See Other"), &[("Location", "/")])?;
+        Ok::<(), Error>(())
+    })?;
+
+    // Operating systems probe well-known URLs to detect captive portals; redirect all of them to the form
+    let location = format!("http://{}/", ip);
+    server.fn_handler("/*", Method::Get, move |request| {
+        request.into_response(302, Some("Found"), &[("Location", &location)])?;
+        Ok::<(), Error>(())
+    })?;
+
+    let mut idle_since = Instant::now();
+    loop {
+        let pending = {
+            let mut attempt = lock(&attempt);
+            match &*attempt {
+                Attempt::Pending(credentials) => {
+                    let credentials = credentials.clone();
+                    *attempt = Attempt::Trying(credentials.ssid.clone());
+                    Some(credentials)
+                }
+                _ => None,
+            }
+        };
+        if let Some(credentials) = pending {
+            let result = try_credentials(&mut wifi, &ap, &credentials, config.connect_timeout)
+                .and_then(|()| store.save(&credentials));
+            match result {
+                Ok(()) => {
+                    *lock(&attempt) = Attempt::Connected;
+                    break;
+                }
+                Err(e) => {
+                    warn!("Wi-Fi provisioning failed: {}", e);
+                    *lock(&attempt) = Attempt::Failed(e.to_string());
+                }
+            }
+            idle_since = Instant::now();
+        }
+        if config.timeout.is_some_and(|timeout| idle_since.elapsed() >= timeout) {
+            info!("Wi-Fi provisioning timed out, restarting to retry the known networks");
+            esp_idf_hal::reset::restart();
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+    // Let the status page show the success before the access point goes down
+    std::thread::sleep(Duration::from_secs(3));
+    info!("Wi-Fi provisioned, restarting");
+    esp_idf_hal::reset::restart();
+}
+
+/// Connect the station side to `credentials` while keeping the access point up.
+fn try_credentials<W: WifiDriver>(
+    wifi: &mut W,
+    ap: &AccessPointConfiguration,
+    credentials: &WifiCredentials,
+    timeout: Duration,
+) -> Result<(), Error> {
+    info!("Trying Wi-Fi credentials for \"{}\"", credentials.ssid);
+    wifi.set_configuration(&Configuration::Mixed(client_configuration(credentials)?, ap.clone()))?;
+    wait_connected(wifi, timeout).map_err(|_| anyhow::anyhow!("Could not connect to \"{}\"", credentials.ssid))
+}
+
+/// Answer every DNS query for an A record with `ip`, so every name leads to the portal.
+fn run_dns(ip: Ipv4Addr) -> Result<(), Error> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 53))?;
+    let mut buf = [0u8; 512];
+    loop {
+        let (len, peer) = socket.recv_from(&mut buf)?;
+        if let Some(reply) = dns_reply(&buf[..len], ip) {
+            if let Err(e) = socket.send_to(&reply, peer) {
+                debug!("Captive portal DNS reply failed: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Build the reply to a DNS query: the first question, answered with `ip` if it asks for an A record.
+fn dns_reply(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    // Header: ID, flags, QDCOUNT, ANCOUNT, NSCOUNT, ARCOUNT; only standard queries with a question are answered
+    if query.len() < 12 || query[2] & 0xf8 != 0 || u16::from_be_bytes([query[4], query[5]]) == 0 {
+        return None;
+    }
+    // The question is a sequence of length-prefixed labels ending with 0, then QTYPE and QCLASS. Queries have no
+    // reason to compress names, so a pointer (top bits set) is refused rather than taken for a length, and so are names
+    // beyond the 255 bytes DNS allows.
+    let mut end = 12;
+    while *query.get(end)? != 0 {
+        let label = query[end] as usize;
+        // Name so far, this label with its length byte, and the terminating zero
+        let name_len = end - 12 + 1 + label + 1;
+        if label > MAX_DNS_LABEL || name_len > MAX_DNS_NAME {
+            return None;
+        }
+        end += 1 + label;
+    }
+    end += 5;
+    let question = query.get(12..end)?;
+    let is_a = question[question.len() - 4..] == [0, 1, 0, 1];
+
+    let mut reply = Vec::with_capacity(end + 16);
+    reply.extend_from_slice(&query[..2]);
+    // Response, recursion desired copied, recursion available, no error
+    reply.extend_from_slice(&[0x80 | (query[2] & 0x01), 0x80]);
+    reply.extend_from_slice(&[0, 1, 0, is_a as u8, 0, 0, 0, 0]);
+    reply.extend_from_slice(question);
+    if is_a {
+        // Name as a pointer to the question, type A, class IN, TTL 60 s, 4 bytes of address
+        reply.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        reply.extend_from_slice(&ip.octets());
+    }
+    Some(reply)
+}
+
+/// Value of `name` in an `application/x-www-form-urlencoded` body.
+fn form_field(body: &[u8], name: &str) -> Option<String> {
+    let body = core::str::from_utf8(body).ok()?;
+    body.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        (url_decode(key)? == name).then(|| url_decode(value)).flatten()
+    })
+}
+
+/// Decode `%XX` escapes and `+` (space). `None` for a truncated or non-hex escape, or if the result is not UTF-8.
+fn url_decode(text: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut input = text.bytes();
+    while let Some(byte) = input.next() {
+        bytes.push(match byte {
+            b'+' => b' ',
+            b'%' => {
+                let hex = [input.next()?, input.next()?];
+                // `from_str_radix` alone would accept a sign, e.g. "%+f"
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return None;
+                }
+                u8::from_str_radix(core::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            byte => byte,
+        });
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn html_escape(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&#39;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// Page for the current state of the attempt: the form, a progress page refreshing itself, or the confirmation.
+fn status_page(attempt: &Attempt, networks: &[AccessPointInfo]) -> String {
+    let message = |refresh: bool, text: String| {
+        format!(
+            "<html><head><meta name=\"viewport\" content=\"width=device-width\">{}<title>Wi-Fi setup</title></head>\
+             <body><p>{}</p></body></html>",
+            if refresh { "<meta http-equiv=\"refresh\" content=\"2\">" } else { "" },
+            text
+        )
+    };
+    match attempt {
+        Attempt::Idle => form_page(networks, None),
+        Attempt::Failed(error) => form_page(networks, Some(error)),
+        Attempt::Pending(credentials) => message(true, format!("Connecting to {}...", html_escape(&credentials.ssid))),
+        Attempt::Trying(ssid) => message(true, format!("Connecting to {}...", html_escape(ssid))),
+        Attempt::Connected => message(false, "Connected. The robot restarts and joins the network.".to_string()),
+    }
+}
+
+/// Setup page listing the scanned networks, with an error message after a failed attempt.
+fn form_page(networks: &[AccessPointInfo], error: Option<&str>) -> String {
+    let mut page = String::from(
+        "<html><head><meta name=\"viewport\" content=\"width=device-width\"><title>Wi-Fi setup</title></head><body>\
+         <h1>Wi-Fi setup</h1>",
+    );
+    if let Some(error) = error {
+        page += &format!("<p style=\"color:red\">{}</p>", html_escape(error));
+    }
+    page += "<form method=\"post\" action=\"/connect\"><p>Network<br><input name=\"ssid\" list=\"networks\" required></p>\
+             <datalist id=\"networks\">";
+    for network in networks.iter().filter(|network| !network.ssid.is_empty()) {
+        page += &format!("<option value=\"{}\">", html_escape(&network.ssid));
+    }
+    page += "</datalist><ul>";
+    for network in networks.iter().filter(|network| !network.ssid.is_empty()) {
+        page += &format!(
+            "<li>{} ({} dBm{})</li>",
+            html_escape(&network.ssid),
+            network.signal_strength,
+            if network.auth_method == Some(AuthMethod::None) { ", open" } else { "" }
+        );
+    }
+    page += "</ul><p>Password<br><input name=\"password\" type=\"password\"></p>\
+             <p><button type=\"submit\">Connect</button></p></form></body></html>";
+    page
+}
+
+fn lock(attempt: &Mutex<Attempt>) -> std::sync::MutexGuard<'_, Attempt> {
+    attempt.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PORTAL: Ipv4Addr = Ipv4Addr::new(192, 168, 71, 1);
+
+    /// A standard query with recursion desired for `name`, asking for `qtype`.
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+        query.push(0);
+        query.extend_from_slice(&qtype.to_be_bytes());
+        query.extend_from_slice(&[0, 1]);
+        query
+    }
+
+    #[test]
+    fn answers_a_queries_with_the_portal_address() {
+        let query = query("connectivitycheck.gstatic.com", 1);
+        let reply = dns_reply(&query, PORTAL).unwrap();
+        assert_eq!(reply[..2], [0x12, 0x34]);
+        assert_eq!(reply[2..4], [0x81, 0x80]);
+        // One question, one answer
+        assert_eq!(reply[4..12], [0, 1, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(reply[12..query.len()], query[12..]);
+        assert_eq!(reply[query.len()..query.len() + 2], [0xc0, 0x0c]);
+        assert_eq!(reply[reply.len() - 4..], PORTAL.octets());
+    }
+
+    #[test]
+    fn other_query_types_get_an_empty_answer() {
+        // AAAA
+        let query = query("example.com", 28);
+        let reply = dns_reply(&query, PORTAL).unwrap();
+        assert_eq!(reply[4..12], [0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(reply.len(), query.len());
+    }
+
+    #[test]
+    fn malformed_queries_are_ignored() {
+        let good = query("example.com", 1);
+        // Truncated header, name, or QTYPE/QCLASS
+        for len in [0, 11, 14, good.len() - 1] {
+            assert_eq!(dns_reply(&good[..len], PORTAL), None, "{} bytes answered", len);
+        }
+        // A response rather than a query
+        let mut response = good.clone();
+        response[2] |= 0x80;
+        assert_eq!(dns_reply(&response, PORTAL), None);
+        // No question
+        let mut empty = good.clone();
+        empty[5] = 0;
+        assert_eq!(dns_reply(&empty, PORTAL), None);
+    }
+
+    #[test]
+    fn compression_pointers_and_long_names_are_refused() {
+        let mut pointer = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        pointer.extend_from_slice(&[0xc0, 0x0c, 0, 0, 1, 0, 1]);
+        assert_eq!(dns_reply(&pointer, PORTAL), None);
+
+        let label = "a".repeat(64);
+        assert_eq!(dns_reply(&query(&label, 1), PORTAL), None);
+        let long = ["a".repeat(63).as_str(); 4].join(".");
+        assert_eq!(dns_reply(&query(&long, 1), PORTAL), None);
+        // 3 × (1 + 63) + (1 + 61) + 1 = 255 bytes
+        let longest = format!("{0}.{0}.{0}.{1}", "a".repeat(63), "a".repeat(61));
+        assert!(dns_reply(&query(&longest, 1), PORTAL).is_some());
+    }
+
+    #[test]
+    fn decodes_form_fields() {
+        let body = b"ssid=Lab+Wi-Fi%21&password=p%40ss%2Bw%C3%B6rd";
+        assert_eq!(form_field(body, "ssid").as_deref(), Some("Lab Wi-Fi!"));
+        assert_eq!(form_field(body, "password").as_deref(), Some("p@ss+w\u{f6}rd"));
+        assert_eq!(form_field(b"ssid=&password=x", "ssid").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn missing_fields_are_none() {
+        assert_eq!(form_field(b"ssid=lab", "password"), None);
+        assert_eq!(form_field(b"ssid", "ssid"), None);
+        assert_eq!(form_field(b"", "ssid"), None);
+        assert_eq!(form_field(&[0xff, b'=', b'x'], "ssid"), None);
+    }
+
+    #[test]
+    fn bad_escapes_are_rejected() {
+        for text in ["%", "%4", "%zz", "%+f", "%-1", "%C3"] {
+            assert_eq!(url_decode(text), None, "{:?} decoded", text);
+        }
+        assert_eq!(url_decode("100%25+sure").as_deref(), Some("100% sure"));
+    }
+}